@@ -1,6 +1,6 @@
 use crate::manager::MessageQueue;
 use anyhow::Result;
-use network_node::header::Header;
+use network_node::header::{AppHeaderId, AppHeaderProperty, Header};
 use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
@@ -31,6 +31,12 @@ pub trait App {
         None
     }
 
+    /// properties of application defined headers(Header::App) which this app uses.
+    /// they are registered when this app is added to AppManager.
+    fn my_app_header_properties(&self) -> Vec<(AppHeaderId, AppHeaderProperty)> {
+        Vec::new()
+    }
+
     /// default app wake up only when message is received and host notifys it
     fn create_task(mut self, message: &Arc<MessageQueue>, priority: u8) -> JoinHandle<()>
     where
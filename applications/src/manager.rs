@@ -1,4 +1,5 @@
 use super::App;
+use anyhow::Result;
 use network_node::header::Header;
 use std::collections::HashMap;
 use std::sync::{Arc, Condvar, Mutex};
@@ -11,10 +12,23 @@ pub struct AppManager {
 }
 
 impl AppManager {
-    pub fn add_app<APP>(&mut self, app: APP, priority: u8)
+    pub fn new() -> Self {
+        Self {
+            apps_handles: Vec::new(),
+            message_queues: Vec::new(),
+            event_map: HashMap::new(),
+        }
+    }
+
+    pub fn add_app<APP>(&mut self, app: APP, priority: u8) -> Result<()>
     where
         APP: App + Send + 'static,
     {
+        // application defined headers must be registered before the app receives packets.
+        for (id, property) in app.my_app_header_properties() {
+            Header::register_app_header(id, property)?;
+        }
+
         let message_queue = Arc::new(MessageQueue::new());
         self.message_queues.push(message_queue.clone());
         let headers = app.my_headers();
@@ -23,6 +37,7 @@ impl AppManager {
         }
         self.apps_handles
             .push(app.create_task(&message_queue, priority));
+        Ok(())
     }
 
     fn add_event_map(&mut self, headers: Vec<Header>, message_queue: &Arc<MessageQueue>) {
@@ -42,6 +57,20 @@ impl AppManager {
         }
     }
 
+    /// push messages to the apps which are related to the header.
+    /// return false if no app is related to the header.
+    pub fn dispatch(&self, header: Header, messages: Vec<u8>) -> bool {
+        let event = match self.event_map.get(&header) {
+            Some(event) => event,
+            None => return false,
+        };
+        for message_queue in event.message_queues.iter() {
+            message_queue.queue.lock().unwrap().extend(&messages);
+            message_queue.condvar.notify_all();
+        }
+        true
+    }
+
     pub fn do_task(self) -> ! {
         // wait for network event
         // get header
@@ -78,6 +107,12 @@ impl AppManager {
     }
 }
 
+impl Default for AppManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Event {
     pub condvar: Condvar,
     pub mutex: Mutex<bool>,
@@ -104,6 +139,50 @@ pub struct MessageQueue {
 
 impl MessageQueue {
     fn new() -> Self {
-        todo!()
+        Self {
+            queue: Mutex::new(Vec::new()),
+            condvar: Condvar::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use network_node::header::{AppHeaderId, AppHeaderProperty};
+
+    struct HeaderApp {}
+    impl App for HeaderApp {
+        fn process_messages(&mut self, _messages: Vec<u8>) -> Result<()> {
+            Ok(())
+        }
+        fn my_headers(&self) -> Option<Vec<Header>> {
+            Some(vec![Header::app(10).unwrap()])
+        }
+        fn my_app_header_properties(&self) -> Vec<(AppHeaderId, AppHeaderProperty)> {
+            vec![(
+                AppHeaderId::new(10).unwrap(),
+                AppHeaderProperty::new(true, false),
+            )]
+        }
+    }
+
+    #[test]
+    fn test_app_header_event_map() {
+        let mut manager = AppManager::new();
+        let message_queue = Arc::new(MessageQueue::new());
+        let app = HeaderApp {};
+        for (id, property) in app.my_app_header_properties() {
+            Header::register_app_header(id, property).unwrap();
+        }
+        manager.add_event_map(app.my_headers().unwrap(), &message_queue);
+
+        let header = Header::app(10).unwrap();
+        assert!(header.is_only_head());
+        assert!(!header.is_require_ack());
+
+        assert!(manager.dispatch(header, vec![1, 2, 3]));
+        assert!(!manager.dispatch(Header::app(11).unwrap(), vec![4]));
+        assert_eq!(*message_queue.queue.lock().unwrap(), vec![1, 2, 3]);
     }
 }
@@ -0,0 +1,493 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use anyhow::{anyhow, Result};
+use network_node::protocol::{ChannelId, Protocol, CHANNEL_LENGTH};
+use network_node::utils::type_alias::{Coordinate, Id};
+
+use crate::connections::Connections;
+use crate::BoundingBox;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Direction {
+    West,
+    East,
+    North,
+    South,
+}
+
+impl Direction {
+    /// west is tried first, so that a packet goes to west as early as possible.
+    const ALL: [Direction; 4] = [
+        Direction::West,
+        Direction::East,
+        Direction::North,
+        Direction::South,
+    ];
+
+    fn step(self, coordinate: Coordinate) -> Option<Coordinate> {
+        let (x, y) = coordinate;
+        match self {
+            Direction::West => Some((x.checked_sub(1)?, y)),
+            Direction::East => Some((x.checked_add(1)?, y)),
+            Direction::North => Some((x, y.checked_add(1)?)),
+            Direction::South => Some((x, y.checked_sub(1)?)),
+        }
+    }
+
+    /// direction of the move from `from` to `to` if they are neighbors
+    fn between(from: Coordinate, to: Coordinate) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|direction| direction.step(from) == Some(to))
+    }
+}
+
+/// Adaptive routing protocol which routes around holes and dead nodes.
+///
+/// It is based on the west-first turn model: a packet moves to west first, and once it moves to
+/// east, north or south, it never moves to west again in the same virtual channel. Turns to west
+/// are prohibited, so channel dependencies in a virtual channel never make a cycle.
+/// If a packet has to turn to west (e.g. a hole is right on the west of the source and the
+/// destination is behind it), it moves to the next virtual channel and starts west-first routing
+/// again. Virtual channels of a packet never decrease, so the whole routing is deadlock-free.
+/// Within this rule a packet takes the shortest path on the known topology, which may be a detour
+/// around holes, and it uses as few channels as possible.
+/// A destination which cannot be reached even with all channels is reported as an error.
+///
+/// The topology is learned by add_connection/remove_connection and remove_node (see
+/// Connections). Each node runs the search from its own position, so a packet may change its
+/// path at every hop as the known topology changes.
+pub struct AdaptiveProtocol {
+    bounding_box: BoundingBox,
+    connections: Connections,
+}
+
+impl AdaptiveProtocol {
+    pub fn new() -> Self {
+        Self::with_bounding_box(BoundingBox::default())
+    }
+    pub fn with_bounding_box(bounding_box: BoundingBox) -> Self {
+        Self {
+            bounding_box,
+            connections: Connections::default(),
+        }
+    }
+    pub fn get_bounding_box(&self) -> BoundingBox {
+        self.bounding_box
+    }
+
+    /// remove all connections of the node, e.g. when it stops responding.
+    pub fn remove_node(&mut self, id: Id) -> Result<()> {
+        // the node must be in the bounding box
+        self.bounding_box.to_coordinate(id)?;
+        self.connections.remove_node(id);
+        Ok(())
+    }
+
+    /// check id and id2 are neighbors in the bounding box
+    fn check_neighbors(&self, id: Id, id2: Id) -> Result<()> {
+        let coordinate = self.bounding_box.to_coordinate(id)?;
+        let coordinate2 = self.bounding_box.to_coordinate(id2)?;
+        match Direction::between(coordinate, coordinate2) {
+            Some(_) => Ok(()),
+            None => Err(anyhow!(
+                "{:?} and {:?} are not neighbors",
+                coordinate,
+                coordinate2
+            )),
+        }
+    }
+
+    fn is_connected(&self, coordinate: Coordinate, coordinate2: Coordinate) -> bool {
+        let (id, id2) = match (
+            self.bounding_box.to_ip_address(coordinate),
+            self.bounding_box.to_ip_address(coordinate2),
+        ) {
+            (Ok(id), Ok(id2)) => (id, id2),
+            _ => return false,
+        };
+        self.connections.is_connected(id, id2)
+    }
+
+    /// find the shortest path which obeys the turn model in each channel by Dijkstra, and return
+    /// the first hop and its channel.
+    /// state is (coordinate, whether the packet can still move to west, channel), and the cost of
+    /// a path is (the number of hops, channel at the end).
+    fn route(
+        &self,
+        this: Id,
+        previous: Option<Id>,
+        destination: Id,
+        channel: ChannelId,
+    ) -> Result<(Id, ChannelId)> {
+        let this_coordinate = self.bounding_box.to_coordinate(this)?;
+        let destination_coordinate = self.bounding_box.to_coordinate(destination)?;
+        if this_coordinate == destination_coordinate {
+            return Err(anyhow!("{:?} is already the destination", this_coordinate));
+        }
+        if channel as usize >= CHANNEL_LENGTH {
+            return Err(anyhow!("channel {} is out of range", channel));
+        }
+        // a packet which came by moving to east, north or south cannot turn to west
+        let can_go_west = match previous {
+            Some(previous) => !matches!(
+                Direction::between(self.bounding_box.to_coordinate(previous)?, this_coordinate),
+                Some(Direction::East | Direction::North | Direction::South)
+            ),
+            None => true,
+        };
+
+        let start = (this_coordinate, can_go_west, channel);
+        // state -> first hop of the path to the state
+        let mut first_hops = HashMap::new();
+        first_hops.insert(start, (this_coordinate, channel));
+        // order of pushing breaks ties, so that west is tried first as same as BFS
+        let mut order = 0;
+        let mut heap = BinaryHeap::from([Reverse((0, channel, order, start))]);
+        while let Some(Reverse((hops, _, _, state))) = heap.pop() {
+            let (coordinate, can_go_west, channel) = state;
+            if coordinate == destination_coordinate {
+                let (first_hop, first_channel) = first_hops[&state];
+                return Ok((self.bounding_box.to_ip_address(first_hop)?, first_channel));
+            }
+            for direction in Direction::ALL {
+                let (next_can_go_west, next_channel) = match (direction, can_go_west) {
+                    (Direction::West, true) => (true, channel),
+                    // turn to west in the next channel
+                    (Direction::West, false) if (channel as usize) + 1 < CHANNEL_LENGTH => {
+                        (true, channel + 1)
+                    }
+                    (Direction::West, false) => continue,
+                    _ => (false, channel),
+                };
+                let next = match direction.step(coordinate) {
+                    Some(next) if self.is_connected(coordinate, next) => next,
+                    _ => continue,
+                };
+                let next_state = (next, next_can_go_west, next_channel);
+                if first_hops.contains_key(&next_state) {
+                    continue;
+                }
+                let first_hop = if state == start {
+                    (next, next_channel)
+                } else {
+                    first_hops[&state]
+                };
+                first_hops.insert(next_state, first_hop);
+                order += 1;
+                heap.push(Reverse((hops + 1, next_channel, order, next_state)));
+            }
+        }
+        Err(anyhow!(
+            "no route from {:?} to {:?}",
+            this_coordinate,
+            destination_coordinate
+        ))
+    }
+}
+
+impl Default for AdaptiveProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Protocol for AdaptiveProtocol {
+    /// whether this node is on the path of a packet which is sent by the source.
+    /// it is decided by the topology which this node knows, so it may differ from the actual path.
+    fn is_in_route(&self, this: Id, source: Id, destination: Id) -> bool {
+        let max_length = (self.bounding_box.width() * self.bounding_box.height() * 2) as usize;
+        let mut previous = None;
+        let mut current = source;
+        let mut channel = self.get_channel(source, destination);
+        for _ in 0..=max_length {
+            if current == this {
+                return true;
+            }
+            if current == destination {
+                return false;
+            }
+            match self.route(current, previous, destination, channel) {
+                Ok((next, next_channel)) => {
+                    previous = Some(current);
+                    current = next;
+                    channel = next_channel;
+                }
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+    fn get_next_node(&self, this: Id, destination: Id) -> Result<Id> {
+        let channel = self.get_channel(this, destination);
+        Ok(self.route(this, None, destination, channel)?.0)
+    }
+    fn get_next_hop(
+        &self,
+        this: Id,
+        previous: Id,
+        destination: Id,
+        channel: ChannelId,
+    ) -> Result<(Id, ChannelId)> {
+        self.route(this, Some(previous), destination, channel)
+    }
+    fn add_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.check_neighbors(id, id2)?;
+        self.connections.add(id, id2);
+        Ok(())
+    }
+    fn remove_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.check_neighbors(id, id2)?;
+        self.connections.remove(id, id2);
+        Ok(())
+    }
+    fn join_global_network(&mut self, _mac_address: Id, coordinate: Coordinate) -> Result<Id> {
+        self.bounding_box = self.bounding_box.including(coordinate)?;
+        self.bounding_box.to_ip_address(coordinate)
+    }
+    fn get_routing_parameters(&self) -> Vec<u8> {
+        self.bounding_box.to_bytes()
+    }
+    /// addresses don't move when the bounding box is extended, so the learned topology is kept.
+    fn set_routing_parameters(&mut self, parameters: &[u8]) -> Result<()> {
+        self.bounding_box = BoundingBox::from_bytes(parameters)?;
+        Ok(())
+    }
+    /// minimal routes take the manhattan distance, and detours around holes are not counted.
+    fn get_distance(&self, this: Id, destination: Id) -> Result<u16> {
+        self.bounding_box.distance(this, destination)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use network_node::conformance::{Conformance, ConnectionReport, Topology};
+
+    /// make the protocol which knows all connections of the grid except holes, as if every node
+    /// has reported its own connections
+    fn make_protocol(bounding_box: BoundingBox, holes: &[Coordinate]) -> AdaptiveProtocol {
+        let mut protocol = AdaptiveProtocol::with_bounding_box(bounding_box);
+        let (min, max) = (bounding_box.get_min(), bounding_box.get_max());
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for neighbor in [(x + 1, y), (x, y + 1)] {
+                    if !bounding_box.contains(neighbor)
+                        || holes.contains(&(x, y))
+                        || holes.contains(&neighbor)
+                    {
+                        continue;
+                    }
+                    let id = bounding_box.to_ip_address((x, y)).unwrap();
+                    let id2 = bounding_box.to_ip_address(neighbor).unwrap();
+                    protocol.add_connection(id, id2).unwrap();
+                    protocol.add_connection(id2, id).unwrap();
+                }
+            }
+        }
+        protocol
+    }
+
+    /// follow get_next_hop from the source, and check the path is valid.
+    /// return coordinates of the path and channels of each hop.
+    fn make_path_with_channels(
+        protocol: &AdaptiveProtocol,
+        source: Coordinate,
+        destination: Coordinate,
+    ) -> Option<(Vec<Coordinate>, Vec<ChannelId>)> {
+        let bounding_box = protocol.get_bounding_box();
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        let mut path = vec![source];
+        let mut channels = vec![protocol.get_channel(ip(source), ip(destination))];
+        let mut next = protocol.get_next_node(ip(source), ip(destination)).ok()?;
+        loop {
+            let this = *path.last().unwrap();
+            let next_coordinate = bounding_box.to_coordinate(next).unwrap();
+            assert!(protocol.is_connected(this, next_coordinate));
+            path.push(next_coordinate);
+            if next_coordinate == destination {
+                return Some((path, channels));
+            }
+            assert!(path.len() <= 2 * (bounding_box.width() * bounding_box.height()) as usize);
+            // a forwarding node must find the route which the previous node found
+            let channel = *channels.last().unwrap();
+            let (next_node, next_channel) = protocol
+                .get_next_hop(next, ip(this), ip(destination), channel)
+                .unwrap();
+            assert!(next_channel >= channel);
+            next = next_node;
+            channels.push(next_channel);
+        }
+    }
+
+    fn make_path(
+        protocol: &AdaptiveProtocol,
+        source: Coordinate,
+        destination: Coordinate,
+    ) -> Option<Vec<Coordinate>> {
+        make_path_with_channels(protocol, source, destination).map(|(path, _)| path)
+    }
+
+    /// in each channel, after moving to other than west, the packet never moves to west.
+    fn obeys_west_first(path: &[Coordinate], channels: &[ChannelId]) -> bool {
+        let hops: Vec<(Direction, ChannelId)> = path
+            .windows(2)
+            .zip(channels)
+            .map(|(hop, channel)| (Direction::between(hop[0], hop[1]).unwrap(), *channel))
+            .collect();
+        hops.windows(2).all(|hops| {
+            let ((direction, channel), (next_direction, next_channel)) = (hops[0], hops[1]);
+            next_channel > channel
+                || direction == Direction::West
+                || next_direction != Direction::West
+        })
+    }
+
+    fn coordinates(bounding_box: BoundingBox, holes: &[Coordinate]) -> Vec<Coordinate> {
+        let (min, max) = (bounding_box.get_min(), bounding_box.get_max());
+        (min.0..=max.0)
+            .flat_map(|x| (min.1..=max.1).map(move |y| (x, y)))
+            .filter(|coordinate| !holes.contains(coordinate))
+            .collect()
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_full_grid_is_minimal() {
+        let bounding_box = BoundingBox::new((-1, -1), (2, 2)).unwrap();
+        let protocol = make_protocol(bounding_box, &[]);
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        let coordinates = coordinates(bounding_box, &[]);
+        for &source in coordinates.iter() {
+            for &destination in coordinates.iter() {
+                if source == destination {
+                    continue;
+                }
+                let (path, channels) =
+                    make_path_with_channels(&protocol, source, destination).unwrap();
+                let distance = (source.0 - destination.0).abs() + (source.1 - destination.1).abs();
+                assert_eq!(path.len() as i16 - 1, distance);
+                assert!(obeys_west_first(&path, &channels), "{:?}", path);
+                // west-first routing is enough in a full grid
+                assert!(channels.iter().all(|channel| *channel == 0));
+                for &this in coordinates.iter() {
+                    assert_eq!(
+                        protocol.is_in_route(ip(this), ip(source), ip(destination)),
+                        path.contains(&this)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_route_around_hole() {
+        let bounding_box = BoundingBox::new((0, 0), (4, 4)).unwrap();
+        let holes = [(2, 2), (2, 3)];
+        let protocol = make_protocol(bounding_box, &holes);
+        let coordinates = coordinates(bounding_box, &holes);
+        for &source in coordinates.iter() {
+            for &destination in coordinates.iter() {
+                if source == destination {
+                    continue;
+                }
+                // every node is reachable by changing channels
+                let (path, channels) =
+                    make_path_with_channels(&protocol, source, destination).unwrap();
+                assert!(obeys_west_first(&path, &channels), "{:?}", path);
+                assert!(!path.iter().any(|coordinate| holes.contains(coordinate)));
+            }
+        }
+        // detour to east
+        let path = make_path(&protocol, (1, 2), (3, 2)).unwrap();
+        assert_eq!(path, vec![(1, 2), (1, 1), (2, 1), (3, 1), (3, 2)]);
+        // detour to west: go west first, and go around the hole
+        let path = make_path(&protocol, (3, 1), (1, 3)).unwrap();
+        assert_eq!(path.len(), 5);
+        // the hole is right on the west, so the packet turns to west in the next channel
+        let (path, channels) = make_path_with_channels(&protocol, (3, 2), (1, 2)).unwrap();
+        assert_eq!(path, vec![(3, 2), (3, 1), (2, 1), (1, 1), (1, 2)]);
+        assert_eq!(channels, vec![0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_dead_node_and_recovery() {
+        let bounding_box = BoundingBox::new((0, 0), (2, 2)).unwrap();
+        let mut protocol = make_protocol(bounding_box, &[]);
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        assert_eq!(
+            make_path(&protocol, (0, 1), (2, 1)).unwrap(),
+            vec![(0, 1), (1, 1), (2, 1)]
+        );
+
+        protocol.remove_node(ip((1, 1))).unwrap();
+        let path = make_path(&protocol, (0, 1), (2, 1)).unwrap();
+        assert_eq!(path.len(), 5);
+        assert!(!path.contains(&(1, 1)));
+        assert!(protocol.get_next_node(ip((0, 1)), ip((1, 1))).is_err());
+
+        // the node comes back
+        protocol.add_connection(ip((0, 1)), ip((1, 1))).unwrap();
+        protocol.add_connection(ip((1, 1)), ip((2, 1))).unwrap();
+        assert_eq!(
+            make_path(&protocol, (0, 1), (2, 1)).unwrap(),
+            vec![(0, 1), (1, 1), (2, 1)]
+        );
+        // only neighbors can be connected
+        assert!(protocol.add_connection(ip((0, 0)), ip((2, 2))).is_err());
+    }
+
+    #[test]
+    fn test_unknown_nodes_are_assumed_alive() {
+        let bounding_box = BoundingBox::new((0, 0), (3, 0)).unwrap();
+        let mut protocol = AdaptiveProtocol::with_bounding_box(bounding_box);
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        assert_eq!(
+            protocol.get_next_node(ip((0, 0)), ip((3, 0))).unwrap(),
+            ip((1, 0))
+        );
+        protocol.remove_connection(ip((1, 0)), ip((2, 0))).unwrap();
+        assert!(protocol.get_next_node(ip((0, 0)), ip((3, 0))).is_err());
+    }
+
+    #[test]
+    fn test_each_node_reports_own_connections() {
+        // NetworkNode tells the protocol only the connections to its own neighbors
+        let bounding_box = BoundingBox::new((0, 0), (3, 1)).unwrap();
+        let mut protocol = AdaptiveProtocol::with_bounding_box(bounding_box);
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        protocol.add_connection(ip((0, 0)), ip((1, 0))).unwrap();
+        protocol.add_connection(ip((0, 0)), ip((0, 1))).unwrap();
+        // connections beyond the neighbors are not reported, so they are assumed alive
+        assert_eq!(
+            protocol.get_next_node(ip((0, 0)), ip((3, 0))).unwrap(),
+            ip((1, 0))
+        );
+        assert_eq!(make_path(&protocol, (0, 0), (3, 1)).unwrap().len(), 5);
+        // the lost neighbor is avoided
+        protocol.remove_connection(ip((0, 0)), ip((1, 0))).unwrap();
+        assert_eq!(
+            make_path(&protocol, (0, 0), (3, 0)).unwrap(),
+            vec![(0, 0), (0, 1), (1, 1), (2, 1), (3, 1), (3, 0)]
+        );
+    }
+
+    #[test]
+    fn test_conformance() {
+        // each node knows only its own connections as NetworkNode
+        Conformance::new(AdaptiveProtocol::new)
+            .check_all(&Topology::grids())
+            .unwrap();
+        // a hole is known only by the nodes next to it, so routes around holes need all connections
+        Conformance::new(AdaptiveProtocol::new)
+            .set_connection_report(ConnectionReport::All)
+            .check_all(
+                &Topology::grids()
+                    .into_iter()
+                    .chain(Topology::grids_with_holes())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+    }
+}
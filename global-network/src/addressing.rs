@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem::size_of;
+
+use anyhow::{anyhow, Result};
+use network_node::addressing::AddressingScheme;
+use network_node::packet::GROUP_ID_BASE;
+use network_node::utils::type_alias::{Coordinate, CoordinateComponent, Id};
+
+/// address(16) | x(16) | y(16)
+const ENTRY_LENGTH: usize = size_of::<Id>() + size_of::<CoordinateComponent>() * 2;
+
+/// addresses which the root has assigned, which are handed out to joining nodes
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct AddressTable {
+    coordinates: BTreeMap<Id, Coordinate>,
+}
+
+impl AddressTable {
+    fn to_address(&self, coordinate: Coordinate) -> Option<Id> {
+        self.coordinates
+            .iter()
+            .find(|(_, known)| **known == coordinate)
+            .map(|(address, _)| *address)
+    }
+    fn to_coordinate(&self, address: Id) -> Result<Coordinate> {
+        self.coordinates
+            .get(&address)
+            .copied()
+            .ok_or_else(|| anyhow!("address {} is not assigned", address))
+    }
+    /// the node moved to the coordinate, so the old address of the coordinate is removed.
+    fn insert(&mut self, address: Id, coordinate: Coordinate) {
+        self.coordinates.retain(|_, known| *known != coordinate);
+        self.coordinates.insert(address, coordinate);
+    }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.coordinates.len() * ENTRY_LENGTH);
+        for (address, (x, y)) in self.coordinates.iter() {
+            bytes.extend(address.to_be_bytes());
+            bytes.extend(x.to_be_bytes());
+            bytes.extend(y.to_be_bytes());
+        }
+        bytes
+    }
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if !bytes.len().is_multiple_of(ENTRY_LENGTH) {
+            return Err(anyhow!("invalid address table: {:?}", bytes));
+        }
+        let coordinates = bytes
+            .chunks(ENTRY_LENGTH)
+            .map(|entry| {
+                let address = Id::from_be_bytes([entry[0], entry[1]]);
+                let x = CoordinateComponent::from_be_bytes([entry[2], entry[3]]);
+                let y = CoordinateComponent::from_be_bytes([entry[4], entry[5]]);
+                (address, (x, y))
+            })
+            .collect();
+        Ok(Self { coordinates })
+    }
+}
+
+/// Addresses are hashes of coordinates, so the network is not limited by a bounding box.
+///
+/// Any node computes the address of a coordinate, but a coordinate of an address is known only
+/// from the table which the root hands out. When hashes collide, the root assigns the next free
+/// address, so the table is also looked up first by to_address.
+#[derive(Debug, Default, Clone)]
+pub struct SparseHashAddressing {
+    table: AddressTable,
+    /// mac address -> ip address. only the root has it.
+    leases: HashMap<Id, Id>,
+}
+
+impl SparseHashAddressing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// FNV-1a of the coordinate, which is less than GROUP_ID_BASE
+    fn hash(coordinate: Coordinate) -> Id {
+        let mut hash: u32 = 0x811C_9DC5;
+        for byte in coordinate
+            .0
+            .to_be_bytes()
+            .into_iter()
+            .chain(coordinate.1.to_be_bytes())
+        {
+            hash ^= byte as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+        (hash % GROUP_ID_BASE as u32) as Id
+    }
+}
+
+impl AddressingScheme for SparseHashAddressing {
+    fn assign(&mut self, mac_address: Id, coordinate: Coordinate) -> Result<Id> {
+        let address = match self.table.to_address(coordinate) {
+            Some(address) => address,
+            None => {
+                let hash = Self::hash(coordinate);
+                let address = (0..GROUP_ID_BASE)
+                    .map(|offset| (hash + offset) % GROUP_ID_BASE)
+                    .find(|address| !self.table.coordinates.contains_key(address))
+                    .ok_or_else(|| anyhow!("no address is left for {:?}", coordinate))?;
+                self.table.insert(address, coordinate);
+                address
+            }
+        };
+        // the node moved, so the address of the old coordinate is released
+        if let Some(old) = self.leases.insert(mac_address, address) {
+            if old != address {
+                self.table.coordinates.remove(&old);
+            }
+        }
+        Ok(address)
+    }
+    fn to_address(&self, coordinate: Coordinate) -> Result<Id> {
+        Ok(self
+            .table
+            .to_address(coordinate)
+            .unwrap_or_else(|| Self::hash(coordinate)))
+    }
+    fn to_coordinate(&self, address: Id) -> Result<Coordinate> {
+        self.table.to_coordinate(address)
+    }
+    fn address_of(&self, mac_address: Id) -> Option<Id> {
+        self.leases.get(&mac_address).copied()
+    }
+    fn release(&mut self, mac_address: Id, address: Id) {
+        if self.leases.get(&mac_address) == Some(&address) {
+            self.leases.remove(&mac_address);
+            self.table.coordinates.remove(&address);
+        }
+    }
+    fn get_parameters(&self) -> Vec<u8> {
+        self.table.to_bytes()
+    }
+    fn set_parameters(&mut self, parameters: &[u8]) -> Result<()> {
+        self.table = AddressTable::from_bytes(parameters)?;
+        Ok(())
+    }
+}
+
+/// Addresses are leased by the root like DHCP: a node gets the smallest free address, and one
+/// which joins again with the same mac address (e.g. to renew the lease) gets the same address.
+/// An address whose lease expired is released and assigned again.
+///
+/// Addresses don't depend on coordinates at all, so both directions are looked up in the table
+/// which the root hands out.
+#[derive(Debug, Default, Clone)]
+pub struct RootAssignedAddressing {
+    table: AddressTable,
+    /// mac address -> ip address. only the root has it.
+    leases: HashMap<Id, Id>,
+}
+
+impl RootAssignedAddressing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AddressingScheme for RootAssignedAddressing {
+    fn assign(&mut self, mac_address: Id, coordinate: Coordinate) -> Result<Id> {
+        let address = match self.leases.get(&mac_address) {
+            Some(address) => *address,
+            None => {
+                let used: HashSet<Id> = self.leases.values().copied().collect();
+                let address = (0..GROUP_ID_BASE)
+                    .find(|address| !used.contains(address))
+                    .ok_or_else(|| anyhow!("no address is left for {}", mac_address))?;
+                self.leases.insert(mac_address, address);
+                address
+            }
+        };
+        self.table.insert(address, coordinate);
+        Ok(address)
+    }
+    fn to_address(&self, coordinate: Coordinate) -> Result<Id> {
+        self.table
+            .to_address(coordinate)
+            .ok_or_else(|| anyhow!("no address is assigned to {:?}", coordinate))
+    }
+    fn to_coordinate(&self, address: Id) -> Result<Coordinate> {
+        self.table.to_coordinate(address)
+    }
+    fn address_of(&self, mac_address: Id) -> Option<Id> {
+        self.leases.get(&mac_address).copied()
+    }
+    fn release(&mut self, mac_address: Id, address: Id) {
+        if self.leases.get(&mac_address) == Some(&address) {
+            self.leases.remove(&mac_address);
+            self.table.coordinates.remove(&address);
+        }
+    }
+    fn get_parameters(&self) -> Vec<u8> {
+        self.table.to_bytes()
+    }
+    fn set_parameters(&mut self, parameters: &[u8]) -> Result<()> {
+        self.table = AddressTable::from_bytes(parameters)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sparse_hash_addressing() {
+        let mut root = SparseHashAddressing::new();
+        let coordinates = [(0, 0), (-300, 200), (1000, -1000), (5, 7)];
+        let addresses: Vec<Id> = coordinates
+            .iter()
+            .enumerate()
+            .map(|(mac_address, coordinate)| root.assign(mac_address as Id, *coordinate).unwrap())
+            .collect();
+        for (coordinate, address) in coordinates.iter().zip(addresses.iter()) {
+            assert!(*address < GROUP_ID_BASE);
+            assert_eq!(root.to_address(*coordinate).unwrap(), *address);
+            assert_eq!(root.to_coordinate(*address).unwrap(), *coordinate);
+        }
+        // the same coordinate gets the same address
+        assert_eq!(root.assign(3, (5, 7)).unwrap(), addresses[3]);
+        assert_eq!(root.address_of(3), Some(addresses[3]));
+
+        // a collision is resolved by the next address
+        let colliding = (-300, 200);
+        root.table.coordinates.remove(&addresses[1]);
+        root.table.insert(addresses[1], (9, 9));
+        let address = root.assign(4, colliding).unwrap();
+        assert_ne!(address, addresses[1]);
+
+        let mut node = SparseHashAddressing::new();
+        node.set_parameters(&root.get_parameters()).unwrap();
+        assert_eq!(node.to_address(colliding).unwrap(), address);
+        assert_eq!(node.to_coordinate(address).unwrap(), colliding);
+        // addresses of unknown coordinates are computed, but their coordinates are unknown
+        let unknown = node.to_address((50, 50)).unwrap();
+        assert!(node.to_coordinate(unknown).is_err());
+        assert_eq!(node.address_of(4), None);
+
+        // the node moves, so its old address is released
+        let moved = root.assign(4, (50, 50)).unwrap();
+        assert_eq!(root.address_of(4), Some(moved));
+        assert!(root.to_coordinate(address).is_err());
+        // a stale release doesn't remove the new lease
+        root.release(4, address);
+        assert_eq!(root.to_coordinate(moved).unwrap(), (50, 50));
+    }
+
+    #[test]
+    fn test_root_assigned_addressing() {
+        let mut root = RootAssignedAddressing::new();
+        assert_eq!(root.assign(0x10, (0, 0)).unwrap(), 0);
+        assert_eq!(root.assign(0x20, (1, 0)).unwrap(), 1);
+        // the node joins again after it moved
+        assert_eq!(root.assign(0x10, (0, 1)).unwrap(), 0);
+        assert_eq!(root.to_coordinate(0).unwrap(), (0, 1));
+        assert_eq!(root.address_of(0x10), Some(0));
+        assert!(root.to_address((0, 0)).is_err());
+
+        let mut node = RootAssignedAddressing::new();
+        node.set_parameters(&root.get_parameters()).unwrap();
+        assert_eq!(node.to_address((1, 0)).unwrap(), 1);
+        assert_eq!(node.to_coordinate(0).unwrap(), (0, 1));
+        assert!(node.to_coordinate(2).is_err());
+        assert!(node.set_parameters(&[0, 1, 2]).is_err());
+
+        // the address of an expired lease is assigned to a new node
+        root.release(0x10, 0);
+        assert!(root.to_coordinate(0).is_err());
+        assert_eq!(root.assign(0x30, (2, 0)).unwrap(), 0);
+        assert_eq!(root.assign(0x10, (0, 1)).unwrap(), 2);
+        // a stale release doesn't remove the new lease
+        root.release(0x10, 0);
+        assert_eq!(root.to_coordinate(0).unwrap(), (2, 0));
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashSet;
+
+use network_node::utils::type_alias::Id;
+
+/// connections between neighbors in the grid which are reported by add_connection and
+/// remove_connection.
+/// add_connection(id, id2) is a report by id of its connection to id2, as NetworkNode tells the
+/// connections to its own neighbors. so id is reported, but id2 is not.
+/// the grid is assumed to be full until a node is reported: connections of a node which has
+/// never been reported are alive, so a node can route before it knows the whole network.
+/// once a node is reported, only its added connections are alive.
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    /// alive connections. each connection is kept as (smaller id, larger id).
+    connections: HashSet<(Id, Id)>,
+    /// nodes which reported their own connections
+    known_nodes: HashSet<Id>,
+}
+
+impl Connections {
+    fn key(id: Id, id2: Id) -> (Id, Id) {
+        (id.min(id2), id.max(id2))
+    }
+
+    pub fn add(&mut self, id: Id, id2: Id) {
+        self.connections.insert(Self::key(id, id2));
+        self.known_nodes.insert(id);
+    }
+
+    pub fn remove(&mut self, id: Id, id2: Id) {
+        self.connections.remove(&Self::key(id, id2));
+        self.known_nodes.insert(id);
+    }
+
+    /// remove all connections of the node
+    pub fn remove_node(&mut self, id: Id) {
+        self.connections
+            .retain(|&(id1, id2)| id1 != id && id2 != id);
+        self.known_nodes.insert(id);
+    }
+
+    pub fn is_connected(&self, id: Id, id2: Id) -> bool {
+        self.connections.contains(&Self::key(id, id2))
+            || (!self.known_nodes.contains(&id) && !self.known_nodes.contains(&id2))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connections() {
+        let mut connections = Connections::default();
+        // nobody is reported
+        assert!(connections.is_connected(0, 1));
+
+        // 1 reports its own connections
+        connections.add(1, 0);
+        connections.remove(1, 2);
+        assert!(connections.is_connected(0, 1));
+        assert!(!connections.is_connected(2, 1));
+        assert!(!connections.is_connected(1, 3));
+        // connections of its neighbors are not reported yet
+        assert!(connections.is_connected(0, 3));
+        assert!(connections.is_connected(2, 3));
+
+        connections.remove_node(0);
+        assert!(!connections.is_connected(0, 1));
+        assert!(!connections.is_connected(0, 3));
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+
+use anyhow::{anyhow, Result};
+use network_node::packet::{ToId, MAX_MESSAGES_LENGTH};
+use network_node::protocol::{Millis, Protocol, RouteEntry, RoutingMessage};
+use network_node::utils::type_alias::{Coordinate, Id};
+
+use crate::links::Links;
+use crate::{BoundingBox, Distance, Route, INFINITY};
+
+/// interval of sending distance vectors to neighbors
+pub const UPDATE_INTERVAL: Millis = 3000;
+/// a route which is not advertised again in this time is removed.
+/// a neighbor which is not heard in this time is considered dead.
+pub const ROUTE_TIMEOUT: Millis = UPDATE_INTERVAL * 4;
+/// unreachable destinations are advertised with INFINITY in this time,
+/// so that neighbors remove them before the route timeout.
+pub const WITHDRAW_TIMEOUT: Millis = UPDATE_INTERVAL * 2;
+/// after a route is lost or becomes longer, longer routes to the destination are ignored in
+/// this time, so that stale routes in loops don't count to infinity.
+pub const HOLD_DOWN_TIME: Millis = UPDATE_INTERVAL * 2;
+
+/// destination(16) | distance(8)
+const ENTRY_LENGTH: usize = size_of::<Id>() + size_of::<Distance>();
+
+/// Distance vector routing protocol.
+///
+/// Each node sends its distance vector to neighbors periodically and when its routes change
+/// (triggered update). A node chooses the neighbor which advertises the shortest distance, so
+/// routes converge to the shortest paths of the actual topology, which doesn't need to be a grid.
+///
+/// - split horizon: a route is not advertised to its next hop. With poisoned reverse (default),
+///   it is advertised with INFINITY instead, so the next hop removes the route through this node
+///   at once.
+/// - route timeout: an entry which is not advertised again in ROUTE_TIMEOUT is removed.
+/// - hold-down: a destination whose route is lost or becomes longer is unreachable until a route
+///   which is not longer than the old one is found or HOLD_DOWN_TIME passes.
+/// - liveness: each update has a hello to all neighbors. a neighbor is alive while its messages
+///   are received, or while the connection is reported by add_connection. remove_connection
+///   removes the neighbor and routes through it.
+///
+/// Routes are kept per address, so they are learned again from scratch when the bounding box
+/// changes.
+pub struct DistanceVectorProtocol {
+    bounding_box: BoundingBox,
+    is_poisoned_reverse: bool,
+    /// ip address of this node. it is known by update or process_routing_message.
+    this_id: Option<Id>,
+    /// the latest time which is given by NetworkNode
+    now: Millis,
+    links: Links,
+    /// vectors from neighbors: neighbor -> destination -> (distance, received time)
+    vectors: HashMap<Id, HashMap<Id, (Distance, Millis)>>,
+    routes: BTreeMap<Id, Route>,
+    /// destinations which became unreachable, and when
+    withdrawn: HashMap<Id, Millis>,
+    /// destination -> (distance of the lost route, when it is lost)
+    hold_downs: HashMap<Id, (Distance, Millis)>,
+    last_update: Option<Millis>,
+    is_triggered: bool,
+}
+
+impl DistanceVectorProtocol {
+    pub fn new() -> Self {
+        Self::with_bounding_box(BoundingBox::default())
+    }
+    pub fn with_bounding_box(bounding_box: BoundingBox) -> Self {
+        Self {
+            bounding_box,
+            is_poisoned_reverse: true,
+            this_id: None,
+            now: 0,
+            links: Links::default(),
+            vectors: HashMap::new(),
+            routes: BTreeMap::new(),
+            withdrawn: HashMap::new(),
+            hold_downs: HashMap::new(),
+            last_update: None,
+            is_triggered: false,
+        }
+    }
+    /// if it is false, routes are not advertised to their next hops at all (simple split horizon).
+    pub fn set_poisoned_reverse(&mut self, is_poisoned_reverse: bool) {
+        self.is_poisoned_reverse = is_poisoned_reverse;
+    }
+    pub fn get_bounding_box(&self) -> BoundingBox {
+        self.bounding_box
+    }
+    pub fn get_route(&self, destination: Id) -> Option<Route> {
+        self.routes.get(&destination).copied()
+    }
+    /// routing table of this node: destination -> route
+    pub fn get_routes(&self) -> &BTreeMap<Id, Route> {
+        &self.routes
+    }
+
+    /// remove dead neighbors and old entries
+    fn expire(&mut self, now: Millis) {
+        let is_alive = |time: Millis| now.saturating_sub(time) <= ROUTE_TIMEOUT;
+        self.links.expire(now, ROUTE_TIMEOUT);
+        for vector in self.vectors.values_mut() {
+            vector.retain(|_, (_, received)| is_alive(*received));
+        }
+        self.withdrawn
+            .retain(|_, time| now.saturating_sub(*time) <= WITHDRAW_TIMEOUT);
+        self.hold_downs
+            .retain(|_, (_, time)| now.saturating_sub(*time) <= HOLD_DOWN_TIME);
+    }
+
+    /// recompute routes by the vectors of neighbors (Bellman-Ford)
+    fn recompute(&mut self) {
+        let this = match self.this_id {
+            Some(this) => this,
+            None => return,
+        };
+        let neighbors = self.links.neighbors(this);
+        self.vectors
+            .retain(|neighbor, _| neighbors.contains(neighbor));
+
+        let mut routes = BTreeMap::new();
+        let hold_downs = &self.hold_downs;
+        let mut offer = |destination: Id, next_hop: Id, distance: Distance| {
+            if destination == this || distance == INFINITY {
+                return;
+            }
+            if matches!(hold_downs.get(&destination), Some((held, _)) if distance > *held) {
+                return;
+            }
+            let route = Route { next_hop, distance };
+            routes
+                .entry(destination)
+                .and_modify(|current: &mut Route| {
+                    if (distance, next_hop) < (current.distance, current.next_hop) {
+                        *current = route;
+                    }
+                })
+                .or_insert(route);
+        };
+        for &neighbor in neighbors.iter() {
+            offer(neighbor, neighbor, 1);
+            if let Some(vector) = self.vectors.get(&neighbor) {
+                for (&destination, &(distance, _)) in vector.iter() {
+                    offer(destination, neighbor, distance.saturating_add(1));
+                }
+            }
+        }
+
+        for (destination, old) in self.routes.iter() {
+            let is_worse = match routes.get(destination) {
+                Some(route) => route.distance > old.distance,
+                None => true,
+            };
+            if is_worse {
+                routes.remove(destination);
+                self.hold_downs
+                    .insert(*destination, (old.distance, self.now));
+                self.withdrawn.insert(*destination, self.now);
+            }
+        }
+        for destination in routes.keys() {
+            self.withdrawn.remove(destination);
+            self.hold_downs.remove(destination);
+        }
+        if routes != self.routes {
+            self.is_triggered = true;
+            self.routes = routes;
+        }
+    }
+
+    /// distance vector for the neighbor, which applies split horizon
+    fn make_vector(&self, this: Id, neighbor: Id) -> Vec<(Id, Distance)> {
+        let mut vector = vec![(this, 0)];
+        for (&destination, route) in self.routes.iter() {
+            if route.next_hop != neighbor {
+                vector.push((destination, route.distance));
+            } else if self.is_poisoned_reverse {
+                vector.push((destination, INFINITY));
+            }
+        }
+        vector.extend(
+            self.withdrawn
+                .keys()
+                .map(|destination| (*destination, INFINITY)),
+        );
+        vector
+    }
+
+    fn make_messages(&self, this: Id) -> Vec<RoutingMessage> {
+        // hello to all neighbors, so that new neighbors find this node
+        let mut messages = vec![RoutingMessage {
+            to: ToId::Broadcast,
+            messages: Self::encode(&[(this, 0)]),
+        }];
+        for neighbor in self.links.neighbors(this) {
+            let vector = self.make_vector(this, neighbor);
+            for entries in vector.chunks(MAX_MESSAGES_LENGTH / ENTRY_LENGTH) {
+                messages.push(RoutingMessage {
+                    to: ToId::Unicast(neighbor),
+                    messages: Self::encode(entries),
+                });
+            }
+        }
+        messages
+    }
+
+    fn encode(entries: &[(Id, Distance)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (destination, distance) in entries {
+            bytes.extend(destination.to_be_bytes());
+            bytes.push(*distance);
+        }
+        bytes
+    }
+
+    fn set_clock(&mut self, this: Id, now: Millis) {
+        if self.this_id != Some(this) {
+            self.this_id = Some(this);
+            self.routes.clear();
+        }
+        self.now = now;
+    }
+}
+
+impl Default for DistanceVectorProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Protocol for DistanceVectorProtocol {
+    /// this node knows only its own routes, so it is decided by poisoned reverse:
+    /// a neighbor advertises INFINITY to this node if this node is its next hop.
+    fn is_in_route(&self, this: Id, source: Id, destination: Id) -> bool {
+        if this == source || this == destination {
+            return true;
+        }
+        let is_next_hop_of_source = self
+            .vectors
+            .get(&source)
+            .and_then(|vector| vector.get(&destination))
+            .is_some_and(|(distance, _)| *distance == INFINITY);
+        is_next_hop_of_source && self.routes.contains_key(&destination)
+    }
+    fn get_next_node(&self, this: Id, destination: Id) -> Result<Id> {
+        if let Some(route) = self.routes.get(&destination) {
+            return Ok(route.next_hop);
+        }
+        // connection which is reported before the next update
+        if self.links.contains(this, destination) {
+            return Ok(destination);
+        }
+        Err(anyhow!("no route from {} to {}", this, destination))
+    }
+    fn add_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.links.report(id, id2);
+        self.recompute();
+        Ok(())
+    }
+    fn remove_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.links.remove(id, id2);
+        self.recompute();
+        Ok(())
+    }
+    fn join_global_network(&mut self, _mac_address: Id, coordinate: Coordinate) -> Result<Id> {
+        self.bounding_box = self.bounding_box.including(coordinate)?;
+        self.bounding_box.to_ip_address(coordinate)
+    }
+    fn get_routing_parameters(&self) -> Vec<u8> {
+        self.bounding_box.to_bytes()
+    }
+    fn set_routing_parameters(&mut self, parameters: &[u8]) -> Result<()> {
+        self.bounding_box = BoundingBox::from_bytes(parameters)?;
+        Ok(())
+    }
+    /// distance of the route, which converges to the number of hops.
+    fn get_distance(&self, this: Id, destination: Id) -> Result<u16> {
+        if this == destination {
+            return Ok(0);
+        }
+        if let Some(route) = self.routes.get(&destination) {
+            return Ok(route.distance as u16);
+        }
+        // connection which is reported before the next update
+        if self.links.contains(this, destination) {
+            return Ok(1);
+        }
+        Err(anyhow!("no route from {} to {}", this, destination))
+    }
+
+    /// age is the time since the next hop advertised the route.
+    fn get_routing_table(&self, _this: Id, now: Millis) -> Vec<RouteEntry> {
+        self.routes
+            .iter()
+            .map(|(&destination, route)| {
+                let received_at = self
+                    .vectors
+                    .get(&route.next_hop)
+                    .and_then(|vector| vector.get(&destination))
+                    .map_or(now, |(_, received_at)| *received_at);
+                RouteEntry {
+                    destination,
+                    next_hop: route.next_hop,
+                    metric: route.distance as u16,
+                    channel: 0,
+                    age: now.saturating_sub(received_at),
+                }
+            })
+            .collect()
+    }
+    fn update(&mut self, this: Id, now: Millis) -> Vec<RoutingMessage> {
+        self.set_clock(this, now);
+        self.expire(now);
+        self.recompute();
+        let is_periodic = self
+            .last_update
+            .is_none_or(|last| now.saturating_sub(last) >= UPDATE_INTERVAL);
+        if !is_periodic && !self.is_triggered {
+            return Vec::new();
+        }
+        self.last_update = Some(now);
+        self.is_triggered = false;
+        self.make_messages(this)
+    }
+    fn process_routing_message(
+        &mut self,
+        this: Id,
+        neighbor: Id,
+        messages: &[u8],
+        now: Millis,
+    ) -> Result<Vec<RoutingMessage>> {
+        if neighbor == this {
+            return Ok(Vec::new());
+        }
+        if !messages.len().is_multiple_of(ENTRY_LENGTH) {
+            return Err(anyhow!("invalid distance vector: {:?}", messages));
+        }
+        self.set_clock(this, now);
+        self.links.hear(this, neighbor, now);
+        let vector = self.vectors.entry(neighbor).or_default();
+        for entry in messages.chunks(ENTRY_LENGTH) {
+            let destination = Id::from_be_bytes([entry[0], entry[1]]);
+            vector.insert(destination, (entry[2], now));
+        }
+        self.recompute();
+        // triggered update
+        if self.is_triggered {
+            return Ok(self.update(this, now));
+        }
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use network_node::conformance::{Conformance, ConnectionReport, Network, Topology};
+
+    /// pendant of the ring
+    const PENDANT: Coordinate = (1, 2);
+
+    /// ring of 6 nodes with a chord between (1, 0) and (1, 1), and a pendant at (1, 2).
+    /// neighbors are found by the distance vectors, so broken connections time out.
+    fn make_network() -> Network<DistanceVectorProtocol> {
+        let topology = Topology::grid((0, 0), (2, 2)).without_nodes(&[(0, 2), (2, 2)]);
+        Conformance::new(DistanceVectorProtocol::new)
+            .set_updates(3, UPDATE_INTERVAL)
+            .set_connection_report(ConnectionReport::None)
+            .build(&topology)
+            .unwrap()
+    }
+
+    /// routes of every node take the shortest paths of the current topology
+    fn assert_shortest_paths(network: &Network<DistanceVectorProtocol>) {
+        let topology = network.get_topology();
+        for &source in topology.get_nodes() {
+            for &destination in topology.get_nodes() {
+                if source == destination {
+                    continue;
+                }
+                let distance = topology.distance(source, destination);
+                let route = network
+                    .get_protocol(source)
+                    .get_route(network.get_id(destination));
+                assert_eq!(
+                    route.map(|route| route.distance as usize),
+                    distance,
+                    "{:?} -> {:?}",
+                    source,
+                    destination
+                );
+                if distance.is_some() {
+                    network.check_route(source, destination).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_converge_to_shortest_paths() {
+        let network = make_network();
+        assert_shortest_paths(&network);
+
+        // (0, 0) reaches the pendant through either (1, 0) or (0, 1)
+        let (source, pendant) = (network.get_id((0, 0)), network.get_id(PENDANT));
+        let protocol = network.get_protocol((0, 0));
+        let next = protocol.get_next_node(source, pendant).unwrap();
+        let east = network.get_id((1, 0));
+        assert!(next == east || next == network.get_id((0, 1)));
+        // the next hop poisons the route to (0, 0), so only it is in route
+        assert_eq!(
+            network
+                .get_protocol((1, 0))
+                .is_in_route(east, source, pendant),
+            next == east
+        );
+        assert!(network
+            .get_protocol(PENDANT)
+            .get_next_node(pendant, pendant)
+            .is_err());
+
+        // routing table lists the same routes
+        let table = protocol.get_routing_table(source, network.get_now());
+        assert_eq!(table.len(), protocol.get_routes().len());
+        let entry = table
+            .iter()
+            .find(|entry| entry.destination == pendant)
+            .unwrap();
+        assert_eq!(entry.next_hop, next);
+        assert_eq!(
+            entry.metric,
+            protocol.get_route(pendant).unwrap().distance as u16
+        );
+        assert!(entry.age <= UPDATE_INTERVAL);
+    }
+
+    #[test]
+    fn test_link_down_by_remove_connection() {
+        let mut network = make_network();
+        let pendant = network.get_id(PENDANT);
+        // the chord is broken, and both ends detect it
+        let (east, center) = (network.get_id((1, 0)), network.get_id((1, 1)));
+        network.disconnect((1, 0), (1, 1));
+        for node in [(1, 0), (1, 1)] {
+            network
+                .get_protocol_mut(node)
+                .remove_connection(east, center)
+                .unwrap();
+        }
+        // the route becomes longer, so it is held down for a while
+        network.tick(1).unwrap();
+        assert!(network.get_protocol((1, 0)).get_route(pendant).is_none());
+        network.tick(HOLD_DOWN_TIME).unwrap();
+        network.tick(UPDATE_INTERVAL).unwrap();
+        assert_shortest_paths(&network);
+        assert_eq!(
+            network
+                .get_protocol((1, 0))
+                .get_route(pendant)
+                .unwrap()
+                .distance,
+            4
+        );
+
+        // the pendant is removed
+        network.disconnect((1, 1), PENDANT);
+        network
+            .get_protocol_mut((1, 1))
+            .remove_connection(center, pendant)
+            .unwrap();
+        network.tick(1).unwrap();
+        for &node in network.get_topology().get_nodes() {
+            if node != PENDANT {
+                assert!(network.get_protocol(node).get_route(pendant).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_route_timeout() {
+        let mut network = make_network();
+        let pendant = network.get_id(PENDANT);
+        // the pendant stops without any notification
+        network.disconnect((1, 1), PENDANT);
+        let mut elapsed = 0;
+        while network.get_protocol((0, 0)).get_route(pendant).is_some() {
+            network.tick(UPDATE_INTERVAL).unwrap();
+            elapsed += UPDATE_INTERVAL;
+            assert!(elapsed <= ROUTE_TIMEOUT * 2, "route does not time out");
+        }
+        assert_shortest_paths(&network);
+    }
+
+    #[test]
+    fn test_split_horizon() {
+        let mut protocol = DistanceVectorProtocol::new();
+        protocol.add_connection(0, 1).unwrap();
+        protocol.add_connection(0, 2).unwrap();
+        // node 1 knows node 3
+        protocol
+            .process_routing_message(0, 1, &[0, 1, 0, 0, 3, 1], 0)
+            .unwrap();
+        assert_eq!(
+            protocol.get_route(3),
+            Some(Route {
+                next_hop: 1,
+                distance: 2
+            })
+        );
+        assert!(protocol.make_vector(0, 1).contains(&(3, INFINITY)));
+        assert!(protocol.make_vector(0, 2).contains(&(3, 2)));
+        protocol.set_poisoned_reverse(false);
+        assert!(!protocol.make_vector(0, 1).iter().any(|(id, _)| *id == 3));
+        assert!(protocol.process_routing_message(0, 1, &[0], 0).is_err());
+    }
+
+    #[test]
+    fn test_conformance() {
+        Conformance::new(DistanceVectorProtocol::new)
+            .set_updates(3, UPDATE_INTERVAL)
+            .check_all(
+                &Topology::grids()
+                    .into_iter()
+                    .chain(Topology::grids_with_holes())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+    }
+}
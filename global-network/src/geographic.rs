@@ -0,0 +1,492 @@
+use std::f32::consts::TAU;
+
+use anyhow::{anyhow, Result};
+use network_node::protocol::{ChannelId, Protocol, CHANNEL_LENGTH};
+use network_node::utils::type_alias::{Coordinate, Id};
+
+use crate::connections::Connections;
+use crate::BoundingBox;
+
+/// channel of packets which are forwarded greedily first
+const GREEDY_CHANNEL: ChannelId = 0;
+/// channel of packets which have met a local minimum first and walk around the hole.
+/// packets in odd channels walk around holes, and packets in even channels are forwarded
+/// greedily.
+const PERIMETER_CHANNEL: ChannelId = 1;
+
+/// offsets of neighbors in counterclockwise order from east
+const NEIGHBOR_OFFSETS: [Coordinate; 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+fn squared_distance(coordinate: Coordinate, coordinate2: Coordinate) -> i32 {
+    let dx = coordinate.0 as i32 - coordinate2.0 as i32;
+    let dy = coordinate.1 as i32 - coordinate2.1 as i32;
+    dx * dx + dy * dy
+}
+
+/// counterclockwise angle from the vector `from` to the vector `to` in [0, 2π)
+fn counterclockwise_angle(from: (i32, i32), to: (i32, i32)) -> f32 {
+    let angle = (to.1 as f32).atan2(to.0 as f32) - (from.1 as f32).atan2(from.0 as f32);
+    angle.rem_euclid(TAU)
+}
+
+fn vector(from: Coordinate, to: Coordinate) -> (i32, i32) {
+    (to.0 as i32 - from.0 as i32, to.1 as i32 - from.1 as i32)
+}
+
+/// Geographic routing protocol which forwards packets by coordinates directly.
+///
+/// A packet is forwarded to the neighbor which is the closest to the destination (greedy mode).
+/// When no neighbor is closer than this node (e.g. a hole is between this node and the
+/// destination), the packet moves to the next channel and walks around the hole (perimeter
+/// mode): its first hop is the first neighbor counterclockwise from the line to the destination,
+/// and the next hops are the first neighbor counterclockwise from the previous node (right-hand
+/// rule). At a node which is closer to the destination than the previous node and has a closer
+/// neighbor, it moves to the next channel and is forwarded greedily again. If it meets a local
+/// minimum again, it walks around the hole by the left-hand rule in the last channel.
+/// Channels of a packet never decrease, so the packet cannot go back and forth between modes
+/// forever. A packet which cannot escape from complicated holes is stopped by its ttl.
+///
+/// It needs no routing table exchange. A node needs only the states of its own connections,
+/// which are learned by add_connection/remove_connection (see Connections). Each address is the
+/// coordinate in the bounding box, so a node knows where the destination is without asking.
+pub struct GeographicProtocol {
+    bounding_box: BoundingBox,
+    connections: Connections,
+}
+
+impl GeographicProtocol {
+    pub fn new() -> Self {
+        Self::with_bounding_box(BoundingBox::default())
+    }
+    pub fn with_bounding_box(bounding_box: BoundingBox) -> Self {
+        Self {
+            bounding_box,
+            connections: Connections::default(),
+        }
+    }
+    pub fn get_bounding_box(&self) -> BoundingBox {
+        self.bounding_box
+    }
+
+    /// check id and id2 are neighbors in the bounding box
+    fn check_neighbors(&self, id: Id, id2: Id) -> Result<()> {
+        let coordinate = self.bounding_box.to_coordinate(id)?;
+        let coordinate2 = self.bounding_box.to_coordinate(id2)?;
+        if NEIGHBOR_OFFSETS.contains(&(coordinate2.0 - coordinate.0, coordinate2.1 - coordinate.1))
+        {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{:?} and {:?} are not neighbors",
+                coordinate,
+                coordinate2
+            ))
+        }
+    }
+
+    /// alive neighbors of the node in counterclockwise order from east
+    fn neighbors(&self, coordinate: Coordinate) -> Vec<Coordinate> {
+        let id = match self.bounding_box.to_ip_address(coordinate) {
+            Ok(id) => id,
+            Err(_) => return Vec::new(),
+        };
+        NEIGHBOR_OFFSETS
+            .iter()
+            .filter_map(|(dx, dy)| {
+                let neighbor = (
+                    coordinate.0.checked_add(*dx)?,
+                    coordinate.1.checked_add(*dy)?,
+                );
+                let neighbor_id = self.bounding_box.to_ip_address(neighbor).ok()?;
+                self.connections
+                    .is_connected(id, neighbor_id)
+                    .then_some(neighbor)
+            })
+            .collect()
+    }
+
+    /// the neighbor which is closer to the destination than this node
+    fn greedy_next(
+        this: Coordinate,
+        neighbors: &[Coordinate],
+        destination: Coordinate,
+    ) -> Option<Coordinate> {
+        neighbors
+            .iter()
+            .copied()
+            .filter(|neighbor| {
+                squared_distance(*neighbor, destination) < squared_distance(this, destination)
+            })
+            .min_by_key(|neighbor| squared_distance(*neighbor, destination))
+    }
+
+    /// the first neighbor counterclockwise (or clockwise) from the direction.
+    /// if `include_start` is false, the neighbor in the direction is the last candidate.
+    fn rotate_to_neighbor(
+        this: Coordinate,
+        neighbors: &[Coordinate],
+        direction: (i32, i32),
+        include_start: bool,
+        is_counterclockwise: bool,
+    ) -> Option<Coordinate> {
+        neighbors.iter().copied().min_by(|neighbor, neighbor2| {
+            let angle = |neighbor: Coordinate| {
+                let angle = if is_counterclockwise {
+                    counterclockwise_angle(direction, vector(this, neighbor))
+                } else {
+                    counterclockwise_angle(vector(this, neighbor), direction)
+                };
+                if angle == 0.0 && !include_start {
+                    TAU
+                } else {
+                    angle
+                }
+            };
+            angle(*neighbor).total_cmp(&angle(*neighbor2))
+        })
+    }
+
+    fn is_perimeter(channel: ChannelId) -> bool {
+        channel % 2 == 1
+    }
+    /// the first perimeter channel uses the right-hand rule, and the next one uses the left-hand
+    /// rule, so that the packet tries the other side of the hole.
+    fn is_right_hand(channel: ChannelId) -> bool {
+        channel == PERIMETER_CHANNEL
+    }
+
+    /// return the next hop and its channel
+    fn route(
+        &self,
+        this: Id,
+        previous: Option<Id>,
+        destination: Id,
+        channel: ChannelId,
+    ) -> Result<(Id, ChannelId)> {
+        let this_coordinate = self.bounding_box.to_coordinate(this)?;
+        let destination_coordinate = self.bounding_box.to_coordinate(destination)?;
+        if this_coordinate == destination_coordinate {
+            return Err(anyhow!("{:?} is already the destination", this_coordinate));
+        }
+        let previous_coordinate = match previous {
+            Some(previous) => Some(self.bounding_box.to_coordinate(previous)?),
+            None => None,
+        };
+        let neighbors = self.neighbors(this_coordinate);
+        let greedy_next = Self::greedy_next(this_coordinate, &neighbors, destination_coordinate);
+
+        let distance = squared_distance(this_coordinate, destination_coordinate);
+        // walk around the hole from the direction
+        let walk = |direction: (i32, i32), include_start: bool, channel: ChannelId| {
+            Self::rotate_to_neighbor(
+                this_coordinate,
+                &neighbors,
+                direction,
+                include_start,
+                Self::is_right_hand(channel),
+            )
+            .map(|next| (next, channel))
+            .ok_or_else(|| anyhow!("{:?} has no neighbor", this_coordinate))
+        };
+        // the first hop around the hole is decided from the line to the destination
+        let to_destination = vector(this_coordinate, destination_coordinate);
+
+        let (next, channel) = match previous_coordinate {
+            _ if !Self::is_perimeter(channel) => match greedy_next {
+                Some(next) => (next, channel),
+                // local minimum: start walking around the hole in the next channel
+                None => walk(to_destination, true, channel + 1)?,
+            },
+            // the source is a local minimum
+            None => walk(to_destination, true, channel)?,
+            Some(previous) => {
+                let is_closer = distance < squared_distance(previous, destination_coordinate);
+                match greedy_next {
+                    // the packet has got around the hole.
+                    // in the last channel, greedy forwarding continues while the packet gets
+                    // closer.
+                    Some(next) if is_closer => match channel as usize + 1 < CHANNEL_LENGTH {
+                        true => (next, channel + 1),
+                        false => (next, channel),
+                    },
+                    // another local minimum
+                    None if is_closer => walk(to_destination, true, channel)?,
+                    _ => walk(vector(this_coordinate, previous), false, channel)?,
+                }
+            }
+        };
+        Ok((self.bounding_box.to_ip_address(next)?, channel))
+    }
+}
+
+impl Default for GeographicProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Protocol for GeographicProtocol {
+    /// whether this node is on the path of a packet which is sent by the source.
+    /// it is decided by the connections which this node knows, so it may differ from the actual
+    /// path.
+    fn is_in_route(&self, this: Id, source: Id, destination: Id) -> bool {
+        let max_length = (self.bounding_box.width() * self.bounding_box.height() * 2) as usize;
+        let mut previous = None;
+        let mut current = source;
+        let mut channel = self.get_channel(source, destination);
+        for _ in 0..=max_length {
+            if current == this {
+                return true;
+            }
+            if current == destination {
+                return false;
+            }
+            match self.route(current, previous, destination, channel) {
+                Ok((next, next_channel)) => {
+                    previous = Some(current);
+                    current = next;
+                    channel = next_channel;
+                }
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+    fn get_next_node(&self, this: Id, destination: Id) -> Result<Id> {
+        let channel = self.get_channel(this, destination);
+        Ok(self.route(this, None, destination, channel)?.0)
+    }
+    fn get_next_hop(
+        &self,
+        this: Id,
+        previous: Id,
+        destination: Id,
+        channel: ChannelId,
+    ) -> Result<(Id, ChannelId)> {
+        self.route(this, Some(previous), destination, channel)
+    }
+    fn add_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.check_neighbors(id, id2)?;
+        self.connections.add(id, id2);
+        Ok(())
+    }
+    fn remove_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.check_neighbors(id, id2)?;
+        self.connections.remove(id, id2);
+        Ok(())
+    }
+    /// a packet from a local minimum starts in the perimeter channel
+    fn get_channel(&self, this: Id, destination: Id) -> ChannelId {
+        let (this, destination) = match (
+            self.bounding_box.to_coordinate(this),
+            self.bounding_box.to_coordinate(destination),
+        ) {
+            (Ok(this), Ok(destination)) => (this, destination),
+            _ => return GREEDY_CHANNEL,
+        };
+        match Self::greedy_next(this, &self.neighbors(this), destination) {
+            Some(_) => GREEDY_CHANNEL,
+            None => PERIMETER_CHANNEL,
+        }
+    }
+    fn join_global_network(&mut self, _mac_address: Id, coordinate: Coordinate) -> Result<Id> {
+        self.bounding_box = self.bounding_box.including(coordinate)?;
+        self.bounding_box.to_ip_address(coordinate)
+    }
+    fn get_routing_parameters(&self) -> Vec<u8> {
+        self.bounding_box.to_bytes()
+    }
+    /// addresses don't move when the bounding box is extended, so the learned connections are
+    /// kept.
+    fn set_routing_parameters(&mut self, parameters: &[u8]) -> Result<()> {
+        self.bounding_box = BoundingBox::from_bytes(parameters)?;
+        Ok(())
+    }
+    /// greedy forwarding takes the manhattan distance. perimeter mode may take longer.
+    fn get_distance(&self, this: Id, destination: Id) -> Result<u16> {
+        self.bounding_box.distance(this, destination)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use network_node::conformance::{Conformance, ConnectionReport, Topology};
+
+    /// make the protocol which knows all connections of the grid except holes, as if every node
+    /// has reported its own connections
+    fn make_protocol(bounding_box: BoundingBox, holes: &[Coordinate]) -> GeographicProtocol {
+        let mut protocol = GeographicProtocol::with_bounding_box(bounding_box);
+        let (min, max) = (bounding_box.get_min(), bounding_box.get_max());
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for neighbor in [(x + 1, y), (x, y + 1)] {
+                    if !bounding_box.contains(neighbor)
+                        || holes.contains(&(x, y))
+                        || holes.contains(&neighbor)
+                    {
+                        continue;
+                    }
+                    let id = bounding_box.to_ip_address((x, y)).unwrap();
+                    let id2 = bounding_box.to_ip_address(neighbor).unwrap();
+                    protocol.add_connection(id, id2).unwrap();
+                    protocol.add_connection(id2, id).unwrap();
+                }
+            }
+        }
+        protocol
+    }
+
+    /// follow get_next_hop from the source, and return coordinates and channels of the path.
+    fn make_path(
+        protocol: &GeographicProtocol,
+        source: Coordinate,
+        destination: Coordinate,
+    ) -> (Vec<Coordinate>, Vec<ChannelId>) {
+        let bounding_box = protocol.get_bounding_box();
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        let mut path = vec![source];
+        let mut channels = vec![protocol.get_channel(ip(source), ip(destination))];
+        let mut next = protocol.get_next_node(ip(source), ip(destination)).unwrap();
+        loop {
+            let this = *path.last().unwrap();
+            let next_coordinate = bounding_box.to_coordinate(next).unwrap();
+            assert!(protocol.neighbors(this).contains(&next_coordinate));
+            path.push(next_coordinate);
+            if next_coordinate == destination {
+                return (path, channels);
+            }
+            assert!(
+                path.len() <= (bounding_box.width() * bounding_box.height()) as usize,
+                "{:?} -> {:?}: {:?}",
+                source,
+                destination,
+                path
+            );
+            let (next_node, channel) = protocol
+                .get_next_hop(next, ip(this), ip(destination), *channels.last().unwrap())
+                .unwrap();
+            next = next_node;
+            channels.push(channel);
+        }
+    }
+
+    fn coordinates(bounding_box: BoundingBox, holes: &[Coordinate]) -> Vec<Coordinate> {
+        let (min, max) = (bounding_box.get_min(), bounding_box.get_max());
+        (min.0..=max.0)
+            .flat_map(|x| (min.1..=max.1).map(move |y| (x, y)))
+            .filter(|coordinate| !holes.contains(coordinate))
+            .collect()
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_full_grid_is_greedy() {
+        let bounding_box = BoundingBox::new((-2, -1), (2, 2)).unwrap();
+        let protocol = make_protocol(bounding_box, &[]);
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        let coordinates = coordinates(bounding_box, &[]);
+        for &source in coordinates.iter() {
+            for &destination in coordinates.iter() {
+                if source == destination {
+                    continue;
+                }
+                let (path, channels) = make_path(&protocol, source, destination);
+                let distance = (source.0 - destination.0).abs() + (source.1 - destination.1).abs();
+                assert_eq!(path.len() as i16 - 1, distance);
+                assert!(channels.iter().all(|channel| *channel == GREEDY_CHANNEL));
+                for &this in coordinates.iter() {
+                    assert_eq!(
+                        protocol.is_in_route(ip(this), ip(source), ip(destination)),
+                        path.contains(&this)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_route_around_holes() {
+        let bounding_box = BoundingBox::new((0, 0), (6, 6)).unwrap();
+        let hole_sets = [
+            vec![(2, 2), (2, 3)],
+            vec![
+                (2, 2),
+                (2, 3),
+                (2, 4),
+                (3, 2),
+                (3, 3),
+                (3, 4),
+                (4, 2),
+                (4, 3),
+                (4, 4),
+            ],
+            vec![(3, 1), (3, 2), (3, 3), (3, 4), (3, 5)],
+            vec![(1, 3), (2, 3), (3, 3), (4, 3), (5, 3), (6, 3)],
+        ];
+        for holes in hole_sets {
+            let protocol = make_protocol(bounding_box, &holes);
+            let coordinates = coordinates(bounding_box, &holes);
+            for &source in coordinates.iter() {
+                for &destination in coordinates.iter() {
+                    if source == destination {
+                        continue;
+                    }
+                    let (path, _) = make_path(&protocol, source, destination);
+                    assert!(!path.iter().any(|coordinate| holes.contains(coordinate)));
+                }
+            }
+        }
+
+        // the hole is right on the east, so the packet walks around it in the perimeter channel,
+        // and it is forwarded greedily again after it gets closer
+        let holes = [(1, 0), (1, 1), (1, 2)];
+        let protocol = make_protocol(BoundingBox::new((0, 0), (2, 3)).unwrap(), &holes);
+        let (path, channels) = make_path(&protocol, (0, 1), (2, 1));
+        assert_eq!(
+            path,
+            vec![(0, 1), (0, 2), (0, 3), (1, 3), (2, 3), (2, 2), (2, 1)]
+        );
+        assert_eq!(channels, vec![1, 1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_connections() {
+        let bounding_box = BoundingBox::new((0, 0), (3, 0)).unwrap();
+        let mut protocol = GeographicProtocol::with_bounding_box(bounding_box);
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        // unknown nodes are assumed to be alive
+        assert_eq!(
+            protocol.get_next_node(ip((0, 0)), ip((3, 0))).unwrap(),
+            ip((1, 0))
+        );
+        // (1, 0) is a dead end, so the packet goes back
+        protocol.remove_connection(ip((1, 0)), ip((2, 0))).unwrap();
+        protocol.add_connection(ip((0, 0)), ip((1, 0))).unwrap();
+        assert_eq!(
+            protocol
+                .get_next_hop(ip((1, 0)), ip((0, 0)), ip((3, 0)), GREEDY_CHANNEL)
+                .unwrap(),
+            (ip((0, 0)), PERIMETER_CHANNEL)
+        );
+        protocol.remove_connection(ip((0, 0)), ip((1, 0))).unwrap();
+        assert!(protocol.get_next_node(ip((0, 0)), ip((3, 0))).is_err());
+        // only neighbors can be connected
+        assert!(protocol.add_connection(ip((0, 0)), ip((2, 0))).is_err());
+    }
+
+    #[test]
+    fn test_conformance() {
+        let topologies: Vec<Topology> = Topology::grids()
+            .into_iter()
+            .chain(Topology::grids_with_holes())
+            .collect();
+        // each node knows only its own connections as NetworkNode, or knows all of them
+        for connection_report in [ConnectionReport::Own, ConnectionReport::All] {
+            Conformance::new(GeographicProtocol::new)
+                .set_connection_report(connection_report)
+                .check_all(&topologies)
+                .unwrap();
+        }
+    }
+}
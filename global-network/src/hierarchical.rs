@@ -0,0 +1,470 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{anyhow, Result};
+use network_node::localnet::LocalNetworkLocation;
+use network_node::protocol::Protocol;
+use network_node::utils::type_alias::{Coordinate, Id};
+
+use crate::connections::Connections;
+use crate::BoundingBox;
+
+/// coordinate of a 2x2 unit. the unit (ux, uy) has nodes from (2ux, 2uy) to (2ux + 1, 2uy + 1).
+type UnitCoordinate = Coordinate;
+
+const LOCATIONS: [LocalNetworkLocation; 4] = [
+    LocalNetworkLocation::UpLeft,
+    LocalNetworkLocation::UpRight,
+    LocalNetworkLocation::DownRight,
+    LocalNetworkLocation::DownLeft,
+];
+
+/// offsets of neighbors
+const NEIGHBOR_OFFSETS: [Coordinate; 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+fn unit_of(coordinate: Coordinate) -> UnitCoordinate {
+    (coordinate.0.div_euclid(2), coordinate.1.div_euclid(2))
+}
+
+/// location of the node in its unit
+fn location_of(coordinate: Coordinate) -> LocalNetworkLocation {
+    let offset = (coordinate.0.rem_euclid(2), coordinate.1.rem_euclid(2));
+    LOCATIONS
+        .into_iter()
+        .find(|location| location.get_root_coordinate() == offset)
+        .expect("offset in unit is 0 or 1")
+}
+
+fn member(unit: UnitCoordinate, location: LocalNetworkLocation) -> Coordinate {
+    let (dx, dy) = location.get_root_coordinate();
+    (unit.0 * 2 + dx, unit.1 * 2 + dy)
+}
+
+/// Hierarchical routing protocol which treats each 2x2 unit (localnet) as a cluster.
+///
+/// Inter-unit routes are searched on unit coordinates: a packet goes to a gateway of its unit,
+/// which is connected to the next unit, and crosses to the next unit. Intra-unit delivery uses
+/// the neighbors and the diagonal node in the unit: the diagonal node is reached through
+/// whichever neighbor is connected to it.
+/// The graph of units has a quarter of nodes, and connections inside a unit don't change
+/// inter-unit routes, so topology churn inside a unit stays local.
+///
+/// The topology is learned by add_connection/remove_connection (see Connections).
+/// Units are aligned to the root localnet, whose down left node is at (0, 0), so a node finds
+/// the unit of any address from the bounding box alone.
+pub struct HierarchicalProtocol {
+    bounding_box: BoundingBox,
+    connections: Connections,
+}
+
+impl HierarchicalProtocol {
+    pub fn new() -> Self {
+        Self::with_bounding_box(BoundingBox::default())
+    }
+    pub fn with_bounding_box(bounding_box: BoundingBox) -> Self {
+        Self {
+            bounding_box,
+            connections: Connections::default(),
+        }
+    }
+    pub fn get_bounding_box(&self) -> BoundingBox {
+        self.bounding_box
+    }
+
+    /// unit which has the node
+    pub fn get_unit(&self, id: Id) -> Result<UnitCoordinate> {
+        Ok(unit_of(self.bounding_box.to_coordinate(id)?))
+    }
+
+    /// the next unit to the destination unit
+    pub fn get_next_unit(
+        &self,
+        unit: UnitCoordinate,
+        destination: UnitCoordinate,
+    ) -> Result<UnitCoordinate> {
+        if unit == destination {
+            return Err(anyhow!("{:?} is already the destination unit", unit));
+        }
+        let (min, max) = (
+            unit_of(self.bounding_box.get_min()),
+            unit_of(self.bounding_box.get_max()),
+        );
+        // unit -> first unit of the path to the unit
+        let mut first_units = HashMap::new();
+        first_units.insert(unit, unit);
+        let mut queue = VecDeque::from([unit]);
+        while let Some(current) = queue.pop_front() {
+            for (dx, dy) in NEIGHBOR_OFFSETS {
+                let next = (current.0 + dx, current.1 + dy);
+                if next.0 < min.0 || next.1 < min.1 || next.0 > max.0 || next.1 > max.1 {
+                    continue;
+                }
+                if first_units.contains_key(&next) || self.gateways(current, next).is_empty() {
+                    continue;
+                }
+                let first_unit = if current == unit {
+                    next
+                } else {
+                    first_units[&current]
+                };
+                if next == destination {
+                    return Ok(first_unit);
+                }
+                first_units.insert(next, first_unit);
+                queue.push_back(next);
+            }
+        }
+        Err(anyhow!(
+            "no route from unit {:?} to unit {:?}",
+            unit,
+            destination
+        ))
+    }
+
+    /// check id and id2 are neighbors in the bounding box
+    fn check_neighbors(&self, id: Id, id2: Id) -> Result<()> {
+        let coordinate = self.bounding_box.to_coordinate(id)?;
+        let coordinate2 = self.bounding_box.to_coordinate(id2)?;
+        if NEIGHBOR_OFFSETS.contains(&(coordinate2.0 - coordinate.0, coordinate2.1 - coordinate.1))
+        {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{:?} and {:?} are not neighbors",
+                coordinate,
+                coordinate2
+            ))
+        }
+    }
+
+    fn is_connected(&self, coordinate: Coordinate, coordinate2: Coordinate) -> bool {
+        let (id, id2) = match (
+            self.bounding_box.to_ip_address(coordinate),
+            self.bounding_box.to_ip_address(coordinate2),
+        ) {
+            (Ok(id), Ok(id2)) => (id, id2),
+            _ => return false,
+        };
+        self.connections.is_connected(id, id2)
+    }
+
+    /// connections from the unit to the next unit as (node in the unit, node in the next unit)
+    fn gateways(
+        &self,
+        unit: UnitCoordinate,
+        next: UnitCoordinate,
+    ) -> Vec<(Coordinate, Coordinate)> {
+        LOCATIONS
+            .into_iter()
+            .map(|location| member(unit, location))
+            .flat_map(|coordinate| {
+                NEIGHBOR_OFFSETS
+                    .into_iter()
+                    .map(move |(dx, dy)| (coordinate, (coordinate.0 + dx, coordinate.1 + dy)))
+            })
+            .filter(|(coordinate, neighbor)| {
+                unit_of(*neighbor) == next && self.is_connected(*coordinate, *neighbor)
+            })
+            .collect()
+    }
+
+    /// next node to the destination in the same unit
+    fn intra_unit_next(&self, this: Coordinate, destination: Coordinate) -> Result<Coordinate> {
+        // nodes in a unit make a ring, so the destination is reached by going around the ring
+        // clockwise or counterclockwise. the shorter way is preferred.
+        let unit = unit_of(this);
+        let destination_location = location_of(destination);
+        let mut ways: Vec<Vec<Coordinate>> = [
+            LocalNetworkLocation::rotate_clockwise,
+            LocalNetworkLocation::rotate_counterclockwise,
+        ]
+        .into_iter()
+        .map(|rotate| {
+            let mut location = location_of(this);
+            let mut way = vec![this];
+            while location != destination_location {
+                location = rotate(&location);
+                way.push(member(unit, location));
+            }
+            way
+        })
+        .collect();
+        ways.sort_by_key(|way| way.len());
+        ways.into_iter()
+            .find(|way| {
+                way.windows(2)
+                    .all(|link| self.is_connected(link[0], link[1]))
+            })
+            .map(|way| way[1])
+            .ok_or_else(|| anyhow!("no route from {:?} to {:?} in the unit", this, destination))
+    }
+
+    fn route(&self, this: Id, destination: Id) -> Result<Id> {
+        let this_coordinate = self.bounding_box.to_coordinate(this)?;
+        let destination_coordinate = self.bounding_box.to_coordinate(destination)?;
+        if this_coordinate == destination_coordinate {
+            return Err(anyhow!("{:?} is already the destination", this_coordinate));
+        }
+        let (unit, destination_unit) = (unit_of(this_coordinate), unit_of(destination_coordinate));
+        if unit == destination_unit {
+            let next = self.intra_unit_next(this_coordinate, destination_coordinate)?;
+            return self.bounding_box.to_ip_address(next);
+        }
+
+        let next_unit = self.get_next_unit(unit, destination_unit)?;
+        let gateways = self.gateways(unit, next_unit);
+        // this node is a gateway, so the packet crosses to the next unit
+        if let Some((_, next)) = gateways
+            .iter()
+            .find(|(gateway, _)| *gateway == this_coordinate)
+        {
+            return self.bounding_box.to_ip_address(*next);
+        }
+        // go to a gateway in the unit. a neighbor is preferred to the diagonal node.
+        let diagonal = member(unit, location_of(this_coordinate).diagonal_location());
+        let mut gateways: Vec<Coordinate> =
+            gateways.into_iter().map(|(gateway, _)| gateway).collect();
+        gateways.sort_by_key(|gateway| *gateway == diagonal);
+        gateways
+            .into_iter()
+            .find_map(|gateway| self.intra_unit_next(this_coordinate, gateway).ok())
+            .ok_or_else(|| anyhow!("no gateway from unit {:?} to unit {:?}", unit, next_unit))
+            .and_then(|next| self.bounding_box.to_ip_address(next))
+    }
+}
+
+impl Default for HierarchicalProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Protocol for HierarchicalProtocol {
+    fn is_in_route(&self, this: Id, source: Id, destination: Id) -> bool {
+        let max_length = (self.bounding_box.width() * self.bounding_box.height()) as usize;
+        let mut current = source;
+        for _ in 0..=max_length {
+            if current == this {
+                return true;
+            }
+            if current == destination {
+                return false;
+            }
+            match self.route(current, destination) {
+                Ok(next) => current = next,
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+    fn get_next_node(&self, this: Id, destination: Id) -> Result<Id> {
+        self.route(this, destination)
+    }
+    fn add_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.check_neighbors(id, id2)?;
+        self.connections.add(id, id2);
+        Ok(())
+    }
+    fn remove_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.check_neighbors(id, id2)?;
+        self.connections.remove(id, id2);
+        Ok(())
+    }
+    fn join_global_network(&mut self, _mac_address: Id, coordinate: Coordinate) -> Result<Id> {
+        self.bounding_box = self.bounding_box.including(coordinate)?;
+        self.bounding_box.to_ip_address(coordinate)
+    }
+    fn get_routing_parameters(&self) -> Vec<u8> {
+        self.bounding_box.to_bytes()
+    }
+    /// addresses don't move when the bounding box is extended, so the learned topology is kept.
+    fn set_routing_parameters(&mut self, parameters: &[u8]) -> Result<()> {
+        self.bounding_box = BoundingBox::from_bytes(parameters)?;
+        Ok(())
+    }
+    /// the manhattan distance, which is a lower bound of hops through units and gateways.
+    fn get_distance(&self, this: Id, destination: Id) -> Result<u16> {
+        self.bounding_box.distance(this, destination)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use network_node::conformance::{Conformance, ConnectionReport, Topology};
+
+    /// make the protocol which knows all connections of the grid except the removed ones, as if
+    /// every node has reported its own connections
+    fn make_protocol(
+        bounding_box: BoundingBox,
+        removed: &[(Coordinate, Coordinate)],
+    ) -> HierarchicalProtocol {
+        let mut protocol = HierarchicalProtocol::with_bounding_box(bounding_box);
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        let (min, max) = (bounding_box.get_min(), bounding_box.get_max());
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for neighbor in [(x + 1, y), (x, y + 1)] {
+                    if bounding_box.contains(neighbor) {
+                        protocol.add_connection(ip((x, y)), ip(neighbor)).unwrap();
+                        protocol.add_connection(ip(neighbor), ip((x, y))).unwrap();
+                    }
+                }
+            }
+        }
+        for (coordinate, coordinate2) in removed {
+            protocol
+                .remove_connection(ip(*coordinate), ip(*coordinate2))
+                .unwrap();
+        }
+        protocol
+    }
+
+    fn make_path(
+        protocol: &HierarchicalProtocol,
+        source: Coordinate,
+        destination: Coordinate,
+    ) -> Vec<Coordinate> {
+        let bounding_box = protocol.get_bounding_box();
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        let mut path = vec![source];
+        while *path.last().unwrap() != destination {
+            let this = *path.last().unwrap();
+            let next = protocol.get_next_node(ip(this), ip(destination)).unwrap();
+            let next = bounding_box.to_coordinate(next).unwrap();
+            assert!(protocol.is_connected(this, next));
+            path.push(next);
+            assert!(path.len() <= (bounding_box.width() * bounding_box.height()) as usize);
+        }
+        path
+    }
+
+    #[test]
+    fn test_units() {
+        assert_eq!(unit_of((-1, 2)), (-1, 1));
+        assert_eq!(location_of((-1, 2)), LocalNetworkLocation::DownRight);
+        assert_eq!(member((-1, 1), LocalNetworkLocation::UpLeft), (-2, 3));
+        for location in LOCATIONS {
+            assert_eq!(location_of(member((3, -2), location)), location);
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_full_grid() {
+        let bounding_box = BoundingBox::new((-2, -2), (3, 1)).unwrap();
+        let protocol = make_protocol(bounding_box, &[]);
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        let (min, max) = (bounding_box.get_min(), bounding_box.get_max());
+        let coordinates: Vec<Coordinate> = (min.0..=max.0)
+            .flat_map(|x| (min.1..=max.1).map(move |y| (x, y)))
+            .collect();
+        for &source in coordinates.iter() {
+            for &destination in coordinates.iter() {
+                if source == destination {
+                    continue;
+                }
+                let path = make_path(&protocol, source, destination);
+                // a detour in a unit is at most 2 hops
+                let distance = (source.0 - destination.0).abs() + (source.1 - destination.1).abs();
+                assert!(path.len() as i16 - 1 <= distance + 2, "{:?}", path);
+                for &this in coordinates.iter() {
+                    assert_eq!(
+                        protocol.is_in_route(ip(this), ip(source), ip(destination)),
+                        path.contains(&this)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_churn_in_unit_stays_local() {
+        let bounding_box = BoundingBox::new((0, 0), (5, 1)).unwrap();
+        let protocol = make_protocol(bounding_box, &[]);
+        assert_eq!(
+            make_path(&protocol, (0, 0), (1, 1)),
+            vec![(0, 0), (0, 1), (1, 1)]
+        );
+
+        // a connection in the unit is down, so the diagonal node is reached through the other
+        // neighbor, and inter-unit routes are not changed
+        let protocol = make_protocol(bounding_box, &[((0, 0), (0, 1))]);
+        assert_eq!(
+            make_path(&protocol, (0, 0), (1, 1)),
+            vec![(0, 0), (1, 0), (1, 1)]
+        );
+        assert_eq!(
+            make_path(&protocol, (0, 0), (0, 1)),
+            vec![(0, 0), (1, 0), (1, 1), (0, 1)]
+        );
+        assert_eq!(protocol.get_next_unit((0, 0), (2, 0)).unwrap(), (1, 0));
+
+        // one of the gateways is down, so the packet goes to the other gateway
+        let protocol = make_protocol(bounding_box, &[((1, 0), (2, 0))]);
+        assert_eq!(
+            make_path(&protocol, (0, 0), (3, 0)),
+            vec![(0, 0), (0, 1), (1, 1), (2, 1), (3, 1), (3, 0)]
+        );
+        // all connections between units are down
+        let protocol = make_protocol(bounding_box, &[((1, 0), (2, 0)), ((1, 1), (2, 1))]);
+        assert!(protocol.get_next_unit((0, 0), (2, 0)).is_err());
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        assert!(protocol.get_next_node(ip((0, 0)), ip((5, 1))).is_err());
+    }
+
+    #[test]
+    fn test_detour_between_units() {
+        // units are 3x2, and connections between (0, 0) and (1, 0) units are down
+        let bounding_box = BoundingBox::new((0, 0), (5, 3)).unwrap();
+        let protocol = make_protocol(bounding_box, &[((1, 0), (2, 0)), ((1, 1), (2, 1))]);
+        assert_eq!(protocol.get_next_unit((0, 0), (1, 0)).unwrap(), (0, 1));
+        let path = make_path(&protocol, (1, 0), (2, 0));
+        assert_eq!(path.first(), Some(&(1, 0)));
+        assert!(path.iter().any(|coordinate| unit_of(*coordinate) == (0, 1)));
+
+        // unknown nodes are assumed to be alive
+        let protocol = HierarchicalProtocol::with_bounding_box(bounding_box);
+        assert_eq!(protocol.get_next_unit((0, 0), (2, 1)).unwrap(), (1, 0));
+    }
+
+    #[test]
+    fn test_each_node_reports_own_connections() {
+        // NetworkNode tells the protocol only the connections to its own neighbors
+        let bounding_box = BoundingBox::new((0, 0), (3, 1)).unwrap();
+        let mut protocol = HierarchicalProtocol::with_bounding_box(bounding_box);
+        let ip = |coordinate: Coordinate| bounding_box.to_ip_address(coordinate).unwrap();
+        protocol.add_connection(ip((0, 0)), ip((1, 0))).unwrap();
+        protocol.add_connection(ip((0, 0)), ip((0, 1))).unwrap();
+        // gateways between units are not reported, so they are assumed alive
+        assert_eq!(protocol.get_next_unit((0, 0), (1, 0)).unwrap(), (1, 0));
+        assert_eq!(
+            make_path(&protocol, (0, 0), (3, 1)),
+            vec![(0, 0), (1, 0), (2, 0), (2, 1), (3, 1)]
+        );
+
+        // the connection to the neighbor is down, so the packet goes to the other gateway
+        protocol.remove_connection(ip((0, 0)), ip((1, 0))).unwrap();
+        assert_eq!(
+            make_path(&protocol, (0, 0), (3, 0)),
+            vec![(0, 0), (0, 1), (1, 1), (2, 1), (3, 1), (3, 0)]
+        );
+    }
+
+    #[test]
+    fn test_conformance() {
+        // each node knows only its own connections as NetworkNode
+        Conformance::new(HierarchicalProtocol::new)
+            .check_all(&Topology::grids())
+            .unwrap();
+        // a hole is known only by the nodes next to it, so routes around holes need all connections
+        Conformance::new(HierarchicalProtocol::new)
+            .set_connection_report(ConnectionReport::All)
+            .check_all(
+                &Topology::grids()
+                    .into_iter()
+                    .chain(Topology::grids_with_holes())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+    }
+}
@@ -1,96 +1,345 @@
+pub mod adaptive;
+pub mod addressing;
+mod connections;
+pub mod distance_vector;
+pub mod geographic;
+pub mod hierarchical;
+pub mod link_state;
+mod links;
+
 use anyhow::anyhow;
 use anyhow::Result;
+use network_node::addressing::AddressingScheme;
+use network_node::packet::GROUP_ID_BASE;
 use network_node::protocol::ChannelId;
 use network_node::protocol::Protocol;
 use network_node::utils::type_alias::Coordinate;
+use network_node::utils::type_alias::CoordinateComponent;
 use network_node::utils::type_alias::Id;
 
-// this network is only for 4 * 4 network
-pub struct DefaultProtocol {
+pub use adaptive::AdaptiveProtocol;
+pub use addressing::{RootAssignedAddressing, SparseHashAddressing};
+pub use distance_vector::DistanceVectorProtocol;
+pub use geographic::GeographicProtocol;
+pub use hierarchical::HierarchicalProtocol;
+pub use link_state::LinkStateProtocol;
+
+pub type Distance = u8;
+
+/// distance of unreachable destination.
+/// the diameter of the network must be less than this.
+pub const INFINITY: Distance = Distance::MAX;
+
+/// entry of routing table of dynamic protocols
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Route {
+    pub next_hop: Id,
+    pub distance: Distance,
+}
+
+/// rectangle which contains all nodes of the network.
+/// ip address is the index of the coordinate in square rings around the root localnet: the root
+/// localnet has 0..4, the next ring has 4..16, and so on (see to_ip_address).
+///
+/// The root extends the box whenever a node joins outside of it, so the box is the bounding box
+/// of the members, and hands it out by routing parameters. The address of a coordinate doesn't
+/// depend on the box, so addresses don't move when the box is extended, and a member with the old
+/// box routes correctly to every node in it until it gets the new box.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct BoundingBox {
+    min: Coordinate,
+    max: Coordinate,
+}
+
+impl BoundingBox {
+    /// min and max are included in the box
+    pub fn new(min: Coordinate, max: Coordinate) -> Result<Self> {
+        if min.0 > max.0 || min.1 > max.1 {
+            return Err(anyhow!(
+                "invalid bounding box: min = {:?}, max = {:?}",
+                min,
+                max
+            ));
+        }
+        let bounding_box = Self { min, max };
+        // all addresses must be less than ids of groups and broadcast
+        let ring = [min.0, min.1, max.0, max.1]
+            .into_iter()
+            .map(ring_of_component)
+            .max()
+            .unwrap_or_default();
+        if (2 * ring + 2).pow(2) > GROUP_ID_BASE as u32 {
+            return Err(anyhow!("bounding box is too large: {:?}", bounding_box));
+        }
+        Ok(bounding_box)
+    }
+    /// the smallest box which contains all of the coordinates
+    pub fn around(coordinates: impl IntoIterator<Item = Coordinate>) -> Result<Self> {
+        let mut coordinates = coordinates.into_iter();
+        let first = coordinates
+            .next()
+            .ok_or_else(|| anyhow!("bounding box needs at least one coordinate"))?;
+        let (min, max) = coordinates.fold((first, first), |(min, max), (x, y)| {
+            ((min.0.min(x), min.1.min(y)), (max.0.max(x), max.1.max(y)))
+        });
+        Self::new(min, max)
+    }
+    /// the box which is extended to contain the coordinate
+    pub fn including(&self, coordinate: Coordinate) -> Result<Self> {
+        Self::around([self.min, self.max, coordinate])
+    }
+    pub fn width(&self) -> u32 {
+        (self.max.0 as i32 - self.min.0 as i32 + 1) as u32
+    }
+    pub fn height(&self) -> u32 {
+        (self.max.1 as i32 - self.min.1 as i32 + 1) as u32
+    }
+    pub fn get_min(&self) -> Coordinate {
+        self.min
+    }
+    pub fn get_max(&self) -> Coordinate {
+        self.max
+    }
+    pub fn contains(&self, coordinate: Coordinate) -> bool {
+        let (x, y) = coordinate;
+        self.min.0 <= x && x <= self.max.0 && self.min.1 <= y && y <= self.max.1
+    }
+    pub fn to_ip_address(&self, coordinate: Coordinate) -> Result<Id> {
+        if !self.contains(coordinate) {
+            return Err(anyhow!(
+                "to_ip_address error: {:?} is out of {:?}",
+                coordinate,
+                self
+            ));
+        }
+        // the ring k is the border of the square from (-k, -k) to (k + 1, k + 1), whose addresses
+        // start from (2k)^2. its 4 sides are numbered counterclockwise from the down left corner.
+        let (x, y) = (coordinate.0 as i32, coordinate.1 as i32);
+        let ring = ring_of_component(coordinate.0).max(ring_of_component(coordinate.1)) as i32;
+        let (low, high) = (-ring, ring + 1);
+        let (side, offset) = if y == low && x < high {
+            (0, x - low)
+        } else if x == high && y < high {
+            (1, y - low)
+        } else if y == high && x > low {
+            (2, high - x)
+        } else {
+            (3, high - y)
+        };
+        Ok(((2 * ring).pow(2) + side * (2 * ring + 1) + offset) as Id)
+    }
+    pub fn to_coordinate(&self, ip_address: Id) -> Result<Coordinate> {
+        let ip_address = ip_address as i32;
+        let mut ring: i32 = 0;
+        while (2 * ring + 2).pow(2) <= ip_address {
+            ring += 1;
+        }
+        let (low, high) = (-ring, ring + 1);
+        let index = ip_address - (2 * ring).pow(2);
+        let (side, offset) = (index / (2 * ring + 1), index % (2 * ring + 1));
+        let (x, y) = match side {
+            0 => (low + offset, low),
+            1 => (high, low + offset),
+            2 => (high - offset, high),
+            _ => (low, high - offset),
+        };
+        let coordinate = (x as CoordinateComponent, y as CoordinateComponent);
+        if !self.contains(coordinate) {
+            return Err(anyhow!(
+                "to_coordinate error: {} is out of {:?}",
+                ip_address,
+                self
+            ));
+        }
+        Ok(coordinate)
+    }
+    /// manhattan distance between the nodes, which is the number of hops if there is no hole
+    pub fn distance(&self, ip_address: Id, ip_address2: Id) -> Result<u16> {
+        Ok(manhattan_distance(
+            self.to_coordinate(ip_address)?,
+            self.to_coordinate(ip_address2)?,
+        ))
+    }
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for component in [self.min.0, self.min.1, self.max.0, self.max.1] {
+            bytes.extend(component.to_be_bytes());
+        }
+        bytes
+    }
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 8 {
+            return Err(anyhow!("length of bounding box is not enough: {:?}", bytes));
+        }
+        let component =
+            |i: usize| CoordinateComponent::from_be_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+        Self::new((component(0), component(1)), (component(2), component(3)))
+    }
+}
+
+/// the ring of the component around the root localnet, which has 0 and 1
+fn ring_of_component(component: CoordinateComponent) -> u32 {
+    if component >= 1 {
+        (component as i32 - 1) as u32
+    } else {
+        (-(component as i32)) as u32
+    }
+}
+
+pub(crate) fn manhattan_distance(coordinate: Coordinate, coordinate2: Coordinate) -> u16 {
+    let dx = (coordinate.0 as i32 - coordinate2.0 as i32).abs();
+    let dy = (coordinate.1 as i32 - coordinate2.1 as i32).abs();
+    (dx + dy).min(u16::MAX as i32) as u16
+}
+
+impl Default for BoundingBox {
+    /// the root localnet, from which the root extends the box as nodes join
+    fn default() -> Self {
+        Self {
+            min: (0, 0),
+            max: (1, 1),
+        }
+    }
+}
+
+/// dense grid addressing: the bounding box is handed out as parameters.
+impl AddressingScheme for BoundingBox {
+    fn assign(&mut self, _mac_address: Id, coordinate: Coordinate) -> Result<Id> {
+        *self = self.including(coordinate)?;
+        self.to_ip_address(coordinate)
+    }
+    fn to_address(&self, coordinate: Coordinate) -> Result<Id> {
+        self.to_ip_address(coordinate)
+    }
+    fn to_coordinate(&self, address: Id) -> Result<Coordinate> {
+        BoundingBox::to_coordinate(self, address)
+    }
+    fn get_parameters(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+    fn set_parameters(&mut self, parameters: &[u8]) -> Result<()> {
+        *self = BoundingBox::from_bytes(parameters)?;
+        Ok(())
+    }
+}
+
+/// XY routing protocol.
+/// addresses are given by the addressing scheme (dense grid by default), which is decided by the
+/// root, and handed out to other nodes when they join.
+pub struct DefaultProtocol<A: AddressingScheme = BoundingBox> {
     routing_table: DefaultRoutingTable,
+    addressing: A,
 }
-// this routing table is only for 4 * 4 networks
 struct DefaultRoutingTable {}
 impl DefaultRoutingTable {
     fn get_next_coordinate(
         &self,
         this_coordinate: Coordinate,
         destination_coordinate: Coordinate,
-    ) -> Coordinate {
+    ) -> Result<Coordinate> {
         let (this_x, this_y) = this_coordinate;
         let (destination_x, destination_y) = destination_coordinate;
 
         // firstly, move x coordinate
         // secondly, move y coordinate
         if this_x < destination_x {
-            return (this_x + 1, this_y);
+            Ok((this_x + 1, this_y))
         } else if this_x > destination_x {
-            return (this_x - 1, this_y);
+            Ok((this_x - 1, this_y))
         } else if this_y < destination_y {
-            return (this_x, this_y + 1);
+            Ok((this_x, this_y + 1))
         } else if this_y > destination_y {
-            return (this_x, this_y - 1);
+            Ok((this_x, this_y - 1))
         } else {
-            panic!("get_next_coordinate error: this_coordinate == destination_coordinate");
+            Err(anyhow!(
+                "get_next_coordinate error: {:?} is the destination itself",
+                this_coordinate
+            ))
         }
     }
+    /// whether this_coordinate is on the path from source_coordinate to destination_coordinate,
+    /// which is made by get_next_coordinate. both ends are included.
     fn is_in_route(
         &self,
         this_coordinate: Coordinate,
         source_coordinate: Coordinate,
         destination_coordinate: Coordinate,
     ) -> bool {
-        let (this_x, this_y) = this_coordinate;
-        let (source_x, source_y) = source_coordinate;
-        let (destination_x, destination_y) = destination_coordinate;
-
-        // source -> destination path is determined by get_next_coordinate.
-        // in the function, firstly, move x coordinate, and secondly, move y coordinate.
-        if source_y == this_y && (source_x <= this_x && this_x <= destination_x) {
-            true
-        } else if destination_x == this_x && (source_y <= this_y && this_y <= destination_y) {
-            true
-        } else {
-            false
+        let mut coordinate = source_coordinate;
+        loop {
+            if coordinate == this_coordinate {
+                return true;
+            }
+            if coordinate == destination_coordinate {
+                return false;
+            }
+            coordinate = match self.get_next_coordinate(coordinate, destination_coordinate) {
+                Ok(coordinate) => coordinate,
+                Err(_) => return false,
+            };
         }
     }
 }
 
 impl DefaultProtocol {
     pub fn new() -> Self {
+        Self::with_bounding_box(BoundingBox::default())
+    }
+    /// the root may start from the bounding box of the actual network if it is known.
+    /// ip addresses don't depend on the box, so they are dense if the network surrounds the root
+    /// localnet.
+    pub fn with_bounding_box(bounding_box: BoundingBox) -> Self {
+        Self::with_addressing(bounding_box)
+    }
+    pub fn get_bounding_box(&self) -> BoundingBox {
+        self.addressing
+    }
+}
+
+impl<A: AddressingScheme> DefaultProtocol<A> {
+    pub fn with_addressing(addressing: A) -> Self {
         Self {
             routing_table: DefaultRoutingTable {},
+            addressing,
         }
     }
-    fn make_ip_address(coordinate: Coordinate) -> Result<Id> {
-        match coordinate {
-            (x, y) if x >= 0 && y >= 0 && x < 4 && y < 4 => return Ok(x as u16 + 4 * y as u16),
-            _ => return Err(anyhow!("join_global_net error: not 4 * 4 netowrk")),
-        }
+    pub fn get_addressing(&self) -> &A {
+        &self.addressing
     }
-    fn get_coordinate(ip_address: Id) -> Result<Coordinate> {
-        match ip_address {
-            0..=15 => return Ok((ip_address as i16 % 4 as i16, ip_address as i16 / 4)),
-            _ => return Err(anyhow!("get_coordinate error: not 4 * 4 netowrk")),
-        }
+    fn make_ip_address(&self, coordinate: Coordinate) -> Result<Id> {
+        self.addressing.to_address(coordinate)
+    }
+    fn get_coordinate(&self, ip_address: Id) -> Result<Coordinate> {
+        self.addressing.to_coordinate(ip_address)
     }
 }
-impl Protocol for DefaultProtocol {
+
+impl Default for DefaultProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: AddressingScheme> Protocol for DefaultProtocol<A> {
     // check whether this node is in route
     fn is_in_route(&self, this_id: Id, source_id: Id, destination_id: Id) -> bool {
-        self.routing_table.is_in_route(
-            Self::get_coordinate(this_id).unwrap(),
-            Self::get_coordinate(source_id).unwrap(),
-            Self::get_coordinate(destination_id).unwrap(),
-        )
+        match (
+            self.get_coordinate(this_id),
+            self.get_coordinate(source_id),
+            self.get_coordinate(destination_id),
+        ) {
+            (Ok(this), Ok(source), Ok(destination)) => {
+                self.routing_table.is_in_route(this, source, destination)
+            }
+            _ => false,
+        }
     }
     // return next node's ip address
-    fn get_next_node(&self, this_id: Id, destination_id: Id) -> Id {
-        Self::make_ip_address(self.routing_table.get_next_coordinate(
-            Self::get_coordinate(this_id).unwrap(),
-            Self::get_coordinate(destination_id).unwrap(),
-        ))
-        .unwrap()
+    fn get_next_node(&self, this_id: Id, destination_id: Id) -> Result<Id> {
+        self.make_ip_address(self.routing_table.get_next_coordinate(
+            self.get_coordinate(this_id)?,
+            self.get_coordinate(destination_id)?,
+        )?)
     }
     // id and id2 are connected
     fn add_connection(&mut self, _id: Id, _id2: Id) -> Result<()> {
@@ -106,7 +355,250 @@ impl Protocol for DefaultProtocol {
     fn change_channel(&mut self, _this: Id, _destination: Id, _channel: ChannelId) -> Result<()> {
         Ok(())
     }
-    fn join_global_network(&mut self, _mac_address: Id, coordinate: Coordinate) -> Result<Id> {
-        Self::make_ip_address(coordinate)
+    fn join_global_network(&mut self, mac_address: Id, coordinate: Coordinate) -> Result<Id> {
+        self.addressing.assign(mac_address, coordinate)
+    }
+    fn address_of(&self, mac_address: Id) -> Option<Id> {
+        self.addressing.address_of(mac_address)
+    }
+    fn leave_global_network(&mut self, mac_address: Id, ip_address: Id) -> Result<()> {
+        self.addressing.release(mac_address, ip_address);
+        Ok(())
+    }
+    fn get_routing_parameters(&self) -> Vec<u8> {
+        self.addressing.get_parameters()
+    }
+    fn set_routing_parameters(&mut self, parameters: &[u8]) -> Result<()> {
+        self.addressing.set_parameters(parameters)
+    }
+    /// XY routing takes the manhattan distance
+    fn get_distance(&self, this: Id, destination: Id) -> Result<u16> {
+        Ok(manhattan_distance(
+            self.get_coordinate(this)?,
+            self.get_coordinate(destination)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use network_node::conformance::{Conformance, ConnectionReport, Topology};
+    use network_node::membership::{JoinRequest, Membership};
+
+    #[test]
+    fn test_ip_address_and_coordinate() {
+        let bounding_boxes = [
+            BoundingBox::new((0, 0), (3, 3)).unwrap(),
+            BoundingBox::new((-1, -2), (5, 0)).unwrap(),
+            BoundingBox::new((-3, 1), (-3, 9)).unwrap(),
+            BoundingBox::default(),
+        ];
+        for bounding_box in bounding_boxes {
+            let protocol = DefaultProtocol::with_bounding_box(bounding_box);
+            let (min, max) = (bounding_box.get_min(), bounding_box.get_max());
+            let mut ip_addresses = Vec::new();
+            for x in min.0..=max.0 {
+                for y in min.1..=max.1 {
+                    let ip_address = protocol.make_ip_address((x, y)).unwrap();
+                    assert_eq!(protocol.get_coordinate(ip_address).unwrap(), (x, y));
+                    ip_addresses.push(ip_address);
+                }
+            }
+            ip_addresses.sort();
+            ip_addresses.dedup();
+            assert_eq!(
+                ip_addresses.len() as u32,
+                bounding_box.width() * bounding_box.height()
+            );
+            assert!(protocol.make_ip_address((min.0 - 1, min.1)).is_err());
+            assert!(protocol.make_ip_address((max.0, max.1 + 1)).is_err());
+            assert!(protocol.get_coordinate(GROUP_ID_BASE - 1).is_err());
+        }
+        // addresses are dense in the squares around the root localnet
+        for ring in 0..4 {
+            let bounding_box = BoundingBox::new((-ring, -ring), (ring + 1, ring + 1)).unwrap();
+            let mut ip_addresses: Vec<Id> = (-ring..=ring + 1)
+                .flat_map(|x| (-ring..=ring + 1).map(move |y| (x, y)))
+                .map(|coordinate| bounding_box.to_ip_address(coordinate).unwrap())
+                .collect();
+            ip_addresses.sort();
+            for (i, ip_address) in ip_addresses.iter().enumerate() {
+                assert_eq!(*ip_address as usize, i);
+            }
+        }
+        assert_eq!(BoundingBox::default().to_ip_address((1, 1)).unwrap(), 2);
+        assert!(BoundingBox::new((0, 0), (-1, 0)).is_err());
+        assert!(BoundingBox::new((-126, -126), (127, 127)).is_ok());
+        assert!(BoundingBox::new((-200, -200), (200, 200)).is_err());
+    }
+
+    #[test]
+    fn test_bounding_box_of_members() {
+        let mut protocol = DefaultProtocol::new();
+        for (mac_address, coordinate) in [(0, 0), (2, -1), (-3, 1)].into_iter().enumerate() {
+            protocol
+                .join_global_network(mac_address as Id, coordinate)
+                .unwrap();
+        }
+        assert_eq!(
+            protocol.get_bounding_box(),
+            BoundingBox::new((-3, -1), (2, 1)).unwrap()
+        );
+        assert_eq!(
+            BoundingBox::around([(0, 0), (2, -1), (-3, 1)]).unwrap(),
+            protocol.get_bounding_box()
+        );
+        assert!(BoundingBox::around([]).is_err());
+        // too large to be addressed
+        assert!(protocol.join_global_network(3, (300, 300)).is_err());
+
+        // the root hands out the box, and the addresses of members don't move with it
+        let mut root = Membership::new();
+        let mut protocol = DefaultProtocol::new();
+        let reply = root.join(&mut protocol, &JoinRequest::new(1, (1, 1)), 0);
+        assert_eq!(reply.ip_address, 2);
+        let mut member = DefaultProtocol::new();
+        member
+            .set_routing_parameters(&reply.routing_parameters)
+            .unwrap();
+        let reply = root.join(&mut protocol, &JoinRequest::new(2, (-1, 0)), 0);
+        let mut node = DefaultProtocol::new();
+        node.set_routing_parameters(&reply.routing_parameters)
+            .unwrap();
+        assert_eq!(
+            node.get_bounding_box(),
+            BoundingBox::new((-1, 0), (1, 1)).unwrap()
+        );
+        assert_eq!(root.find_by_mac_address(1).unwrap().ip_address, 2);
+        assert_eq!(node.make_ip_address((1, 1)).unwrap(), 2);
+        // the member with the old box routes to the same address
+        assert_eq!(member.make_ip_address((1, 1)).unwrap(), 2);
+        assert_eq!(
+            member.get_next_node(0, 2).unwrap(),
+            node.get_next_node(0, 2).unwrap()
+        );
+        let reply = root.join(&mut protocol, &JoinRequest::new(1, (1, 1)), 0);
+        assert_eq!(reply.ip_address, 2);
+    }
+
+    #[test]
+    fn test_negative_coordinate_routing() {
+        let mut protocol = DefaultProtocol::new();
+        protocol.join_global_network(0, (-1, 0)).unwrap();
+        protocol.join_global_network(1, (-3, -2)).unwrap();
+        // addresses are taken after the box is extended
+        let this = protocol.make_ip_address((-1, 0)).unwrap();
+        let destination = protocol.make_ip_address((-3, -2)).unwrap();
+        let next = protocol.get_next_node(this, destination).unwrap();
+        assert_eq!(protocol.get_coordinate(next).unwrap(), (-2, 0));
+        // there is no next node of the destination itself
+        assert!(protocol.get_next_node(this, this).is_err());
+    }
+
+    fn neighbors(coordinate: Coordinate) -> [Coordinate; 4] {
+        let (x, y) = coordinate;
+        [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)]
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_is_in_route_matches_get_next_node() {
+        let bounding_boxes = [
+            BoundingBox::new((0, 0), (0, 0)).unwrap(),
+            BoundingBox::new((0, 0), (1, 1)).unwrap(),
+            BoundingBox::new((0, 0), (3, 3)).unwrap(),
+            BoundingBox::new((-2, -1), (2, 3)).unwrap(),
+            BoundingBox::new((-3, 0), (2, 0)).unwrap(),
+            BoundingBox::new((1, -4), (2, 1)).unwrap(),
+        ];
+        for bounding_box in bounding_boxes {
+            let protocol = DefaultProtocol::with_bounding_box(bounding_box);
+            let (min, max) = (bounding_box.get_min(), bounding_box.get_max());
+            let coordinates: Vec<Coordinate> = (min.0..=max.0)
+                .flat_map(|x| (min.1..=max.1).map(move |y| (x, y)))
+                .collect();
+            let ip = |coordinate: Coordinate| protocol.make_ip_address(coordinate).unwrap();
+
+            for &source in coordinates.iter() {
+                for &destination in coordinates.iter() {
+                    // make path by get_next_node
+                    let mut path = vec![source];
+                    let mut this = source;
+                    while this != destination {
+                        let next = protocol.get_next_node(ip(this), ip(destination)).unwrap();
+                        let next = protocol.get_coordinate(next).unwrap();
+                        assert!(neighbors(this).contains(&next), "{:?} -> {:?}", this, next);
+                        path.push(next);
+                        this = next;
+                        assert!(
+                            path.len() <= coordinates.len(),
+                            "route does not terminate: {:?}",
+                            path
+                        );
+                    }
+
+                    for &this in coordinates.iter() {
+                        assert_eq!(
+                            protocol.is_in_route(ip(this), ip(source), ip(destination)),
+                            path.contains(&this),
+                            "this: {:?}, source: {:?}, destination: {:?}, path: {:?}",
+                            this,
+                            source,
+                            destination,
+                            path
+                        );
+                    }
+
+                    // forwarding decision: a packet sent by a node on the path is forwarded only
+                    // by the next node among the neighbors of the sender.
+                    for hop in path.windows(2) {
+                        let (sender, next) = (hop[0], hop[1]);
+                        for neighbor in neighbors(sender) {
+                            if !bounding_box.contains(neighbor) {
+                                continue;
+                            }
+                            assert_eq!(
+                                protocol.is_in_route(ip(neighbor), ip(sender), ip(destination)),
+                                neighbor == next,
+                                "sender: {:?}, neighbor: {:?}, destination: {:?}",
+                                sender,
+                                neighbor,
+                                destination
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_routing_parameters() {
+        let root = DefaultProtocol::with_bounding_box(BoundingBox::new((-2, 0), (5, 3)).unwrap());
+        let mut node = DefaultProtocol::new();
+        node.set_routing_parameters(&root.get_routing_parameters())
+            .unwrap();
+        assert_eq!(node.get_bounding_box(), root.get_bounding_box());
+        assert!(node.set_routing_parameters(&[0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_conformance() {
+        Conformance::new(DefaultProtocol::new)
+            .check_all(&Topology::grids())
+            .unwrap();
+        // is_in_route is checked when every node knows the same connections
+        Conformance::new(DefaultProtocol::new)
+            .set_connection_report(ConnectionReport::All)
+            .check_all(&Topology::grids())
+            .unwrap();
+        // routing is combined with other addressing schemes
+        Conformance::new(|| DefaultProtocol::with_addressing(SparseHashAddressing::new()))
+            .check_all(&Topology::grids())
+            .unwrap();
+        Conformance::new(|| DefaultProtocol::with_addressing(RootAssignedAddressing::new()))
+            .check_all(&Topology::grids())
+            .unwrap();
     }
 }
@@ -0,0 +1,692 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::mem::size_of;
+
+use anyhow::{anyhow, Result};
+use network_node::packet::ToId;
+use network_node::protocol::{Millis, Protocol, RouteEntry, RoutingMessage};
+use network_node::utils::type_alias::{Coordinate, CoordinateComponent, Id};
+
+use crate::links::Links;
+use crate::{BoundingBox, Distance, Route, INFINITY};
+
+pub type Sequence = u16;
+
+/// interval of hello to neighbors
+pub const HELLO_INTERVAL: Millis = 3000;
+/// a neighbor which is not heard in this time is considered dead
+pub const NEIGHBOR_TIMEOUT: Millis = HELLO_INTERVAL * 4;
+/// the own LSA is advertised again in this interval even if neighbors are not changed
+pub const REFRESH_INTERVAL: Millis = 30_000;
+/// an LSA which is not refreshed in this time is removed from the database
+pub const MAX_AGE: Millis = REFRESH_INTERVAL * 4;
+
+const HELLO: u8 = 0;
+const LINK_STATE: u8 = 1;
+/// id(16) | x(16) | y(16)
+const NODE_LENGTH: usize = size_of::<Id>() + size_of::<CoordinateComponent>() * 2;
+
+/// link-state advertisement, which lists live neighbors of the origin.
+/// Data form is like this
+/// [ 1(8) | sequence(16) | age in seconds(16) | origin(16) | x(16) | y(16) | neighbors(...) ]
+/// each neighbor is [ id(16) | x(16) | y(16) ]
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct LinkStateAdvertisement {
+    pub origin: Id,
+    pub sequence: Sequence,
+    /// time from the origination
+    pub age: Millis,
+    pub coordinate: Coordinate,
+    pub neighbors: Vec<(Id, Coordinate)>,
+}
+
+impl LinkStateAdvertisement {
+    const HEADER_LENGTH: usize = 1 + size_of::<Sequence>() + size_of::<u16>() + NODE_LENGTH;
+
+    /// sequence numbers wrap around, so they are compared by serial number arithmetic.
+    pub fn is_newer_than(&self, other: &Self) -> bool {
+        (self.sequence.wrapping_sub(other.sequence) as i16) > 0
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let age = (self.age / 1000).min(u16::MAX as Millis) as u16;
+        let mut bytes = vec![LINK_STATE];
+        bytes.extend(self.sequence.to_be_bytes());
+        bytes.extend(age.to_be_bytes());
+        encode_node(&mut bytes, self.origin, self.coordinate);
+        for (id, coordinate) in self.neighbors.iter() {
+            encode_node(&mut bytes, *id, *coordinate);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < Self::HEADER_LENGTH
+            || !(bytes.len() - Self::HEADER_LENGTH).is_multiple_of(NODE_LENGTH)
+            || bytes[0] != LINK_STATE
+        {
+            return Err(anyhow!("invalid link-state advertisement: {:?}", bytes));
+        }
+        let sequence = Sequence::from_be_bytes([bytes[1], bytes[2]]);
+        let age = u16::from_be_bytes([bytes[3], bytes[4]]) as Millis * 1000;
+        let (origin, coordinate) = decode_node(&bytes[5..]);
+        let neighbors = bytes[Self::HEADER_LENGTH..]
+            .chunks(NODE_LENGTH)
+            .map(decode_node)
+            .collect();
+        Ok(Self {
+            origin,
+            sequence,
+            age,
+            coordinate,
+            neighbors,
+        })
+    }
+}
+
+fn encode_node(bytes: &mut Vec<u8>, id: Id, coordinate: Coordinate) {
+    bytes.extend(id.to_be_bytes());
+    bytes.extend(coordinate.0.to_be_bytes());
+    bytes.extend(coordinate.1.to_be_bytes());
+}
+
+fn decode_node(bytes: &[u8]) -> (Id, Coordinate) {
+    let id = Id::from_be_bytes([bytes[0], bytes[1]]);
+    let x = CoordinateComponent::from_be_bytes([bytes[2], bytes[3]]);
+    let y = CoordinateComponent::from_be_bytes([bytes[4], bytes[5]]);
+    (id, (x, y))
+}
+
+/// node in the topology database
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct TopologyNode {
+    pub id: Id,
+    pub coordinate: Coordinate,
+    /// neighbors which also list this node
+    pub neighbors: Vec<Id>,
+}
+
+struct Entry {
+    lsa: LinkStateAdvertisement,
+    received_at: Millis,
+}
+
+impl Entry {
+    fn age(&self, now: Millis) -> Millis {
+        self.lsa.age + now.saturating_sub(self.received_at)
+    }
+}
+
+/// Link-state routing protocol.
+///
+/// Each node finds its neighbors by hello, and floods a link-state advertisement (LSA) which lists
+/// its live neighbors and their coordinates. An LSA is replaced by one with a newer sequence
+/// number, and removed when its age exceeds MAX_AGE, so the origin refreshes it periodically.
+/// Every node builds the whole topology from the LSAs and computes next hops by Dijkstra.
+/// A connection is used only if both ends list each other.
+///
+/// The topology database can be queried by get_topology, e.g. to know the current display shape.
+/// LSAs carry the coordinates of the nodes, so the database draws the shape without the
+/// bounding box.
+pub struct LinkStateProtocol {
+    bounding_box: BoundingBox,
+    /// ip address of this node. it is known by update or process_routing_message.
+    this_id: Option<Id>,
+    links: Links,
+    /// coordinates which neighbors tell by hello
+    neighbor_coordinates: HashMap<Id, Coordinate>,
+    database: BTreeMap<Id, Entry>,
+    sequence: Sequence,
+    /// neighbors in the own LSA
+    advertised_neighbors: Vec<Id>,
+    last_hello: Option<Millis>,
+    last_refresh: Option<Millis>,
+    routes: BTreeMap<Id, Route>,
+}
+
+impl LinkStateProtocol {
+    pub fn new() -> Self {
+        Self::with_bounding_box(BoundingBox::default())
+    }
+    pub fn with_bounding_box(bounding_box: BoundingBox) -> Self {
+        Self {
+            bounding_box,
+            this_id: None,
+            links: Links::default(),
+            neighbor_coordinates: HashMap::new(),
+            database: BTreeMap::new(),
+            sequence: 0,
+            advertised_neighbors: Vec::new(),
+            last_hello: None,
+            last_refresh: None,
+            routes: BTreeMap::new(),
+        }
+    }
+    pub fn get_bounding_box(&self) -> BoundingBox {
+        self.bounding_box
+    }
+    pub fn get_route(&self, destination: Id) -> Option<Route> {
+        self.routes.get(&destination).copied()
+    }
+    /// routing table of this node: destination -> route
+    pub fn get_routes(&self) -> &BTreeMap<Id, Route> {
+        &self.routes
+    }
+    pub fn get_link_state(&self, origin: Id) -> Option<&LinkStateAdvertisement> {
+        self.database.get(&origin).map(|entry| &entry.lsa)
+    }
+
+    /// all nodes in the topology database in ascending order of id
+    pub fn get_topology(&self) -> Vec<TopologyNode> {
+        self.database
+            .values()
+            .map(|entry| TopologyNode {
+                id: entry.lsa.origin,
+                coordinate: entry.lsa.coordinate,
+                neighbors: self.two_way_neighbors(&entry.lsa).collect(),
+            })
+            .collect()
+    }
+
+    /// coordinates of this node and the nodes which are reachable from this node
+    pub fn get_shape(&self) -> Vec<Coordinate> {
+        self.database
+            .values()
+            .filter(|entry| {
+                Some(entry.lsa.origin) == self.this_id
+                    || self.routes.contains_key(&entry.lsa.origin)
+            })
+            .map(|entry| entry.lsa.coordinate)
+            .collect()
+    }
+
+    fn two_way_neighbors<'a>(
+        &'a self,
+        lsa: &'a LinkStateAdvertisement,
+    ) -> impl Iterator<Item = Id> + 'a {
+        lsa.neighbors.iter().filter_map(move |(neighbor, _)| {
+            let entry = self.database.get(neighbor)?;
+            entry
+                .lsa
+                .neighbors
+                .iter()
+                .any(|(id, _)| *id == lsa.origin)
+                .then_some(*neighbor)
+        })
+    }
+
+    /// Dijkstra from the root: destination -> (first hop, distance)
+    fn shortest_path_tree(&self, root: Id) -> BTreeMap<Id, (Id, u32)> {
+        let mut tree: BTreeMap<Id, (Id, u32)> = BTreeMap::new();
+        let mut heap = BinaryHeap::from([Reverse((0, root, root))]);
+        while let Some(Reverse((distance, id, first_hop))) = heap.pop() {
+            if tree.contains_key(&id) {
+                continue;
+            }
+            tree.insert(id, (first_hop, distance));
+            let entry = match self.database.get(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for neighbor in self.two_way_neighbors(&entry.lsa) {
+                if tree.contains_key(&neighbor) {
+                    continue;
+                }
+                let first_hop = if id == root { neighbor } else { first_hop };
+                // all connections have the same cost
+                heap.push(Reverse((distance + 1, neighbor, first_hop)));
+            }
+        }
+        tree.remove(&root);
+        tree
+    }
+
+    fn recompute(&mut self) {
+        let this = match self.this_id {
+            Some(this) => this,
+            None => return,
+        };
+        self.routes = self
+            .shortest_path_tree(this)
+            .into_iter()
+            .filter(|(_, (_, distance))| *distance < INFINITY as u32)
+            .map(|(destination, (next_hop, distance))| {
+                let distance = distance as Distance;
+                (destination, Route { next_hop, distance })
+            })
+            .collect();
+    }
+
+    fn set_this_id(&mut self, this: Id) {
+        if self.this_id != Some(this) {
+            // ip address is changed, so the own LSA must be advertised again
+            self.this_id = Some(this);
+            self.advertised_neighbors.clear();
+            self.last_refresh = None;
+        }
+    }
+
+    fn coordinate_of(&self, id: Id) -> Coordinate {
+        match self.neighbor_coordinates.get(&id) {
+            Some(coordinate) => *coordinate,
+            None => self.bounding_box.to_coordinate(id).unwrap_or_default(),
+        }
+    }
+
+    /// make the own LSA with the next sequence number and install it
+    fn originate(&mut self, this: Id, now: Millis) -> LinkStateAdvertisement {
+        let neighbors = self.links.neighbors(this);
+        self.sequence = self.sequence.wrapping_add(1);
+        let lsa = LinkStateAdvertisement {
+            origin: this,
+            sequence: self.sequence,
+            age: 0,
+            coordinate: self.coordinate_of(this),
+            neighbors: neighbors
+                .iter()
+                .map(|neighbor| (*neighbor, self.coordinate_of(*neighbor)))
+                .collect(),
+        };
+        self.advertised_neighbors = neighbors;
+        self.last_refresh = Some(now);
+        self.database.insert(
+            this,
+            Entry {
+                lsa: lsa.clone(),
+                received_at: now,
+            },
+        );
+        lsa
+    }
+
+    /// send the LSA to all neighbors except the sender
+    fn flood(
+        &self,
+        this: Id,
+        lsa: &LinkStateAdvertisement,
+        except: Option<Id>,
+    ) -> Vec<RoutingMessage> {
+        let messages = lsa.to_bytes();
+        self.links
+            .neighbors(this)
+            .into_iter()
+            .filter(|neighbor| Some(*neighbor) != except)
+            .map(|neighbor| RoutingMessage {
+                to: ToId::Unicast(neighbor),
+                messages: messages.clone(),
+            })
+            .collect()
+    }
+
+    /// the whole database with current ages, which is sent to a new neighbor
+    fn make_database_messages(&self, neighbor: Id, now: Millis) -> Vec<RoutingMessage> {
+        self.database
+            .values()
+            .map(|entry| {
+                let mut lsa = entry.lsa.clone();
+                lsa.age = entry.age(now);
+                RoutingMessage {
+                    to: ToId::Unicast(neighbor),
+                    messages: lsa.to_bytes(),
+                }
+            })
+            .collect()
+    }
+
+    fn process_link_state(
+        &mut self,
+        this: Id,
+        neighbor: Id,
+        lsa: LinkStateAdvertisement,
+        now: Millis,
+    ) -> Vec<RoutingMessage> {
+        if lsa.origin == this {
+            // an old LSA of this node remains (e.g. after reboot), so advertise a newer one.
+            let is_newer = match self.database.get(&this) {
+                Some(entry) => lsa.is_newer_than(&entry.lsa),
+                None => true,
+            };
+            if !is_newer {
+                return Vec::new();
+            }
+            self.sequence = lsa.sequence;
+            let lsa = self.originate(this, now);
+            return self.flood(this, &lsa, None);
+        }
+
+        match self.database.get(&lsa.origin) {
+            Some(entry) if entry.lsa.is_newer_than(&lsa) => {
+                // the neighbor has an old LSA
+                let mut newer = entry.lsa.clone();
+                newer.age = entry.age(now);
+                return vec![RoutingMessage {
+                    to: ToId::Unicast(neighbor),
+                    messages: newer.to_bytes(),
+                }];
+            }
+            Some(entry) if !lsa.is_newer_than(&entry.lsa) => return Vec::new(),
+            _ => {}
+        }
+
+        let messages = self.flood(this, &lsa, Some(neighbor));
+        if lsa.age >= MAX_AGE {
+            // flushed by the origin
+            self.database.remove(&lsa.origin);
+        } else {
+            self.database.insert(
+                lsa.origin,
+                Entry {
+                    lsa,
+                    received_at: now,
+                },
+            );
+        }
+        self.recompute();
+        messages
+    }
+}
+
+impl Default for LinkStateProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Protocol for LinkStateProtocol {
+    fn is_in_route(&self, this: Id, source: Id, destination: Id) -> bool {
+        let mut current = source;
+        for _ in 0..=self.database.len() {
+            if current == this {
+                return true;
+            }
+            if current == destination {
+                return false;
+            }
+            current = match self.shortest_path_tree(current).get(&destination) {
+                Some((next_hop, _)) => *next_hop,
+                None => return false,
+            };
+        }
+        false
+    }
+    fn get_next_node(&self, this: Id, destination: Id) -> Result<Id> {
+        if let Some(route) = self.routes.get(&destination) {
+            return Ok(route.next_hop);
+        }
+        // connection which is reported before the next update
+        if self.links.contains(this, destination) {
+            return Ok(destination);
+        }
+        Err(anyhow!("no route from {} to {}", this, destination))
+    }
+    fn add_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.links.report(id, id2);
+        Ok(())
+    }
+    fn remove_connection(&mut self, id: Id, id2: Id) -> Result<()> {
+        self.links.remove(id, id2);
+        Ok(())
+    }
+    fn join_global_network(&mut self, _mac_address: Id, coordinate: Coordinate) -> Result<Id> {
+        self.bounding_box = self.bounding_box.including(coordinate)?;
+        self.bounding_box.to_ip_address(coordinate)
+    }
+    fn get_routing_parameters(&self) -> Vec<u8> {
+        self.bounding_box.to_bytes()
+    }
+    fn set_routing_parameters(&mut self, parameters: &[u8]) -> Result<()> {
+        self.bounding_box = BoundingBox::from_bytes(parameters)?;
+        Ok(())
+    }
+    /// distance of the shortest path in the topology database.
+    fn get_distance(&self, this: Id, destination: Id) -> Result<u16> {
+        if this == destination {
+            return Ok(0);
+        }
+        if let Some(route) = self.routes.get(&destination) {
+            return Ok(route.distance as u16);
+        }
+        // connection which is reported before the next update
+        if self.links.contains(this, destination) {
+            return Ok(1);
+        }
+        Err(anyhow!("no route from {} to {}", this, destination))
+    }
+
+    /// age is the age of the destination's LSA.
+    fn get_routing_table(&self, _this: Id, now: Millis) -> Vec<RouteEntry> {
+        self.routes
+            .iter()
+            .map(|(&destination, route)| RouteEntry {
+                destination,
+                next_hop: route.next_hop,
+                metric: route.distance as u16,
+                channel: 0,
+                age: self
+                    .database
+                    .get(&destination)
+                    .map_or(0, |entry| entry.age(now)),
+            })
+            .collect()
+    }
+    fn update(&mut self, this: Id, now: Millis) -> Vec<RoutingMessage> {
+        self.set_this_id(this);
+        self.links.expire(now, NEIGHBOR_TIMEOUT);
+        self.database
+            .retain(|origin, entry| *origin == this || entry.age(now) < MAX_AGE);
+
+        let mut messages = Vec::new();
+        if self
+            .last_hello
+            .is_none_or(|last| now.saturating_sub(last) >= HELLO_INTERVAL)
+        {
+            let mut hello = vec![HELLO];
+            encode_node(&mut hello, this, self.coordinate_of(this));
+            messages.push(RoutingMessage {
+                to: ToId::Broadcast,
+                messages: hello,
+            });
+            self.last_hello = Some(now);
+        }
+
+        let neighbors = self.links.neighbors(this);
+        let is_refresh = self
+            .last_refresh
+            .is_none_or(|last| now.saturating_sub(last) >= REFRESH_INTERVAL);
+        if neighbors != self.advertised_neighbors || is_refresh {
+            // new neighbors need the whole database
+            for neighbor in neighbors.iter() {
+                if !self.advertised_neighbors.contains(neighbor) {
+                    messages.extend(self.make_database_messages(*neighbor, now));
+                }
+            }
+            let lsa = self.originate(this, now);
+            messages.extend(self.flood(this, &lsa, None));
+        }
+        self.recompute();
+        messages
+    }
+    fn process_routing_message(
+        &mut self,
+        this: Id,
+        neighbor: Id,
+        messages: &[u8],
+        now: Millis,
+    ) -> Result<Vec<RoutingMessage>> {
+        if neighbor == this {
+            return Ok(Vec::new());
+        }
+        self.set_this_id(this);
+        let mut replies = match messages.first() {
+            Some(&HELLO) if messages.len() == 1 + NODE_LENGTH => {
+                let (_, coordinate) = decode_node(&messages[1..]);
+                self.neighbor_coordinates.insert(neighbor, coordinate);
+                Vec::new()
+            }
+            Some(&LINK_STATE) => {
+                let lsa = LinkStateAdvertisement::from_bytes(messages)?;
+                self.process_link_state(this, neighbor, lsa, now)
+            }
+            _ => return Err(anyhow!("invalid link-state message: {:?}", messages)),
+        };
+        self.links.hear(this, neighbor, now);
+        // a new neighbor is advertised at once
+        replies.extend(self.update(this, now));
+        Ok(replies)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use network_node::conformance::{Conformance, ConnectionReport, Network, Topology};
+
+    /// (0, 1) - (1, 1) - (2, 1) - (3, 1)
+    ///   |        |
+    /// (0, 0) - (1, 0) - (2, 0) - (3, 0)
+    /// neighbors are found by hello, so stopped nodes are detected by the protocol.
+    fn make_network() -> Network<LinkStateProtocol> {
+        let topology = Topology::grid((0, 0), (3, 1))
+            .without_connections(&[((2, 0), (2, 1)), ((3, 0), (3, 1))]);
+        Conformance::new(LinkStateProtocol::new)
+            .set_updates(2, HELLO_INTERVAL)
+            .set_connection_report(ConnectionReport::None)
+            .build(&topology)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_link_state_advertisement_bytes() {
+        let lsa = LinkStateAdvertisement {
+            origin: 3,
+            sequence: 0xFFFF,
+            age: 5000,
+            coordinate: (-1, 2),
+            neighbors: vec![(4, (0, 2)), (2, (-2, 2))],
+        };
+        assert_eq!(
+            LinkStateAdvertisement::from_bytes(&lsa.to_bytes()).unwrap(),
+            lsa
+        );
+        assert!(LinkStateAdvertisement::from_bytes(&lsa.to_bytes()[..9]).is_err());
+
+        // sequence number wraps around
+        let mut newer = lsa.clone();
+        newer.sequence = 1;
+        assert!(newer.is_newer_than(&lsa));
+        assert!(!lsa.is_newer_than(&newer));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_shortest_paths_and_topology() {
+        let network = make_network();
+        network.check_routes().unwrap();
+        let id = |node: Coordinate| network.get_id(node);
+
+        let route = network.get_protocol((3, 0)).get_route(id((3, 1))).unwrap();
+        assert_eq!(
+            route,
+            Route {
+                next_hop: id((2, 0)),
+                distance: 5
+            }
+        );
+        let protocol = network.get_protocol((0, 1));
+        assert_eq!(protocol.get_route(id((2, 0))).unwrap().distance, 3);
+        assert_eq!(
+            network
+                .get_protocol((3, 1))
+                .get_next_node(id((3, 1)), id((0, 0)))
+                .unwrap(),
+            id((2, 1))
+        );
+        let protocol = network.get_protocol((0, 0));
+        assert!(protocol.is_in_route(id((1, 0)), id((3, 0)), id((3, 1))));
+        assert!(!protocol.is_in_route(id((0, 1)), id((3, 0)), id((3, 1))));
+
+        // every node has the same topology
+        let topology = protocol.get_topology();
+        assert_eq!(topology.len(), 8);
+        for &node in network.get_topology().get_nodes() {
+            assert_eq!(network.get_protocol(node).get_topology(), topology);
+        }
+        let center = topology.iter().find(|node| node.id == id((1, 1))).unwrap();
+        let mut neighbors = vec![id((1, 0)), id((0, 1)), id((2, 1))];
+        neighbors.sort();
+        assert_eq!(center.neighbors, neighbors);
+        // coordinates are advertised with the neighbors
+        assert_eq!(center.coordinate, (1, 1));
+        assert_eq!(network.get_protocol((2, 0)).get_shape().len(), 8);
+    }
+
+    #[test]
+    fn test_node_removal_and_aging() {
+        let mut network = make_network();
+        let (stopped, separated) = (network.get_id((2, 1)), network.get_id((3, 1)));
+
+        // (2, 1) stops, so (3, 1) is separated
+        network.stop((2, 1));
+        network.tick(NEIGHBOR_TIMEOUT + 1).unwrap();
+        for &node in network.get_topology().get_nodes() {
+            if node == (3, 1) {
+                continue;
+            }
+            let protocol = network.get_protocol(node);
+            assert!(protocol.get_route(stopped).is_none());
+            assert!(protocol.get_route(separated).is_none());
+            assert_eq!(protocol.get_shape().len(), 6);
+        }
+        // LSAs of unreachable nodes remain until they are aged
+        assert!(network
+            .get_protocol((0, 0))
+            .get_link_state(separated)
+            .is_some());
+        while network.get_now() < MAX_AGE * 2 {
+            network.tick(REFRESH_INTERVAL).unwrap();
+        }
+        let protocol = network.get_protocol((0, 0));
+        assert!(protocol.get_link_state(separated).is_none());
+        assert_eq!(protocol.get_topology().len(), 6);
+        // reachable nodes are refreshed
+        let route = protocol.get_route(network.get_id((3, 0))).unwrap();
+        assert_eq!(route.distance, 3);
+    }
+
+    #[test]
+    fn test_remove_connection() {
+        let mut network = make_network();
+        let id = |node: Coordinate| network.get_id(node);
+        let (origin, east, destination) = (id((0, 0)), id((1, 0)), id((2, 0)));
+        let north_center = id((1, 1));
+        let route = network.get_protocol((0, 1)).get_route(destination).unwrap();
+        assert_eq!(route.distance, 3);
+
+        // liveness detection tells that the link between (0, 0) and (1, 0) is broken
+        network.disconnect((0, 0), (1, 0));
+        for node in [(0, 0), (1, 0)] {
+            network
+                .get_protocol_mut(node)
+                .remove_connection(origin, east)
+                .unwrap();
+        }
+        network.tick(1).unwrap();
+        let route = network.get_protocol((0, 0)).get_route(destination).unwrap();
+        assert_eq!(route.distance, 4);
+        let route = network.get_protocol((0, 1)).get_route(destination).unwrap();
+        assert_eq!(route.next_hop, north_center);
+    }
+
+    #[test]
+    fn test_conformance() {
+        Conformance::new(LinkStateProtocol::new)
+            .set_updates(3, HELLO_INTERVAL)
+            .check_all(
+                &Topology::grids()
+                    .into_iter()
+                    .chain(Topology::grids_with_holes())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use network_node::protocol::Millis;
+use network_node::utils::type_alias::Id;
+
+/// connections between nodes which dynamic protocols know.
+/// a connection is alive while messages are received through it, or while it is reported by
+/// add_connection.
+#[derive(Debug, Default)]
+pub(crate) struct Links {
+    /// connection -> when it is heard last.
+    /// None means the connection is reported by add_connection, and it doesn't expire.
+    links: HashMap<(Id, Id), Option<Millis>>,
+}
+
+impl Links {
+    fn key(id: Id, id2: Id) -> (Id, Id) {
+        (id.min(id2), id.max(id2))
+    }
+
+    /// connection which is reported by add_connection
+    pub fn report(&mut self, id: Id, id2: Id) {
+        self.links.insert(Self::key(id, id2), None);
+    }
+
+    /// a message is received through the connection
+    pub fn hear(&mut self, id: Id, id2: Id, now: Millis) {
+        self.links
+            .entry(Self::key(id, id2))
+            .and_modify(|heard| {
+                if heard.is_some() {
+                    *heard = Some(now);
+                }
+            })
+            .or_insert(Some(now));
+    }
+
+    pub fn remove(&mut self, id: Id, id2: Id) {
+        self.links.remove(&Self::key(id, id2));
+    }
+
+    pub fn contains(&self, id: Id, id2: Id) -> bool {
+        self.links.contains_key(&Self::key(id, id2))
+    }
+
+    /// remove connections which are not heard in the timeout
+    pub fn expire(&mut self, now: Millis, timeout: Millis) {
+        self.links
+            .retain(|_, heard| heard.is_none_or(|heard| now.saturating_sub(heard) <= timeout));
+    }
+
+    /// neighbors of the node in ascending order
+    pub fn neighbors(&self, this: Id) -> Vec<Id> {
+        let mut neighbors: Vec<Id> = self
+            .links
+            .keys()
+            .filter_map(|&(id, id2)| match (id == this, id2 == this) {
+                (true, false) => Some(id2),
+                (false, true) => Some(id),
+                _ => None,
+            })
+            .collect();
+        neighbors.sort();
+        neighbors
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_links() {
+        let mut links = Links::default();
+        links.hear(1, 0, 0);
+        links.report(0, 2);
+        links.hear(3, 4, 0);
+        assert_eq!(links.neighbors(0), vec![1, 2]);
+        assert!(links.contains(1, 0));
+
+        // reported connection doesn't expire even if it is heard
+        links.hear(2, 0, 5);
+        links.hear(0, 1, 10);
+        links.expire(30, 20);
+        assert_eq!(links.neighbors(0), vec![1, 2]);
+        assert!(links.neighbors(3).is_empty());
+
+        links.remove(0, 1);
+        assert_eq!(links.neighbors(0), vec![2]);
+    }
+}
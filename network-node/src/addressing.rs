@@ -0,0 +1,37 @@
+use anyhow::Result;
+
+use crate::utils::type_alias::{Coordinate, Id};
+
+/// mapping between coordinates, mac addresses and ip addresses.
+/// it is separated from Protocol, so a routing protocol can be combined with any scheme.
+/// ip addresses must be less than GROUP_ID_BASE.
+pub trait AddressingScheme {
+    /// ip address of the node which joins global network.
+    /// it is called only in the root, so the root can keep the assigned addresses.
+    fn assign(&mut self, mac_address: Id, coordinate: Coordinate) -> Result<Id>;
+    /// ip address of the node at the coordinate
+    fn to_address(&self, coordinate: Coordinate) -> Result<Id>;
+    /// coordinate of the node which has the ip address
+    fn to_coordinate(&self, address: Id) -> Result<Coordinate>;
+    /// ip address which is assigned to the node of the mac address.
+    /// it is known only in the root.
+    fn address_of(&self, _mac_address: Id) -> Option<Id> {
+        // default implementation derives addresses from coordinates, so mac addresses are not kept.
+        None
+    }
+    /// the lease of the address expired, so it can be assigned to another node.
+    /// it is called only in the root.
+    fn release(&mut self, _mac_address: Id, _address: Id) {
+        // default implementation derives addresses from coordinates, so nothing is kept.
+    }
+
+    /// parameters which the root hands out to joining nodes with routing parameters.
+    fn get_parameters(&self) -> Vec<u8> {
+        // default implementation computes addresses without any parameter.
+        Vec::new()
+    }
+    /// set parameters which are received from the root.
+    fn set_parameters(&mut self, _parameters: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
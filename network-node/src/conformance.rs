@@ -0,0 +1,540 @@
+//! conformance test of Protocol implementations.
+//!
+//! `Conformance::check` builds a network of protocols on a topology, and checks invariants which
+//! every protocol must keep:
+//! - join_global_network gives distinct unicast addresses, and they don't move while the network
+//!   grows, because members keep the addresses until they renew the leases.
+//! - routes from every node to every node terminate at the destination within a bounded number
+//!   of hops, and each hop goes through a connection of the topology in a valid channel.
+//! - get_next_node to the node itself is an error, because the packet is delivered locally.
+//! - get_next_node and is_in_route agree: the next hop is in route, and other neighbors of the
+//!   source which are not on the path are not. it is not checked if each node knows only its own
+//!   connections (ConnectionReport::Own), because a node cannot foresee the route which the next
+//!   nodes choose.
+//! - get_distance, if it is implemented for anycast, is positive and not larger than the hops of
+//!   the route.
+//!
+//! It is enabled by the "conformance" feature so that tests of any protocol crate can use it, e.g.
+//! `Conformance::new(DefaultProtocol::new).check_all(&Topology::grids())`.
+//! `Conformance::build` gives the simulated network itself, so that tests can also change the
+//! topology and the time, and check routes after that.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use anyhow::{anyhow, Result};
+
+use crate::packet::{ToId, GROUP_ID_BASE};
+use crate::protocol::{ChannelId, Millis, Protocol, RoutingMessage, CHANNEL_LENGTH};
+use crate::utils::type_alias::{Coordinate, Id};
+
+/// the number of messages which are delivered in a round, to detect protocols which don't
+/// converge
+const MAX_MESSAGES_IN_ROUND: usize = 100_000;
+
+/// how the connections of the topology are told to the protocols by Protocol::add_connection.
+/// add_connection(id, id2) is told as the report of id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionReport {
+    /// each node is told only the connections to its own neighbors, as NetworkNode does
+    Own,
+    /// every node is told every connection, as reported by both of its nodes
+    All,
+    /// connections are not told, so the protocol must find them by routing messages
+    None,
+}
+
+/// nodes and connections of a network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    nodes: Vec<Coordinate>,
+    connections: HashSet<(Coordinate, Coordinate)>,
+}
+
+impl Topology {
+    /// every node in the rectangle is connected to its neighbors
+    pub fn grid(min: Coordinate, max: Coordinate) -> Self {
+        let mut nodes = Vec::new();
+        let mut connections = HashSet::new();
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                nodes.push((x, y));
+                if x < max.0 {
+                    connections.insert(Self::key((x, y), (x + 1, y)));
+                }
+                if y < max.1 {
+                    connections.insert(Self::key((x, y), (x, y + 1)));
+                }
+            }
+        }
+        Self { nodes, connections }
+    }
+
+    /// remove the nodes and their connections
+    pub fn without_nodes(mut self, holes: &[Coordinate]) -> Self {
+        self.nodes.retain(|node| !holes.contains(node));
+        self.connections
+            .retain(|(node, node2)| !holes.contains(node) && !holes.contains(node2));
+        self
+    }
+
+    /// remove the connections, e.g. broken connectors
+    pub fn without_connections(mut self, connections: &[(Coordinate, Coordinate)]) -> Self {
+        for (node, node2) in connections {
+            self.connections.remove(&Self::key(*node, *node2));
+        }
+        self
+    }
+
+    /// full grids which contain the root at (0, 0)
+    pub fn grids() -> Vec<Self> {
+        vec![
+            Self::grid((0, 0), (1, 0)),
+            Self::grid((0, 0), (1, 1)),
+            Self::grid((-1, -1), (1, 1)),
+            Self::grid((-2, 0), (1, 2)),
+        ]
+    }
+
+    /// grids with holes and broken connections, which need routes around them
+    pub fn grids_with_holes() -> Vec<Self> {
+        vec![
+            Self::grid((-1, -1), (1, 1)).without_nodes(&[(0, 1)]),
+            Self::grid((0, 0), (3, 3)).without_nodes(&[(1, 1), (2, 1), (2, 2)]),
+            Self::grid((0, 0), (4, 2)).without_nodes(&[(2, 0), (2, 1)]),
+            Self::grid((-2, -2), (2, 2)).without_connections(&[((0, 0), (1, 0)), ((0, 1), (0, 2))]),
+        ]
+    }
+
+    pub fn get_nodes(&self) -> &[Coordinate] {
+        &self.nodes
+    }
+
+    pub fn is_connected(&self, node: Coordinate, node2: Coordinate) -> bool {
+        self.connections.contains(&Self::key(node, node2))
+    }
+
+    /// the number of hops of the shortest path, or None if the node is not reachable
+    pub fn distance(&self, from: Coordinate, to: Coordinate) -> Option<usize> {
+        self.distances(from).get(&to).copied()
+    }
+
+    fn key(node: Coordinate, node2: Coordinate) -> (Coordinate, Coordinate) {
+        (node.min(node2), node.max(node2))
+    }
+
+    fn neighbors(&self, node: Coordinate) -> Vec<Coordinate> {
+        self.nodes
+            .iter()
+            .copied()
+            .filter(|neighbor| self.is_connected(node, *neighbor))
+            .collect()
+    }
+
+    /// distances of the nodes which are reachable from the node by BFS
+    fn distances(&self, from: Coordinate) -> HashMap<Coordinate, usize> {
+        let mut distances = HashMap::new();
+        if !self.nodes.contains(&from) {
+            return distances;
+        }
+        distances.insert(from, 0);
+        let mut queue = VecDeque::from([from]);
+        while let Some(node) = queue.pop_front() {
+            let distance = distances[&node] + 1;
+            for neighbor in self.neighbors(node) {
+                if let Entry::Vacant(entry) = distances.entry(neighbor) {
+                    entry.insert(distance);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        distances
+    }
+
+    /// whether every node is reachable from the first node
+    fn is_reachable(&self) -> bool {
+        match self.nodes.first() {
+            Some(first) => self.distances(*first).len() == self.nodes.len(),
+            None => true,
+        }
+    }
+}
+
+/// conformance test of the protocol which is made by make_protocol
+pub struct Conformance<T, F>
+where
+    T: Protocol,
+    F: Fn() -> T,
+{
+    make_protocol: F,
+    /// the number of calls of Protocol::update before checking routes
+    update_rounds: u32,
+    /// time between calls of Protocol::update
+    update_interval: Millis,
+    /// how connections of the topology are told
+    connection_report: ConnectionReport,
+}
+
+/// protocols of all nodes, which are made by the root.
+/// routing messages are delivered through the connections of the topology.
+pub struct Network<T: Protocol> {
+    topology: Topology,
+    protocols: HashMap<Id, T>,
+    coordinates: HashMap<Id, Coordinate>,
+    ids: HashMap<Coordinate, Id>,
+    now: Millis,
+    connection_report: ConnectionReport,
+}
+
+impl<T, F> Conformance<T, F>
+where
+    T: Protocol,
+    F: Fn() -> T,
+{
+    pub fn new(make_protocol: F) -> Self {
+        Self {
+            make_protocol,
+            update_rounds: 0,
+            update_interval: 0,
+            connection_report: ConnectionReport::Own,
+        }
+    }
+
+    /// dynamic protocols exchange routing messages by the rounds of update before checking.
+    pub fn set_updates(&mut self, rounds: u32, interval: Millis) -> &mut Self {
+        self.update_rounds = rounds;
+        self.update_interval = interval;
+        self
+    }
+
+    /// each node is told only its own connections by default.
+    /// dynamic protocols can find their neighbors by routing messages. if connections are not
+    /// reported, a connection which is broken without telling it must be detected by the
+    /// protocol.
+    pub fn set_connection_report(&mut self, connection_report: ConnectionReport) -> &mut Self {
+        self.connection_report = connection_report;
+        self
+    }
+
+    pub fn check_all(&self, topologies: &[Topology]) -> Result<()> {
+        for topology in topologies {
+            self.check(topology)
+                .map_err(|e| e.context(format!("in topology {:?}", topology.nodes)))?;
+        }
+        Ok(())
+    }
+
+    pub fn check(&self, topology: &Topology) -> Result<()> {
+        if !topology.is_reachable() {
+            return Err(anyhow!("topology is not connected"));
+        }
+        self.build(topology)?.check_routes()
+    }
+
+    /// make the network on the topology, and exchange routing messages by the rounds of update
+    pub fn build(&self, topology: &Topology) -> Result<Network<T>> {
+        let mut network = self.join(topology)?;
+        for _ in 0..self.update_rounds {
+            network.tick(self.update_interval)?;
+        }
+        Ok(network)
+    }
+
+    /// the root (at (0, 0) if it exists) assigns addresses, and the others get its routing
+    /// parameters. then connections of the topology are told as connection_report.
+    fn join(&self, topology: &Topology) -> Result<Network<T>> {
+        let root = topology
+            .nodes
+            .iter()
+            .copied()
+            .find(|node| *node == (0, 0))
+            .or_else(|| topology.nodes.first().copied())
+            .ok_or_else(|| anyhow!("topology has no node"))?;
+        let mut root_protocol = (self.make_protocol)();
+        let mut coordinates = HashMap::new();
+        let mut ids = HashMap::new();
+        let mut first_ids = Vec::new();
+        for (mac_address, &node) in topology.nodes.iter().enumerate() {
+            first_ids.push(root_protocol.join_global_network(mac_address as Id, node)?);
+        }
+        // members renew their leases after the network has grown
+        for (mac_address, &node) in topology.nodes.iter().enumerate() {
+            let id = root_protocol.join_global_network(mac_address as Id, node)?;
+            if id != first_ids[mac_address] {
+                return Err(anyhow!(
+                    "address of {:?} moved from {} to {} while the network grew",
+                    node,
+                    first_ids[mac_address],
+                    id
+                ));
+            }
+            if id >= GROUP_ID_BASE {
+                return Err(anyhow!("{:?} got group address {:#06x}", node, id));
+            }
+            if let Some(other) = coordinates.insert(id, node) {
+                return Err(anyhow!(
+                    "{:?} and {:?} got the same address {}",
+                    other,
+                    node,
+                    id
+                ));
+            }
+            ids.insert(node, id);
+        }
+
+        let parameters = root_protocol.get_routing_parameters();
+        let mut root_protocol = Some(root_protocol);
+        let mut protocols = HashMap::new();
+        for &node in topology.nodes.iter() {
+            // the root keeps assigned addresses
+            let mut protocol = match root_protocol.take_if(|_| node == root) {
+                Some(protocol) => protocol,
+                None => {
+                    let mut protocol = (self.make_protocol)();
+                    protocol.set_routing_parameters(&parameters)?;
+                    protocol
+                }
+            };
+            for (node1, node2) in topology.connections.iter() {
+                for (reporter, neighbor) in [(node1, node2), (node2, node1)] {
+                    let is_told = match self.connection_report {
+                        ConnectionReport::Own => *reporter == node,
+                        ConnectionReport::All => true,
+                        ConnectionReport::None => false,
+                    };
+                    if is_told {
+                        protocol.add_connection(ids[reporter], ids[neighbor])?;
+                    }
+                }
+            }
+            protocols.insert(ids[&node], protocol);
+        }
+        Ok(Network {
+            topology: topology.clone(),
+            protocols,
+            coordinates,
+            ids,
+            now: 0,
+            connection_report: self.connection_report,
+        })
+    }
+}
+
+impl<T: Protocol> Network<T> {
+    pub fn get_topology(&self) -> &Topology {
+        &self.topology
+    }
+    pub fn get_now(&self) -> Millis {
+        self.now
+    }
+    pub fn get_id(&self, node: Coordinate) -> Id {
+        self.ids[&node]
+    }
+    pub fn get_protocol(&self, node: Coordinate) -> &T {
+        &self.protocols[&self.ids[&node]]
+    }
+    pub fn get_protocol_mut(&mut self, node: Coordinate) -> &mut T {
+        self.protocols
+            .get_mut(&self.ids[&node])
+            .expect("joined node")
+    }
+
+    /// the connection is broken. protocols are not told, so they must detect it by themselves.
+    pub fn disconnect(&mut self, node: Coordinate, node2: Coordinate) {
+        self.topology = self.topology.clone().without_connections(&[(node, node2)]);
+    }
+
+    /// the node stops without telling it to the others
+    pub fn stop(&mut self, node: Coordinate) {
+        self.topology = self.topology.clone().without_nodes(&[node]);
+    }
+
+    /// advance the time, and deliver routing messages of update of every running node until no
+    /// message is sent.
+    pub fn tick(&mut self, millis: Millis) -> Result<()> {
+        self.now += millis;
+        let now = self.now;
+        let mut queue = VecDeque::new();
+        for node in self.topology.nodes.iter() {
+            let id = self.ids[node];
+            let protocol = self.protocols.get_mut(&id).expect("joined node");
+            queue.extend(
+                protocol
+                    .update(id, now)
+                    .into_iter()
+                    .map(|message| (id, message)),
+            );
+        }
+        let mut count = 0;
+        while let Some((sender, RoutingMessage { to, messages })) = queue.pop_front() {
+            count += 1;
+            if count > MAX_MESSAGES_IN_ROUND {
+                return Err(anyhow!("routing messages don't converge at {}", now));
+            }
+            let neighbors: Vec<Id> = self
+                .topology
+                .neighbors(self.coordinates[&sender])
+                .into_iter()
+                .map(|neighbor| self.ids[&neighbor])
+                .collect();
+            let receivers = match to {
+                ToId::Broadcast => neighbors,
+                ToId::Unicast(id) if neighbors.contains(&id) => vec![id],
+                // lost if they are not connected
+                ToId::Unicast(id) if self.coordinates.contains_key(&id) => vec![],
+                to => {
+                    return Err(anyhow!(
+                        "routing message from {} to {:?}, which is not a node",
+                        sender,
+                        to
+                    ))
+                }
+            };
+            for receiver in receivers {
+                let protocol = self.protocols.get_mut(&receiver).expect("joined node");
+                let replies = protocol.process_routing_message(receiver, sender, &messages, now)?;
+                queue.extend(replies.into_iter().map(|message| (receiver, message)));
+            }
+        }
+        Ok(())
+    }
+
+    /// check the routes between every pair of nodes
+    pub fn check_routes(&self) -> Result<()> {
+        for &source in self.topology.nodes.iter() {
+            for &destination in self.topology.nodes.iter() {
+                self.check_route(source, destination)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// follow the route as NetworkNode forwards a packet.
+    /// a packet to the source itself is delivered locally, so it has no route.
+    #[allow(deprecated)]
+    pub fn check_route(&self, source: Coordinate, destination: Coordinate) -> Result<()> {
+        let (source_id, destination_id) = (self.ids[&source], self.ids[&destination]);
+        let max_length = self.topology.nodes.len() * CHANNEL_LENGTH * 2;
+        let route = |this: Id| &self.protocols[&this];
+        if source == destination {
+            return match route(source_id).get_next_node(source_id, destination_id) {
+                Ok(next) => Err(anyhow!(
+                    "{:?} has a route to itself through {}",
+                    source,
+                    next
+                )),
+                Err(_) => Ok(()),
+            };
+        }
+
+        let mut path = vec![source];
+        let mut previous = None;
+        let mut this = source_id;
+        let mut channel = route(source_id).get_channel(source_id, destination_id);
+        while this != destination_id {
+            if channel as usize >= CHANNEL_LENGTH {
+                return Err(anyhow!("channel {} is out of range at {:?}", channel, path));
+            }
+            let (next, next_channel): (Id, ChannelId) = match previous {
+                None => (route(this).get_next_node(this, destination_id)?, channel),
+                Some(previous) => {
+                    route(this).get_next_hop(this, previous, destination_id, channel)?
+                }
+            };
+            let next_coordinate = *self
+                .coordinates
+                .get(&next)
+                .ok_or_else(|| anyhow!("next node {} of {:?} doesn't exist", next, path))?;
+            if !self
+                .topology
+                .is_connected(*path.last().unwrap(), next_coordinate)
+            {
+                return Err(anyhow!(
+                    "{:?} is not connected to {:?}",
+                    path,
+                    next_coordinate
+                ));
+            }
+            path.push(next_coordinate);
+            if path.len() > max_length {
+                return Err(anyhow!("route doesn't terminate: {:?}", path));
+            }
+            previous = Some(this);
+            this = next;
+            channel = next_channel;
+        }
+
+        // the source and the next hop are in route, and the other neighbors are not
+        if self.connection_report != ConnectionReport::Own {
+            let next = self.ids[&path[1]];
+            if !route(source_id).is_in_route(source_id, source_id, destination_id)
+                || !route(next).is_in_route(next, source_id, destination_id)
+            {
+                return Err(anyhow!(
+                    "is_in_route doesn't agree with the path {:?}",
+                    path
+                ));
+            }
+            for neighbor in self.topology.neighbors(source) {
+                let id = self.ids[&neighbor];
+                if !path.contains(&neighbor) && route(id).is_in_route(id, source_id, destination_id)
+                {
+                    return Err(anyhow!(
+                        "{:?} is in route, but not on the path {:?}",
+                        neighbor,
+                        path
+                    ));
+                }
+            }
+        }
+
+        if let Ok(distance) = route(source_id).get_distance(source_id, destination_id) {
+            if distance == 0 || distance as usize >= path.len() {
+                return Err(anyhow!(
+                    "distance {} doesn't agree with the path {:?}",
+                    distance,
+                    path
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::test::TestProtocol;
+
+    #[test]
+    fn test_topology() {
+        let topology = Topology::grid((0, 0), (2, 1));
+        assert_eq!(topology.get_nodes().len(), 6);
+        assert!(topology.is_connected((1, 1), (1, 0)));
+        assert!(!topology.is_connected((0, 0), (1, 1)));
+
+        let topology = topology.without_nodes(&[(1, 0)]);
+        assert_eq!(topology.neighbors((0, 0)), vec![(0, 1)]);
+        assert!(topology.is_reachable());
+        let topology = topology.without_connections(&[((1, 1), (0, 1))]);
+        assert!(!topology.is_reachable());
+        for topology in Topology::grids()
+            .into_iter()
+            .chain(Topology::grids_with_holes())
+        {
+            assert!(topology.is_reachable());
+        }
+    }
+
+    #[test]
+    fn test_inconsistent_protocol() {
+        // every node gets address 0
+        let conformance = Conformance::new(TestProtocol::new);
+        // a single node gets a distinct address, but it has a route to itself
+        assert!(conformance.check(&Topology::grid((0, 0), (0, 0))).is_err());
+        assert!(conformance.check(&Topology::grid((0, 0), (1, 0))).is_err());
+        assert!(conformance
+            .check(&Topology::grid((0, 0), (1, 0)).without_connections(&[((0, 0), (1, 0))]))
+            .is_err());
+    }
+}
@@ -0,0 +1,470 @@
+//! estimation of the coordinate when the node starts.
+//!
+//! A node which is not in the root localnet asks its neighbors for their coordinates by broadcast,
+//! and decides its coordinate from the replies. It is driven by NetworkNode::poll, so that the
+//! application can update the display or time out while the node is waiting for neighbors.
+//!
+//! Neighbors may claim coordinates which disagree, e.g. a node which is moved still tells its old
+//! coordinate. Of two claims which contradict each other, the one which more neighbors support is
+//! kept, and claims which are supported equally are not used. Each consistent pair of claims votes
+//! for a coordinate of this node, and the majority is chosen. Conflicts which are not resolved
+//! are reported to the root.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem::size_of;
+
+use anyhow::{anyhow, Error, Result};
+
+use crate::header::Header;
+use crate::packet::{Packet, ToId};
+use crate::protocol::Millis;
+use crate::utils::type_alias::{Coordinate, CoordinateComponent, Id};
+
+/// interval of requesting coordinates of neighbors again
+pub const REQUEST_INTERVAL: Millis = 1_000;
+/// interval of requesting again after the request failed, e.g. by collision
+pub const RETRY_INTERVAL: Millis = 300;
+/// estimation fails when the coordinate is not decided in this time
+pub const ESTIMATION_TIMEOUT: Millis = 120_000;
+/// the number of conflicts which are kept
+pub const MAX_CONFLICTS: usize = 8;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum EstimationState {
+    /// no coordinate is received yet
+    Discovering,
+    /// some coordinates are received, but they don't decide the coordinate of this node yet.
+    /// it needs a neighbor outside the localnet, or a confirmed node in the localnet.
+    WaitingForNeighbor,
+    /// the coordinate is decided
+    Confirmed,
+    /// the coordinate is not decided in ESTIMATION_TIMEOUT, or the node doesn't join global
+    /// network in time after the coordinate is decided
+    Failed,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ConflictKind {
+    /// a node is claimed at two coordinates by the same number of neighbors
+    SameNode = 0,
+    /// two nodes are claimed at the same coordinate by the same number of neighbors
+    SameCoordinate = 1,
+    /// two coordinates of this node get the same number of votes
+    Tie = 2,
+}
+
+impl TryFrom<u8> for ConflictKind {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(ConflictKind::SameNode),
+            1 => Ok(ConflictKind::SameCoordinate),
+            2 => Ok(ConflictKind::Tie),
+            _ => Err(anyhow!("invalid conflict kind: {}", value)),
+        }
+    }
+}
+
+/// two claims (node, coordinate) which disagree, in ascending order
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Conflict {
+    pub kind: ConflictKind,
+    pub claims: [(Id, Coordinate); 2],
+}
+
+impl Conflict {
+    pub fn new(kind: ConflictKind, claim: (Id, Coordinate), claim2: (Id, Coordinate)) -> Self {
+        Self {
+            kind,
+            claims: [claim.min(claim2), claim.max(claim2)],
+        }
+    }
+}
+
+/// resolve claims (node that send the coordinate, node that has the coordinate, coordinate)
+/// which contradict each other, and return the claims which are kept and the conflicts which are
+/// not resolved.
+/// a claim is supported by the neighbors which send it, so the same claim from different neighbors
+/// agrees. of two contradicting claims, the one with fewer supporters is dropped, and both are
+/// dropped if they are tied.
+pub fn find_conflicts(
+    claims: &[(Id, Id, Coordinate)],
+) -> (Vec<(Id, Id, Coordinate)>, Vec<Conflict>) {
+    // (node, coordinate) -> the number of neighbors which send it
+    let mut supporters: Vec<((Id, Coordinate), usize)> = Vec::new();
+    for (_, id, coordinate) in claims {
+        match supporters
+            .iter_mut()
+            .find(|(claim, _)| *claim == (*id, *coordinate))
+        {
+            Some((_, count)) => *count += 1,
+            None => supporters.push(((*id, *coordinate), 1)),
+        }
+    }
+    supporters.sort();
+
+    let mut dropped = Vec::new();
+    let mut conflicts = Vec::new();
+    for (i, (claim, count)) in supporters.iter().enumerate() {
+        for (claim2, count2) in supporters[i + 1..].iter() {
+            let kind = if claim.0 == claim2.0 {
+                ConflictKind::SameNode
+            } else if claim.1 == claim2.1 {
+                ConflictKind::SameCoordinate
+            } else {
+                continue;
+            };
+            match count.cmp(count2) {
+                Ordering::Greater => dropped.push(*claim2),
+                Ordering::Less => dropped.push(*claim),
+                Ordering::Equal => {
+                    dropped.push(*claim);
+                    dropped.push(*claim2);
+                    conflicts.push(Conflict::new(kind, *claim, *claim2));
+                }
+            }
+        }
+    }
+    let kept = claims
+        .iter()
+        .copied()
+        .filter(|(_, id, coordinate)| !dropped.contains(&(*id, *coordinate)))
+        .collect();
+    (kept, conflicts)
+}
+
+/// conflict which a node reports to the root.
+/// Data form is like this
+/// [ mac_address(16) | kind(8) | (id(16) | x(16) | y(16)) * 2 ]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct ConflictReport {
+    /// mac address of the node which found the conflict
+    pub mac_address: Id,
+    pub conflict: Conflict,
+}
+
+impl ConflictReport {
+    const CLAIM_LENGTH: usize = size_of::<Id>() + size_of::<CoordinateComponent>() * 2;
+    const MESSAGE_LENGTH: usize = size_of::<Id>() + 1 + Self::CLAIM_LENGTH * 2;
+
+    /// the report goes up along the spanning tree as same as join request, so it reaches the
+    /// root before the node joins global network.
+    pub fn to_packet(&self, parent: Id) -> Result<Packet> {
+        let mut messages = Vec::new();
+        messages.extend(self.mac_address.to_be_bytes());
+        messages.push(self.conflict.kind as u8);
+        for (id, (x, y)) in self.conflict.claims {
+            messages.extend(id.to_be_bytes());
+            messages.extend(x.to_be_bytes());
+            messages.extend(y.to_be_bytes());
+        }
+        Packet::new(
+            0,
+            Header::ReportConflict,
+            self.mac_address,
+            ToId::Broadcast,
+            self.mac_address,
+            ToId::Unicast(parent),
+            messages,
+        )
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<Self> {
+        if packet.get_header() != Header::ReportConflict {
+            return Err(anyhow!(
+                "This packet is not ReportConflict: {:?}",
+                packet.get_header()
+            ));
+        }
+        let messages = packet.get_ref_messages();
+        if messages.len() < Self::MESSAGE_LENGTH {
+            return Err(anyhow!(
+                "length of conflict report is not enough: {:?}",
+                messages
+            ));
+        }
+        let mac_address = Id::from_be_bytes([messages[0], messages[1]]);
+        let kind = ConflictKind::try_from(messages[2])?;
+        let mut claims = messages[3..Self::MESSAGE_LENGTH]
+            .chunks_exact(Self::CLAIM_LENGTH)
+            .map(|bytes| {
+                (
+                    Id::from_be_bytes([bytes[0], bytes[1]]),
+                    (
+                        CoordinateComponent::from_be_bytes([bytes[2], bytes[3]]),
+                        CoordinateComponent::from_be_bytes([bytes[4], bytes[5]]),
+                    ),
+                )
+            });
+        let claim = claims.next().expect("checked by MESSAGE_LENGTH");
+        let claim2 = claims.next().expect("checked by MESSAGE_LENGTH");
+        Ok(Self {
+            mac_address,
+            conflict: Conflict::new(kind, claim, claim2),
+        })
+    }
+}
+
+/// progress of the estimation
+#[derive(Debug, Clone)]
+pub struct Estimation {
+    state: EstimationState,
+    /// (node that send the coordinate(neighbor), node that has the coordinate, coordinate)
+    /// if the information is send by confirmed node in non-localnet, first and second node Id is
+    /// same.
+    neighbor_confirmed: Vec<(Id, Id, Coordinate)>,
+    started_at: Millis,
+    /// the time to send the next request
+    request_at: Millis,
+    /// the number of requests which are sent
+    requests: u32,
+    /// the number of replies which have coordinates
+    replies: u32,
+    /// the last error, e.g. a collision or a broken packet
+    last_error: Option<String>,
+    /// conflicts which are found, at most MAX_CONFLICTS
+    conflicts: Vec<Conflict>,
+}
+
+impl Estimation {
+    pub fn new(now: Millis) -> Self {
+        Self {
+            state: EstimationState::Discovering,
+            neighbor_confirmed: Vec::new(),
+            started_at: now,
+            request_at: now,
+            requests: 0,
+            replies: 0,
+            last_error: None,
+            conflicts: Vec::new(),
+        }
+    }
+
+    /// the root localnet knows its coordinates without estimation
+    pub fn confirmed() -> Self {
+        Self {
+            state: EstimationState::Confirmed,
+            ..Self::new(0)
+        }
+    }
+
+    pub fn get_state(&self) -> EstimationState {
+        self.state
+    }
+
+    /// whether this node still waits for coordinates of neighbors
+    pub fn is_estimating(&self) -> bool {
+        matches!(
+            self.state,
+            EstimationState::Discovering | EstimationState::WaitingForNeighbor
+        )
+    }
+
+    pub fn get_neighbor_confirmed(&self) -> &Vec<(Id, Id, Coordinate)> {
+        &self.neighbor_confirmed
+    }
+
+    pub fn get_requests(&self) -> u32 {
+        self.requests
+    }
+
+    pub fn get_replies(&self) -> u32 {
+        self.replies
+    }
+
+    pub fn get_last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    pub fn get_conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    pub fn get_elapsed(&self, now: Millis) -> Millis {
+        now.saturating_sub(self.started_at)
+    }
+
+    pub(crate) fn is_request_due(&self, now: Millis) -> bool {
+        self.is_estimating() && now >= self.request_at
+    }
+
+    /// the request is sent, or failed to be sent
+    pub(crate) fn requested(&mut self, now: Millis, error: Option<&Error>) {
+        // random delay avoids collision with neighbors which start at the same time
+        let jitter = rand::random::<Millis>() % 200;
+        match error {
+            Some(e) => {
+                self.set_error(e);
+                self.request_at = now + RETRY_INTERVAL + jitter;
+            }
+            None => {
+                self.requests += 1;
+                self.request_at = now + REQUEST_INTERVAL + jitter;
+            }
+        }
+    }
+
+    /// coordinates which a neighbor replied
+    pub(crate) fn receive(&mut self, from_id: Id, coordinates: Vec<(Id, Coordinate)>) {
+        self.replies += 1;
+        for (coordinate_id, coordinate) in coordinates {
+            let item = (from_id, coordinate_id, coordinate);
+            if !self.neighbor_confirmed.contains(&item) {
+                self.neighbor_confirmed.push(item);
+            }
+        }
+        if self.state == EstimationState::Discovering && !self.neighbor_confirmed.is_empty() {
+            self.state = EstimationState::WaitingForNeighbor;
+        }
+    }
+
+    pub(crate) fn set_error(&mut self, error: &Error) {
+        self.last_error = Some(error.to_string());
+    }
+
+    /// keep the conflicts, and return the ones which are new, so that they are reported
+    pub(crate) fn add_conflicts(&mut self, conflicts: Vec<Conflict>) -> Vec<Conflict> {
+        let mut added = Vec::new();
+        for conflict in conflicts {
+            if !self.conflicts.contains(&conflict) && self.conflicts.len() < MAX_CONFLICTS {
+                self.conflicts.push(conflict);
+                added.push(conflict);
+            }
+        }
+        added
+    }
+
+    pub(crate) fn confirm(&mut self) {
+        self.state = EstimationState::Confirmed;
+    }
+
+    /// the node gives up, e.g. it doesn't join global network in time
+    pub(crate) fn fail(&mut self, error: &Error) {
+        self.set_error(error);
+        self.state = EstimationState::Failed;
+    }
+
+    /// fail if the coordinate is not decided in ESTIMATION_TIMEOUT
+    pub(crate) fn check_timeout(&mut self, now: Millis) {
+        if self.is_estimating() && self.get_elapsed(now) > ESTIMATION_TIMEOUT {
+            self.state = EstimationState::Failed;
+        }
+    }
+}
+
+/// text for the display, which is at most 21 characters per line
+impl fmt::Display for Estimation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            EstimationState::Discovering => "discovering",
+            EstimationState::WaitingForNeighbor => "waiting neighbor",
+            EstimationState::Confirmed => "confirmed",
+            EstimationState::Failed => "failed",
+        };
+        writeln!(f, "{}", state)?;
+        writeln!(
+            f,
+            "req {} rep {} coord {}",
+            self.requests,
+            self.replies,
+            self.neighbor_confirmed.len()
+        )?;
+        if !self.conflicts.is_empty() {
+            writeln!(f, "conflicts {}", self.conflicts.len())?;
+        }
+        if let Some(error) = &self.last_error {
+            let error: String = error.chars().take(21).collect();
+            writeln!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_estimation() {
+        let mut estimation = Estimation::new(0);
+        assert_eq!(estimation.get_state(), EstimationState::Discovering);
+        assert!(estimation.is_request_due(0));
+
+        // collision is retried sooner than the next request
+        estimation.requested(0, Some(&anyhow!("collision")));
+        assert_eq!(estimation.get_requests(), 0);
+        assert_eq!(estimation.get_last_error(), Some("collision"));
+        assert!(!estimation.is_request_due(RETRY_INTERVAL - 1));
+        assert!(estimation.is_request_due(REQUEST_INTERVAL));
+        estimation.requested(REQUEST_INTERVAL, None);
+        assert_eq!(estimation.get_requests(), 1);
+        assert!(!estimation.is_request_due(REQUEST_INTERVAL * 2 - 1));
+
+        // duplicates are ignored
+        estimation.receive(3, vec![(3, (1, 0))]);
+        estimation.receive(3, vec![(3, (1, 0))]);
+        assert_eq!(estimation.get_state(), EstimationState::WaitingForNeighbor);
+        assert_eq!(estimation.get_replies(), 2);
+        assert_eq!(estimation.get_neighbor_confirmed(), &vec![(3, 3, (1, 0))]);
+        assert_eq!(
+            estimation.to_string(),
+            "waiting neighbor\nreq 1 rep 2 coord 1\ncollision\n"
+        );
+
+        estimation.check_timeout(ESTIMATION_TIMEOUT);
+        assert!(estimation.is_estimating());
+        estimation.check_timeout(ESTIMATION_TIMEOUT + 1);
+        assert_eq!(estimation.get_state(), EstimationState::Failed);
+        assert!(!estimation.is_request_due(ESTIMATION_TIMEOUT * 2));
+
+        assert_eq!(
+            Estimation::confirmed().get_state(),
+            EstimationState::Confirmed
+        );
+    }
+
+    #[test]
+    fn test_find_conflicts() {
+        // the same claim from two neighbors agrees
+        let claims = [(1, 3, (1, 0)), (2, 3, (1, 0))];
+        assert_eq!(find_conflicts(&claims), (claims.to_vec(), vec![]));
+
+        // tied claims are both dropped
+        let (kept, conflicts) = find_conflicts(&[(1, 3, (1, 0)), (2, 3, (5, 5)), (2, 4, (1, 0))]);
+        assert!(kept.is_empty());
+        assert_eq!(
+            conflicts,
+            vec![
+                Conflict::new(ConflictKind::SameNode, (3, (1, 0)), (3, (5, 5))),
+                Conflict::new(ConflictKind::SameCoordinate, (3, (1, 0)), (4, (1, 0))),
+            ]
+        );
+
+        // the majority is kept without a conflict
+        let (kept, conflicts) = find_conflicts(&[
+            (1, 3, (1, 0)),
+            (2, 3, (1, 0)),
+            (5, 3, (5, 5)),
+            (5, 4, (1, 0)),
+            (6, 6, (2, 0)),
+        ]);
+        assert_eq!(kept, vec![(1, 3, (1, 0)), (2, 3, (1, 0)), (6, 6, (2, 0))]);
+        assert!(conflicts.is_empty());
+
+        let report = ConflictReport {
+            mac_address: 0x1234,
+            conflict: Conflict::new(ConflictKind::Tie, (12, (2, 0)), (12, (-2, 0))),
+        };
+        let packet = report.to_packet(5).unwrap();
+        assert_eq!(packet.get_to(), ToId::Unicast(5));
+        assert_eq!(ConflictReport::from_packet(&packet).unwrap(), report);
+
+        let conflicts = vec![report.conflict];
+        let mut estimation = Estimation::new(0);
+        assert_eq!(estimation.add_conflicts(conflicts.clone()), conflicts);
+        assert!(estimation.add_conflicts(conflicts.clone()).is_empty());
+        assert_eq!(estimation.get_conflicts(), &conflicts[..]);
+    }
+}
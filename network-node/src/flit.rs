@@ -1,5 +1,6 @@
 use super::header::Header;
-use super::packet::PacketId;
+use super::packet::{PacketId, MAX_PACKET_ID};
+use super::protocol::ChannelId;
 use super::serial::SerialTrait;
 use crate::utils::type_alias::Id;
 use anyhow::anyhow;
@@ -10,7 +11,7 @@ use std::convert::TryFrom;
 use std::ops;
 
 /// Flit consists of 64 bits.
-/// HeadFlit : [ FlitType(2) | LengthOfFlit(6) | Header(8) | SourceId(16) | DestinationId(16) | PacketId(8) | Checksum(8) ]
+/// HeadFlit : [ FlitType(2) | LengthOfFlit(6) | Header(8) | SourceId(16) | DestinationId(16) | Channel(2) | PacketId(6) | Checksum(8) ]
 /// Body and TailFlit : [ FlitType(2) | FlitId(6) | Message(48) | Checksum(8)]
 /// NopeFlit : [ FlitType(2) | z(undefined)(62) ]
 #[derive(Debug, Clone, Copy)]
@@ -33,6 +34,8 @@ const TIMEOUT_MILLIS: u64 = 100000;
 const DELAY_MILLIS: u64 = 1000;
 const MAX_LOOPS: u64 = TIMEOUT_MILLIS / DELAY_MILLIS;
 pub const MAX_FLIT_LENGTH: FlitId = 64;
+/// channel is upper 2 bits of the packet id byte in head flit
+const CHANNEL_SHIFT: u8 = 6;
 
 impl Flit {
     // ////////////////////////////////
@@ -160,16 +163,14 @@ impl Flit {
         let flittype = FlitType::Head as u8;
         let len_of_flit = len_of_flit as u8;
         flitbyte[0] = Self::set_2_6bits(flittype, len_of_flit);
-        let header = header as u8;
-        flitbyte[1] = header;
+        flitbyte[1] = header.into();
         let source_id = source_id.to_be_bytes();
         flitbyte[2] = source_id[0];
         flitbyte[3] = source_id[1];
         let destination_id = destination_id.to_be_bytes();
         flitbyte[4] = destination_id[0];
         flitbyte[5] = destination_id[1];
-        let packet_id = packet_id.to_be_bytes();
-        flitbyte[6] = packet_id[0];
+        flitbyte[6] = packet_id & MAX_PACKET_ID;
 
         let checksum = Self::calculate_checksum(&flitbyte);
         flitbyte[7] = checksum;
@@ -216,6 +217,18 @@ impl Flit {
     fn set_flit_type(flit: &mut Flit, flit_type: FlitType) {
         *flit |= (flit_type as u64) << 62;
     }
+    /// set virtual channel of head flit
+    pub fn set_channel(&mut self, channel: ChannelId) {
+        let mut flitbyte = self.to_be_bytes();
+        flitbyte[6] = (flitbyte[6] & MAX_PACKET_ID) | (channel << CHANNEL_SHIFT);
+        flitbyte[7] = 0;
+        flitbyte[7] = Self::calculate_checksum(&flitbyte);
+        *self = Flit::from_be_bytes(flitbyte);
+    }
+    /// virtual channel of head flit
+    pub fn get_channel(&self) -> ChannelId {
+        self.to_be_bytes()[6] >> CHANNEL_SHIFT
+    }
 
     fn calculate_checksum(flitbyte: &[u8; 8]) -> u8 {
         let mut sum: u8 = 0;
@@ -284,7 +297,7 @@ impl Flit {
         let header = Header::try_from(bytes[1])?;
         let source_id = u16::from_be_bytes([bytes[2], bytes[3]]);
         let destination_id = u16::from_be_bytes([bytes[4], bytes[5]]);
-        let packet_id = bytes[6];
+        let packet_id = bytes[6] & MAX_PACKET_ID;
         let checksum = bytes[7];
         let mut sum: u8 = 0;
         for i in 0..=6 {
@@ -334,7 +347,7 @@ impl Flit {
     }
     pub fn get_header(&self) -> Result<Header> {
         let header = Self::get_u8_from_u64(self.0, 48);
-        Ok(Header::try_from(header)?)
+        Header::try_from(header)
     }
 
     // ////////////////////////////////
@@ -390,7 +403,7 @@ mod test {
     fn test_simple_head_flit() {
         // make head flit
         let flit = Flit::make_head_flit(0, Header::Data, 0, 1, 0);
-        assert_eq!(Header::Data as u8, 0b00000000);
+        assert_eq!(u8::from(Header::Data), 0b00000000);
 
         let (flit_type, length_of_flit) = Flit::get_flit_type_and_length(&flit).unwrap();
         assert_eq!(
@@ -479,6 +492,24 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_channel() {
+        let mut flit = Flit::make_head_flit(3, Header::Data, 0x1234, 0x5678, 0x2A);
+        assert_eq!(flit.get_channel(), 0);
+        flit.set_channel(3);
+        assert_eq!(flit.get_channel(), 3);
+        // other fields and checksum are kept
+        let (length_of_flit, header, source_id, destination_id, packet_id) =
+            Flit::get_head_information(&flit).unwrap();
+        assert_eq!(
+            (length_of_flit, header, source_id, destination_id, packet_id),
+            (3, Header::Data, 0x1234, 0x5678, 0x2A)
+        );
+        flit.set_channel(1);
+        assert_eq!(flit.get_channel(), 1);
+        assert!(Flit::get_head_information(&flit).is_ok());
+    }
+
     #[test]
     fn test_get_header() {
         let flit = Flit::make_head_flit(0, Header::Data, 0, 1, 0);
@@ -1,9 +1,46 @@
+use std::convert::TryFrom;
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Error, Result};
 use num_enum::TryFromPrimitive;
 
+/// header values in [APP_HEADER_BEGIN, 0xFF] are reserved for applications.
+/// others are used by the system.
+pub const APP_HEADER_BEGIN: u8 = 0x80;
+pub const APP_HEADER_LENGTH: usize = (u8::MAX - APP_HEADER_BEGIN) as usize + 1;
+
+/// id of header defined by applications.
+/// it is an offset from APP_HEADER_BEGIN, so it is always less than APP_HEADER_LENGTH.
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+pub struct AppHeaderId(u8);
+
+impl AppHeaderId {
+    pub fn new(id: u8) -> Result<Self> {
+        if id as usize >= APP_HEADER_LENGTH {
+            return Err(anyhow!(
+                "app header id must be less than {}, but {}",
+                APP_HEADER_LENGTH,
+                id
+            ));
+        }
+        Ok(Self(id))
+    }
+
+    pub fn get(&self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u8> for AppHeaderId {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self> {
+        Self::new(value)
+    }
+}
+
 /// todo) now we use only data and ack header
 /// initial of a header that use only head flit is H
-#[derive(TryFromPrimitive, Eq, PartialEq, Debug, Clone, Copy, Hash)]
-#[repr(u8)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
 pub enum Header {
     // ////////////////////////////////
     // general case
@@ -26,7 +63,7 @@ pub enum Header {
     // ////////////////////////////////
     // Making general network
     // ////////////////////////////////
-    // todo
+    // spanning tree (see spanning_tree.rs)
     SendParentId,
     ReceiveParentId,
     SendChildId,
@@ -34,9 +71,115 @@ pub enum Header {
 
     // System ack
     HAck,
+
+    // join global network (see membership.rs)
+    RequestJoinNetwork,
+    ReplyJoinNetwork,
+
+    // messages of dynamic routing protocol, which are exchanged only between neighbors
+    // (see Protocol::update)
+    Routing,
+
+    // routing table of a remote node, which is requested for diagnosis
+    // (see Protocol::get_routing_table)
+    RequestRoutingTable,
+    ReplyRoutingTable,
+
+    // services which a node provides, which are flooded for anycast (see service.rs)
+    AdvertiseService,
+
+    // probe which tells neighbors that this node is alive (see neighbor.rs)
+    ProbeNeighbor,
+
+    // coordinates which neighbors claimed inconsistently, which are reported to the root
+    // (see estimation.rs)
+    ReportConflict,
+
+    // ////////////////////////////////
+    // application defined
+    // ////////////////////////////////
+    App(AppHeaderId),
+}
+
+/// raw value of system header.
+/// the order must be the same as Header.
+#[derive(TryFromPrimitive, Eq, PartialEq, Debug, Clone, Copy)]
+#[repr(u8)]
+enum SystemHeader {
+    Data,
+    GeneralAck,
+    Error,
+    HCheckConnection,
+    HRequestConfirmedCoordinate,
+    ConfirmCoordinate,
+    SendParentId,
+    ReceiveParentId,
+    SendChildId,
+    ReceiveChildId,
+    HAck,
+    RequestJoinNetwork,
+    ReplyJoinNetwork,
+    Routing,
+    RequestRoutingTable,
+    ReplyRoutingTable,
+    AdvertiseService,
+    ProbeNeighbor,
+    ReportConflict,
+}
+
+/// properties of application defined header.
+/// unregistered header is treated as same as Header::Data.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct AppHeaderProperty {
+    pub is_only_head: bool,
+    pub is_require_ack: bool,
+}
+
+impl AppHeaderProperty {
+    pub const fn new(is_only_head: bool, is_require_ack: bool) -> Self {
+        Self {
+            is_only_head,
+            is_require_ack,
+        }
+    }
+}
+
+impl Default for AppHeaderProperty {
+    fn default() -> Self {
+        Self::new(false, true)
+    }
 }
 
+static APP_HEADER_PROPERTIES: RwLock<[AppHeaderProperty; APP_HEADER_LENGTH]> =
+    RwLock::new([AppHeaderProperty::new(false, true); APP_HEADER_LENGTH]);
+
 impl Header {
+    /// make application defined header.
+    pub fn app(id: u8) -> Result<Self> {
+        Ok(Header::App(AppHeaderId::new(id)?))
+    }
+
+    /// register properties of application defined header.
+    /// it is shared by all packets in this node, so every node must register the same properties.
+    pub fn register_app_header(id: AppHeaderId, property: AppHeaderProperty) -> Result<Self> {
+        let mut properties = APP_HEADER_PROPERTIES
+            .write()
+            .map_err(|e| anyhow!("failed to lock app header properties: {}", e))?;
+        properties[id.get() as usize] = property;
+        Ok(Header::App(id))
+    }
+
+    fn app_header_property(id: AppHeaderId) -> AppHeaderProperty {
+        match APP_HEADER_PROPERTIES.read() {
+            Ok(properties) => properties[id.get() as usize],
+            Err(_) => AppHeaderProperty::default(),
+        }
+    }
+
+    pub fn is_app(&self) -> bool {
+        matches!(self, Header::App(_))
+    }
+
     // todo) consider whether to use body and tail flits
     pub fn is_only_head(&self) -> bool {
         match self {
@@ -47,23 +190,151 @@ impl Header {
             | Header::SendChildId
             | Header::ReceiveChildId
             | Header::ConfirmCoordinate
+            | Header::RequestJoinNetwork
+            | Header::ReplyJoinNetwork
+            | Header::Routing
+            | Header::RequestRoutingTable
+            | Header::ReplyRoutingTable
+            | Header::AdvertiseService
+            | Header::ProbeNeighbor
+            | Header::ReportConflict
             | Header::Error => false,
             Header::HAck | Header::HRequestConfirmedCoordinate | Header::HCheckConnection => true,
+            Header::App(id) => Self::app_header_property(*id).is_only_head,
         }
     }
     pub fn is_require_ack(&self) -> bool {
         match self {
             Header::Data
             | Header::GeneralAck
-            | Header::SendParentId
             | Header::ReceiveParentId
             | Header::SendChildId
             | Header::ReceiveChildId
+            | Header::RequestJoinNetwork
+            | Header::ReplyJoinNetwork
             | Header::Error => true,
+            // SendParentId is sent by broadcast
+            // Routing is sent periodically, so lost messages are recovered by the next one
+            // routing table is requested again if it is lost
+            // AdvertiseService and ProbeNeighbor are sent periodically by broadcast
+            // ReportConflict is only for diagnosis
             Header::HAck
             | Header::HRequestConfirmedCoordinate
             | Header::HCheckConnection
-            | Header::ConfirmCoordinate => false,
+            | Header::ConfirmCoordinate
+            | Header::SendParentId
+            | Header::Routing
+            | Header::RequestRoutingTable
+            | Header::ReplyRoutingTable
+            | Header::AdvertiseService
+            | Header::ProbeNeighbor
+            | Header::ReportConflict => false,
+            Header::App(id) => Self::app_header_property(*id).is_require_ack,
         }
     }
 }
+
+impl From<SystemHeader> for Header {
+    fn from(value: SystemHeader) -> Self {
+        match value {
+            SystemHeader::Data => Header::Data,
+            SystemHeader::GeneralAck => Header::GeneralAck,
+            SystemHeader::Error => Header::Error,
+            SystemHeader::HCheckConnection => Header::HCheckConnection,
+            SystemHeader::HRequestConfirmedCoordinate => Header::HRequestConfirmedCoordinate,
+            SystemHeader::ConfirmCoordinate => Header::ConfirmCoordinate,
+            SystemHeader::SendParentId => Header::SendParentId,
+            SystemHeader::ReceiveParentId => Header::ReceiveParentId,
+            SystemHeader::SendChildId => Header::SendChildId,
+            SystemHeader::ReceiveChildId => Header::ReceiveChildId,
+            SystemHeader::HAck => Header::HAck,
+            SystemHeader::RequestJoinNetwork => Header::RequestJoinNetwork,
+            SystemHeader::ReplyJoinNetwork => Header::ReplyJoinNetwork,
+            SystemHeader::Routing => Header::Routing,
+            SystemHeader::RequestRoutingTable => Header::RequestRoutingTable,
+            SystemHeader::ReplyRoutingTable => Header::ReplyRoutingTable,
+            SystemHeader::AdvertiseService => Header::AdvertiseService,
+            SystemHeader::ProbeNeighbor => Header::ProbeNeighbor,
+            SystemHeader::ReportConflict => Header::ReportConflict,
+        }
+    }
+}
+
+impl From<Header> for u8 {
+    fn from(value: Header) -> Self {
+        let system_header = match value {
+            Header::Data => SystemHeader::Data,
+            Header::GeneralAck => SystemHeader::GeneralAck,
+            Header::Error => SystemHeader::Error,
+            Header::HCheckConnection => SystemHeader::HCheckConnection,
+            Header::HRequestConfirmedCoordinate => SystemHeader::HRequestConfirmedCoordinate,
+            Header::ConfirmCoordinate => SystemHeader::ConfirmCoordinate,
+            Header::SendParentId => SystemHeader::SendParentId,
+            Header::ReceiveParentId => SystemHeader::ReceiveParentId,
+            Header::SendChildId => SystemHeader::SendChildId,
+            Header::ReceiveChildId => SystemHeader::ReceiveChildId,
+            Header::HAck => SystemHeader::HAck,
+            Header::RequestJoinNetwork => SystemHeader::RequestJoinNetwork,
+            Header::ReplyJoinNetwork => SystemHeader::ReplyJoinNetwork,
+            Header::Routing => SystemHeader::Routing,
+            Header::RequestRoutingTable => SystemHeader::RequestRoutingTable,
+            Header::ReplyRoutingTable => SystemHeader::ReplyRoutingTable,
+            Header::AdvertiseService => SystemHeader::AdvertiseService,
+            Header::ProbeNeighbor => SystemHeader::ProbeNeighbor,
+            Header::ReportConflict => SystemHeader::ReportConflict,
+            Header::App(id) => return APP_HEADER_BEGIN + id.get(),
+        };
+        system_header as u8
+    }
+}
+
+impl TryFrom<u8> for Header {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self> {
+        if value >= APP_HEADER_BEGIN {
+            return Ok(Header::App(AppHeaderId(value - APP_HEADER_BEGIN)));
+        }
+        match SystemHeader::try_from(value) {
+            Ok(header) => Ok(header.into()),
+            Err(_) => Err(anyhow!("invalid header: {}", value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_conversion() {
+        for value in 0..=u8::MAX {
+            match Header::try_from(value) {
+                Ok(header) => assert_eq!(u8::from(header), value),
+                Err(_) => assert!(value < APP_HEADER_BEGIN),
+            }
+        }
+        assert_eq!(u8::from(Header::Data), 0);
+        assert_eq!(u8::from(Header::HAck), 10);
+        assert_eq!(u8::from(Header::app(0).unwrap()), APP_HEADER_BEGIN);
+        assert_eq!(Header::try_from(0xFF).unwrap(), Header::app(0x7F).unwrap());
+        assert!(Header::try_from(19).is_err());
+        assert!(Header::app(0x80).is_err());
+        assert!(Header::app(200).is_err());
+        assert!(AppHeaderId::try_from(u8::MAX).is_err());
+    }
+
+    #[test]
+    fn test_register_app_header() {
+        let header = Header::app(3).unwrap();
+        assert!(!header.is_only_head());
+        assert!(header.is_require_ack());
+
+        let id = AppHeaderId::new(3).unwrap();
+        let header = Header::register_app_header(id, AppHeaderProperty::new(true, false)).unwrap();
+        assert_eq!(header, Header::App(id));
+        assert!(header.is_only_head());
+        assert!(!header.is_require_ack());
+        // other app headers are not changed
+        assert!(!Header::app(4).unwrap().is_only_head());
+    }
+}
@@ -1,13 +1,26 @@
+pub mod addressing;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod estimation;
 pub mod flit;
 pub mod header;
+pub mod localization;
 pub mod localnet;
+pub mod membership;
+pub mod neighbor;
 pub mod packet;
 pub mod protocol;
 pub mod serial;
+pub mod service;
+pub mod spanning_tree;
 pub mod system;
 pub mod utils;
 
-use std::{thread::sleep, time::Duration};
+use std::{
+    collections::VecDeque,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 use crate::{
     serial::SerialTrait,
@@ -16,21 +29,56 @@ use crate::{
 use system::SystemInfo;
 use utils::type_alias::{Coordinate, Id};
 
-use rand::prelude::*;
-
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, Result};
 use log::info;
 
+use estimation::{Conflict, ConflictKind, ConflictReport, Estimation, EstimationState};
+use localization::{Evidence, Placement, ShapeEvent};
 use localnet::LocalNetwork;
+use membership::{
+    JoinReply, JoinReplyFragment, JoinRequest, JoinStatus, Member, Membership, LEASE_TIME,
+};
+use neighbor::{LinkEvent, Neighbor, NeighborProbe, NeighborTable, PROBE_INTERVAL};
 use packet::Packet;
 pub use protocol::Protocol;
+use protocol::{ChannelId, Millis, RouteEntry, RoutingMessage, RoutingTablePage, CHANNEL_LENGTH};
+use service::{ServiceId, ServiceTable, MAX_SERVICES, SERVICE_ADVERTISE_INTERVAL};
+use spanning_tree::{Depth, SpanningTree};
 
 use self::{
     header::Header,
     localnet::LocalNetworkLocation,
-    packet::{PacketId, ToId},
+    packet::{ErrorCode, PacketError, PacketId, ToId, Ttl, MAX_PACKET_ID},
 };
 
+/// the number of packets which are kept for resending
+const SENT_PACKETS_LENGTH: usize = 16;
+/// the number of resending a packet when Error packet is received
+const MAX_RESEND: u8 = 3;
+/// interval of sending join request again when the reply is lost
+const JOIN_REQUEST_INTERVAL: Millis = 3_000;
+/// joining fails when the join reply is not accepted in this time after the coordinate is decided
+const JOIN_TIMEOUT: Millis = 60_000;
+/// interval of sending SendParentId while poll is called, so that new nodes can join the tree
+const PARENT_ADVERTISE_INTERVAL: Millis = 2_000;
+/// random delay of replying the coordinate to a new node, so that its neighbors don't collide
+const COORDINATE_REPLY_DELAY: Millis = 100;
+/// interval of renewing the lease again when the reply is lost
+const LEASE_RETRY_INTERVAL: Millis = LEASE_TIME / 8;
+/// the number of join requests which are relayed at the same time
+const JOIN_ROUTES_LENGTH: usize = 16;
+/// the number of packets which wait for forwarding in each channel
+const FORWARD_BUFFER_LENGTH: usize = 8;
+/// the number of flooded packets which are remembered to drop duplicates
+const SEEN_PACKETS_LENGTH: usize = 32;
+/// the number of packets which are received in a poll while estimating the coordinate
+const ESTIMATION_RECEIVE_LENGTH: usize = 16;
+/// the number of link and shape events and conflict reports which are kept until the
+/// application takes them
+const EVENTS_LENGTH: usize = 16;
+/// radius of a broadcast packet which reaches only neighbors
+pub const NEIGHBOR_RADIUS: Ttl = 1;
+
 pub struct NetworkNode<T, S>
 where
     T: Protocol,
@@ -41,11 +89,71 @@ where
     localnet: LocalNetwork,
     global_location: LocalNetworkLocation,
     coordinate: Coordinate,
+    /// whether the coordinate agrees with the connections to other localnets.
+    /// it is false while this localnet is detached, until it is placed again.
+    is_located: bool,
     serial: S,
     protocol: T,
+    spanning_tree: SpanningTree,
+    /// the time to send SendParentId
+    parent_advertise_at: Millis,
+    estimation: Estimation,
+    /// (the time to send, packet) of replies to coordinate requests of new nodes
+    coordinate_replies: Vec<(Millis, Packet)>,
+
+    // for joining global network
+    is_joined: bool,
+    /// only the root has membership table
+    membership: Option<Membership>,
+    /// (mac address of requester, child which relayed the request)
+    join_routes: Vec<(Id, Id)>,
+    join_reply: Option<JoinReply>,
+    /// reply whose fragments are being received
+    receiving_join_reply: Option<JoinReply>,
+    /// the time to send join request again
+    join_request_at: Millis,
+    /// joining fails if the node is not joined until this time
+    join_deadline: Millis,
+    /// conflicts which other nodes reported. only the root keeps them until the application
+    /// takes them.
+    conflict_reports: Vec<ConflictReport>,
+    /// reports of this node or relayed ones, which wait for a route to the root
+    unsent_conflict_reports: Vec<ConflictReport>,
+    /// the time to renew the lease of ip address
+    lease_renew_at: Millis,
+
+    // for anycast
+    /// services which this node provides
+    services: Vec<ServiceId>,
+    /// providers which other nodes advertised
+    service_table: ServiceTable,
+    /// the time to advertise services of this node
+    services_advertise_at: Millis,
+
+    // for neighbors
+    neighbors: NeighborTable,
+    /// events which are not taken by the application yet
+    link_events: Vec<LinkEvent>,
+    /// the time to send the next probe
+    probe_at: Millis,
+    /// connections of this localnet to other localnets, which decided the coordinate last
+    evidence: Vec<Evidence>,
+    /// events which are not taken by the application yet
+    shape_events: Vec<ShapeEvent>,
 
     // for packet
     packet_id: PacketId,
+    /// packets which this node sent and the number of resending them
+    sent_packets: Vec<(Packet, u8)>,
+    /// packets which wait for forwarding, separated by channel
+    /// so that a blocked channel doesn't block the others.
+    forward_buffers: [VecDeque<Packet>; CHANNEL_LENGTH],
+    /// (global source, packet id, the largest ttl) of flooded packets which this node has
+    /// received
+    seen_packets: VecDeque<(Id, PacketId, Ttl)>,
+
+    /// the time when this node is created, which is the origin of time for the protocol
+    started_at: Instant,
 }
 
 impl<T, S> NetworkNode<T, S>
@@ -53,169 +161,184 @@ where
     T: Protocol,
     S: SerialTrait,
 {
-    pub fn new(mut serial: S, mut protocol: T, system_info: &impl SystemInfo) -> Result<Self> {
-        let localnet = LocalNetwork::new(system_info);
-
-        if localnet.is_root() {
-            return Self::new_root(localnet, serial, protocol);
+    /// create a node and wait until it estimates its coordinate and joins global network.
+    /// it fails when the coordinate is not decided in ESTIMATION_TIMEOUT.
+    pub fn new(serial: S, protocol: T, system_info: &impl SystemInfo) -> Result<Self> {
+        let mut node = Self::start(serial, protocol, system_info);
+        loop {
+            if node.poll()? {
+                return Ok(node);
+            }
+            if node.estimation.get_state() == EstimationState::Failed {
+                return Err(anyhow!(
+                    "failed to estimate coordinate or join global network: {:?}",
+                    node.estimation
+                ));
+            }
+            sleep(Duration::from_millis(10));
         }
+    }
 
-        info!("not root node");
+    /// create a node without blocking.
+    /// the node estimates its coordinate and joins global network while poll is called, and
+    /// get_estimation tells what it is waiting for.
+    pub fn start(serial: S, protocol: T, system_info: &impl SystemInfo) -> Self {
+        let localnet = LocalNetwork::new(system_info);
         let mac_address = localnet.get_mac_address();
-        let neighbor_confirmed = Self::loop_until_ready(mac_address, &mut serial)?;
-
-        info!("confirming coordinate...");
-
-        let (coordinate, global_location) =
-            Self::coordinate_and_global_location_from_neighbor_confirmed(
-                &neighbor_confirmed,
-                mac_address,
-            )?;
-
-        // Join global network
-        let ip_address = protocol.join_global_network(mac_address, coordinate)?;
+        let is_root = localnet.is_root();
+        let (estimation, membership, services) = if is_root {
+            info!("root node");
+            (
+                Estimation::confirmed(),
+                Some(Membership::new()),
+                vec![service::ROOT_SERVICE],
+            )
+        } else {
+            info!("not root node");
+            (Estimation::new(0), None, Vec::new())
+        };
 
-        Ok(NetworkNode {
+        // until joining global network, mac address is used as ip address.
+        // the coordinate of the root localnet is the same as its local location, and the others
+        // are decided by estimation.
+        NetworkNode {
             mac_address,
-            ip_address,
-            localnet,
-            global_location,
-            coordinate,
-            serial,
-            protocol,
-
-            packet_id: 1,
-        })
-    }
-    #[inline]
-    fn new_root(
-        // neighbor_confirmed: &Vec<(Id, Id, Coordinate)>,
-        localnet: LocalNetwork,
-        serial: S,
-        protocol: T,
-    ) -> Result<Self> {
-        info!("root node");
-        let neighbor_in_localnet: Vec<Id> = localnet.get_neighbor_ids().into();
-        let mut localnet_id_and_coordinate: Vec<(Id, Coordinate)> = Vec::new();
-        for localnet_id in neighbor_in_localnet.iter() {
-            let location = LocalNetworkLocation::from_id(*localnet_id);
-            let coordinate = location.get_root_coordinate();
-            localnet_id_and_coordinate.push((*localnet_id, coordinate));
-        }
-        info!(
-            "localnet_id_and_coordinate: {:?}",
-            localnet_id_and_coordinate
-        );
-        // same as locallocation
-        let global_location = localnet.get_location();
-        info!("global_location: {:?}", global_location);
-        return Ok(NetworkNode {
-            ip_address: localnet.get_mac_address(),
-            mac_address: localnet.get_mac_address(),
+            ip_address: mac_address,
+            global_location: localnet.get_location(),
             coordinate: localnet.root_coordinate(),
+            is_located: is_root,
             localnet,
-            global_location,
             serial,
             protocol,
+            spanning_tree: SpanningTree::new(mac_address, is_root),
+            parent_advertise_at: 0,
+            estimation,
+            coordinate_replies: Vec::new(),
 
-            packet_id: 0,
-        });
-    }
+            is_joined: false,
+            membership,
+            join_routes: Vec::new(),
+            join_reply: None,
+            receiving_join_reply: None,
+            join_request_at: 0,
+            join_deadline: JOIN_TIMEOUT,
+            conflict_reports: Vec::new(),
+            unsent_conflict_reports: Vec::new(),
+            lease_renew_at: 0,
 
-    #[inline]
-    fn loop_until_ready(mac_address: Id, serial: &mut S) -> Result<Vec<(Id, Id, Coordinate)>> {
-        // (node that send the coordinate(neighbor), node that has the coordinate, coordinate)
-        // if the information is send by confirmed node in non-localnet, first and second node Id is same.
-        let mut neighbor_confirmed: Vec<(Id, Id, Coordinate)> = Vec::new();
-        let mut rng = rand::thread_rng();
+            services,
+            service_table: ServiceTable::new(),
+            services_advertise_at: 0,
 
-        const DELAY_INIT_MAX: u64 = 100;
-        const DELAY_MAX: u64 = 10000;
+            neighbors: NeighborTable::new(),
+            link_events: Vec::new(),
+            probe_at: 0,
+            evidence: Vec::new(),
+            shape_events: Vec::new(),
 
-        let mut delay = rng.gen_range(1..DELAY_INIT_MAX);
+            packet_id: 1,
+            sent_packets: Vec::new(),
+            forward_buffers: Default::default(),
+            seen_packets: VecDeque::new(),
 
-        let mut wait = |reset| {
-            if reset {
-                delay = rng.gen_range(1..DELAY_INIT_MAX);
-                return;
-            }
-            sleep(Duration::from_millis(delay));
-            delay *= 2;
-            if delay > DELAY_MAX {
-                delay = DELAY_MAX
+            started_at: Instant::now(),
+        }
+    }
+
+    /// estimate the coordinate and join global network without blocking.
+    /// it should be called repeatedly until it returns true, which means the node has joined.
+    /// the node fails when it is not joined in JOIN_TIMEOUT after the coordinate is decided.
+    /// after joining, it should be still called, so that new nodes can join the spanning tree
+    /// and the node joins again when the address is lost.
+    pub fn poll(&mut self) -> Result<bool> {
+        if self.estimation.is_estimating() {
+            self.poll_estimation()?;
+        }
+        // even if the estimation has failed, conflicts which made it fail are reported
+        self.send_conflict_reports();
+        if self.estimation.get_state() == EstimationState::Confirmed {
+            self.poll_parent_advertisement()?;
+            if !self.is_joined {
+                self.poll_join()?;
             }
-        };
+        }
+        Ok(self.is_joined)
+    }
 
-        let mut error_wait = |e: Error, serial: &mut S| {
-            info!("error: {:?}", e);
-            serial.flush_all().unwrap();
-            sleep(Duration::from_millis(rand::random::<u64>() % 200 + 300));
-        };
+    /// send SendParentId every PARENT_ADVERTISE_INTERVAL
+    fn poll_parent_advertisement(&mut self) -> Result<()> {
+        let now = self.now();
+        if now >= self.parent_advertise_at {
+            self.advertise_parent_id()?;
+            self.parent_advertise_at = now + PARENT_ADVERTISE_INTERVAL;
+        }
+        Ok(())
+    }
 
-        'outer: loop {
-            while !Self::is_ready(&neighbor_confirmed, mac_address) {
-                // send broadcast packet
-                match Self::request_confirmed_coordinate(serial, mac_address) {
-                    Ok(_) => {
-                        info!("send request confirmed coordinate packet");
-                    }
-                    Err(e) => {
-                        // coliision
-                        error_wait(e, serial);
-                        continue;
-                    }
-                }
+    fn poll_estimation(&mut self) -> Result<()> {
+        self.send_coordinate_replies()?;
+        let now = self.now();
+        if self.estimation.is_request_due(now) {
+            // send broadcast packet
+            let result = Self::request_confirmed_coordinate(&mut self.serial, self.mac_address);
+            if result.is_err() {
+                // collision
+                self.serial.flush_all()?;
+            } else {
+                info!("send request confirmed coordinate packet");
+            }
+            self.estimation.requested(now, result.as_ref().err());
+        }
 
-                // delay
-                sleep(Duration::from_millis(500));
+        // packets which arrived since the last poll
+        for _ in 0..ESTIMATION_RECEIVE_LENGTH {
+            let received_packet = match Packet::receive(&mut self.serial, self.mac_address) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    info!("error: {:?}", e);
+                    self.estimation.set_error(&e);
+                    self.serial.flush_all()?;
+                    break;
+                }
+            };
+            if let Err(e) = self.process_reply_for_request_confirmed_coordinate(received_packet) {
+                info!("error: {:?}", e);
+                self.estimation.set_error(&e);
+            }
+        }
 
-                let mut loop_count = 0;
-                loop {
-                    if loop_count > 100 {
-                        // time out
-                        info!("time out");
-                        continue 'outer;
-                    }
-                    let received_packet = match Packet::receive(serial, mac_address) {
-                        Ok(Some(packet)) => packet,
-                        Ok(None) => {
-                            loop_count += 1;
-                            continue;
-                        }
-                        Err(e) => {
-                            error_wait(e, serial);
-                            loop_count += 1;
-                            continue;
-                        }
-                    };
-                    // wait(true);
-                    println!("received packet: {:?}", received_packet);
-                    match Self::process_reply_for_request_confirmed_coordinate(
-                        serial,
-                        mac_address,
-                        received_packet,
-                        &mut neighbor_confirmed,
-                    ) {
-                        Ok(true) => {
-                            // valid packet
-                            info!("neighbor_confirmed: {:?}", neighbor_confirmed);
-                            break;
-                        }
-                        Ok(false) => {
-                            info!("not valid packet");
-                            loop_count += 1;
-                            continue;
-                        }
-                        Err(e) => {
-                            error_wait(e, serial);
-                            loop_count += 1;
-                            continue;
-                        }
-                    }
+        let neighbor_confirmed = self.estimation.get_neighbor_confirmed();
+        if Self::is_ready(neighbor_confirmed, self.mac_address) {
+            info!("confirming coordinate...");
+            let mut conflicts = Vec::new();
+            let result = Self::coordinate_and_global_location_from_neighbor_confirmed(
+                neighbor_confirmed,
+                self.mac_address,
+                &mut conflicts,
+            );
+            for conflict in self.estimation.add_conflicts(conflicts) {
+                info!("conflict of coordinates: {:?}", conflict);
+                self.queue_conflict_report(ConflictReport {
+                    mac_address: self.mac_address,
+                    conflict,
+                });
+            }
+            match result {
+                Ok((coordinate, global_location)) => {
+                    self.coordinate = coordinate;
+                    self.global_location = global_location;
+                    self.is_located = true;
+                    self.estimation.confirm();
+                    self.join_deadline = now + JOIN_TIMEOUT;
+                    return Ok(());
                 }
+                // wait for more coordinates
+                Err(e) => self.estimation.set_error(&e),
             }
-            return Ok(neighbor_confirmed);
         }
+        self.estimation.check_timeout(now);
+        Ok(())
     }
 
     fn request_confirmed_coordinate(serial: &mut S, node_id: Id) -> Result<()> {
@@ -224,32 +347,30 @@ where
         Ok(())
     }
     fn process_reply_for_request_confirmed_coordinate(
-        serial: &mut S,
-        node_id: Id,
+        &mut self,
         received_packet: Packet,
-        neighbor_confirmed: &mut Vec<(Id, Id, Coordinate)>,
     ) -> Result<bool> {
+        let node_id = self.mac_address;
         match received_packet.get_header() {
             Header::ConfirmCoordinate => {
                 // if received packed source node is in the same localnet of this node,
                 let coordinates = match received_packet.load_confirmed_coordinate_packet(node_id) {
                     Ok(coordinates) => coordinates,
                     Err(e) => {
-                        println!("error in load_confirmed_coordinate_packet: {:?}", e);
+                        info!("error in load_confirmed_coordinate_packet: {:?}", e);
                         return Ok(false);
                     }
                 };
-                let from_id = received_packet.get_global_from();
-
-                for (coordinate_id, coordinate) in coordinates {
-                    let item = (from_id, coordinate_id, coordinate);
-                    if !neighbor_confirmed.contains(&item) {
-                        neighbor_confirmed.push(item);
-                    }
-                }
+                self.estimation
+                    .receive(received_packet.get_global_from(), coordinates);
+                info!(
+                    "neighbor_confirmed: {:?}",
+                    self.estimation.get_neighbor_confirmed()
+                );
                 return Ok(true);
             }
             Header::HRequestConfirmedCoordinate => {
+                let neighbor_confirmed = self.estimation.get_neighbor_confirmed();
                 if neighbor_confirmed.len() != 0 {
                     let packet = match Packet::make_confirm_coordinate_packet(
                         node_id,
@@ -261,11 +382,8 @@ where
                             return Err(anyhow!("failed to make confirm coordinate packet"));
                         }
                     };
-                    println!("send packet: {:?}", packet);
-
-                    sleep(Duration::from_millis(rand::random::<u64>() % 100 + 200));
-
-                    packet.send(serial)?;
+                    // confirmed neighbors reply sooner
+                    self.queue_coordinate_reply(packet, COORDINATE_REPLY_DELAY * 2);
                 }
                 Ok(true)
             }
@@ -273,58 +391,108 @@ where
         }
     }
 
+    /// decide the coordinate by the majority of claims of neighbors.
+    /// claims which contradict each other are resolved by find_conflicts, and the conflicts which
+    /// are not resolved are added to conflicts.
     fn coordinate_and_global_location_from_neighbor_confirmed(
-        neighbor_confirmed: &Vec<(Id, Id, Coordinate)>,
+        neighbor_confirmed: &[(Id, Id, Coordinate)],
         this_id: Id,
+        conflicts: &mut Vec<Conflict>,
     ) -> Result<(Coordinate, LocalNetworkLocation)> {
+        let (claims, unresolved) = estimation::find_conflicts(neighbor_confirmed);
+        conflicts.extend(unresolved);
+
+        // (candidate, the number of votes)
+        let mut votes: Vec<((Coordinate, LocalNetworkLocation), u32)> = Vec::new();
+        let mut vote = |candidate| match votes.iter_mut().find(|(known, _)| *known == candidate) {
+            Some((_, count)) => *count += 1,
+            None => votes.push((candidate, 1)),
+        };
+
         // check if other node in localnet is already confirmed
-        let same_localnet: Vec<&(Id, Id, Coordinate)> = neighbor_confirmed
+        let mut same_localnet: Vec<&(Id, Id, Coordinate)> = claims
             .iter()
             .filter(|(_, id, _)| is_same_localnet(this_id, *id))
             .collect();
-        if same_localnet.len() > 0 {
-            assert_eq!(same_localnet.len(), 4);
-            return Self::get_coordinate_from_confirmed_localnet_node(&same_localnet, this_id);
+        same_localnet.sort_by_key(|(_, id, _)| *id);
+        same_localnet.dedup_by_key(|(_, id, _)| *id);
+        if same_localnet.len() == 4 {
+            if let Ok(candidate) =
+                Self::get_coordinate_from_confirmed_localnet_node(&same_localnet, this_id)
+            {
+                vote(candidate);
+            }
+        }
+
+        // each pair of neighbors outside the localnet which are next to each other
+        for (i, claim) in claims.iter().enumerate() {
+            for claim_cmp in claims[i + 1..].iter() {
+                if let Some(candidate) =
+                    Self::coordinate_from_distance_1_claims(*claim, *claim_cmp, this_id)
+                {
+                    vote(candidate);
+                }
+            }
         }
 
-        let (from_id, id, coordinate, from_id_cmp, id_cmp, coordinate_cmp) =
-            Self::find_distance_1_neighbor(neighbor_confirmed)
-                .expect("failed to find distance 1 neighbor");
+        votes.sort_by(|(_, count), (_, count_cmp)| count_cmp.cmp(count));
+        match votes[..] {
+            [] => Err(anyhow!(
+                "no consistent claims decide the coordinate: neighbor_confirmed {:?}",
+                neighbor_confirmed
+            )),
+            [(candidate, count), (candidate_cmp, count_cmp), ..] if count == count_cmp => {
+                conflicts.push(Conflict::new(
+                    ConflictKind::Tie,
+                    (this_id, candidate.0),
+                    (this_id, candidate_cmp.0),
+                ));
+                Err(anyhow!(
+                    "claims are tied: {:?} and {:?} by {} votes",
+                    candidate,
+                    candidate_cmp,
+                    count
+                ))
+            }
+            [(candidate, _), ..] => Ok(candidate),
+        }
+    }
+
+    /// coordinate of this node from two claims whose coordinates are next to each other.
+    /// one of them is sent directly by the neighbor outside the localnet, and the other one is
+    /// told by a node in the localnet.
+    fn coordinate_from_distance_1_claims(
+        claim: (Id, Id, Coordinate),
+        claim_cmp: (Id, Id, Coordinate),
+        this_id: Id,
+    ) -> Option<(Coordinate, LocalNetworkLocation)> {
+        let (from_id, id, coordinate) = claim;
+        let (from_id_cmp, id_cmp, coordinate_cmp) = claim_cmp;
+        if calculate_l0_distance(coordinate, coordinate_cmp) != 1 {
+            return None;
+        }
 
         // node that id and id_cmp is not in the same localnet. and node that id is directly
         // connected to this node (but id_cmp is not).
-        let (id, coordinate, id_cmp, coordinate_cmp) = if is_same_localnet(this_id, from_id) {
+        let (id, coordinate, id_cmp, coordinate_cmp) = match (
+            is_same_localnet(this_id, from_id),
+            is_same_localnet(this_id, from_id_cmp),
+        ) {
             // swap
-            (id_cmp, coordinate_cmp, id, coordinate)
-        } else if is_same_localnet(this_id, from_id_cmp) {
-            (id, coordinate, id_cmp, coordinate_cmp)
-        } else {
-            return Err(anyhow!(
-                "invalid this_id must be the same node as from_id or from_id_cmp: neighbor_confirmed {:?}, (id, coordinate, id_cmp, coordinate_cmp) = ({}, {:?}, {}, {:?})",
-                neighbor_confirmed
-                , id, coordinate, id_cmp, coordinate_cmp
-            ));
+            (true, false) => (id_cmp, coordinate_cmp, id, coordinate),
+            (false, true) => (id, coordinate, id_cmp, coordinate_cmp),
+            _ => return None,
         };
-
         if is_same_localnet(this_id, id) || is_same_localnet(this_id, id_cmp) {
-            return Err(anyhow!(
-                "invalid this_id cannot be the same node as id and id_cmp: neighbor_confirmed {:?}, (id, coordinate, id_cmp, coordinate_cmp) = ({}, {:?}, {}, {:?})",
-                neighbor_confirmed
-                , id, coordinate, id_cmp, coordinate_cmp
-            ));
+            return None;
         }
 
-        let location = LocalNetworkLocation::from_id(id);
-        let location_cmp = LocalNetworkLocation::from_id(id_cmp);
-
-        return Ok(
-            Self::get_global_coordinate_and_global_location_from_local_location(
-                location,
-                coordinate,
-                location_cmp,
-                coordinate_cmp,
-            ),
-        );
+        Self::get_global_coordinate_and_global_location_from_local_location(
+            LocalNetworkLocation::from_id(id),
+            coordinate,
+            LocalNetworkLocation::from_id(id_cmp),
+            coordinate_cmp,
+        )
     }
 
     fn get_coordinate_from_confirmed_localnet_node(
@@ -362,19 +530,21 @@ where
     /// not cmp node is directly connected to this node but not in localnet.
     /// cmp node is not directly connected to this node and not in localnet.
     /// not cmp node and cmp node is directly connected.
-    /// return this coordinate and this global location.
+    /// return this coordinate and this global location, or None if the nodes are not next to
+    /// each other.
     fn get_global_coordinate_and_global_location_from_local_location(
         local_location: LocalNetworkLocation,
         coordinate: Coordinate,
         local_location_cmp: LocalNetworkLocation,
         coordinate_cmp: Coordinate,
-    ) -> (Coordinate, LocalNetworkLocation) {
+    ) -> Option<(Coordinate, LocalNetworkLocation)> {
         let is_clockwise_location = if local_location.rotate_clockwise() == local_location_cmp {
             true
         } else if local_location.rotate_counterclockwise() == local_location_cmp {
             false
         } else {
-            panic!("invalid local_location and local_location_cmp: local_location = {:?}, local_location_cmp = {:?}, local_location.rotate_clockwise = {:?}, local_location.rotate_counterclockwise = {:?}", local_location, local_location_cmp, local_location.rotate_clockwise(), local_location.rotate_counterclockwise());
+            info!("invalid local_location and local_location_cmp: local_location = {:?}, local_location_cmp = {:?}", local_location, local_location_cmp);
+            return None;
         };
         const X: bool = true;
         const Y: bool = false;
@@ -383,7 +553,7 @@ where
         } else if coordinate.1 != coordinate_cmp.1 {
             Y
         } else {
-            unreachable!();
+            return None;
         };
         let is_small_coordinate =
             if coordinate.0 < coordinate_cmp.0 || coordinate.1 < coordinate_cmp.1 {
@@ -391,20 +561,22 @@ where
             } else {
                 false
             };
-        match (
-            is_clockwise_location,
-            different_coordinate,
-            is_small_coordinate,
-        ) {
-            (true, X, true) => (add_y(coordinate, 1), LocalNetworkLocation::DownLeft),
-            (true, X, false) => (add_y(coordinate, -1), LocalNetworkLocation::UpRight),
-            (true, Y, true) => (add_x(coordinate, -1), LocalNetworkLocation::DownRight),
-            (true, Y, false) => (add_x(coordinate, 1), LocalNetworkLocation::UpLeft),
-            (false, X, true) => (add_y(coordinate, -1), LocalNetworkLocation::UpLeft),
-            (false, X, false) => (add_y(coordinate, 1), LocalNetworkLocation::DownRight),
-            (false, Y, true) => (add_x(coordinate, 1), LocalNetworkLocation::DownLeft),
-            (false, Y, false) => (add_x(coordinate, -1), LocalNetworkLocation::UpRight),
-        }
+        Some(
+            match (
+                is_clockwise_location,
+                different_coordinate,
+                is_small_coordinate,
+            ) {
+                (true, X, true) => (add_y(coordinate, 1), LocalNetworkLocation::DownLeft),
+                (true, X, false) => (add_y(coordinate, -1), LocalNetworkLocation::UpRight),
+                (true, Y, true) => (add_x(coordinate, -1), LocalNetworkLocation::DownRight),
+                (true, Y, false) => (add_x(coordinate, 1), LocalNetworkLocation::UpLeft),
+                (false, X, true) => (add_y(coordinate, -1), LocalNetworkLocation::UpLeft),
+                (false, X, false) => (add_y(coordinate, 1), LocalNetworkLocation::DownRight),
+                (false, Y, true) => (add_x(coordinate, 1), LocalNetworkLocation::DownLeft),
+                (false, Y, false) => (add_x(coordinate, -1), LocalNetworkLocation::UpRight),
+            },
+        )
     }
 
     /// find distance 1 neighbor from neighbor_confirmed
@@ -426,12 +598,313 @@ where
         }
         None
     }
-    pub fn join_global_network(&mut self) {
-        // todo:
-        ()
+    /// join global network by request/reply exchange with the root.
+    /// the request goes up along the spanning tree, and the root assigns ip address.
+    /// this function blocks until the reply is received or JOIN_TIMEOUT has passed.
+    /// see poll for the non-blocking way.
+    pub fn join_global_network(&mut self) -> Result<()> {
+        while !self.is_joined {
+            self.poll_join()?;
+            if self.estimation.get_state() == EstimationState::Failed {
+                return Err(anyhow!(
+                    "failed to join global network: {:?}",
+                    self.estimation
+                ));
+            }
+            sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    /// send the join request again every JOIN_REQUEST_INTERVAL until the reply is received
+    fn poll_join(&mut self) -> Result<()> {
+        let request = JoinRequest::new(self.mac_address, self.coordinate);
+        let now = self.now();
+        if let Some(membership) = self.membership.as_mut() {
+            // root
+            let reply = membership.join(&mut self.protocol, &request, now);
+            return self.process_join_reply(reply);
+        }
+
+        if let Some(reply) = self.join_reply.take() {
+            return self.process_join_reply(reply);
+        }
+        if now > self.join_deadline {
+            self.estimation
+                .fail(&anyhow!("not joined in {}ms", JOIN_TIMEOUT));
+            return Ok(());
+        }
+        if let Some(parent) = self.spanning_tree.get_parent() {
+            if now >= self.join_request_at {
+                info!("send join request to {}", parent);
+                if let Err(e) = request
+                    .to_packet(parent)
+                    .and_then(|packet| packet.send(&mut self.serial))
+                {
+                    info!("failed to send join request: {:?}", e);
+                }
+                self.join_request_at = now + JOIN_REQUEST_INTERVAL;
+            }
+        }
+
+        // neighbors may wait for coordinate of this node, and get_packet replies to them
+        match self.get_packet() {
+            Ok(_) => {}
+            Err(e) => {
+                info!("error in join_global_network: {:?}", e);
+                self.flush_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the root keeps the report, and the others keep it until it is sent to the root.
+    /// only the latest EVENTS_LENGTH reports are kept.
+    fn queue_conflict_report(&mut self, report: ConflictReport) {
+        let reports = if self.membership.is_some() {
+            &mut self.conflict_reports
+        } else {
+            &mut self.unsent_conflict_reports
+        };
+        if reports.len() >= EVENTS_LENGTH {
+            reports.remove(0);
+        }
+        reports.push(report);
+    }
+
+    /// send conflict reports toward the root once a route exists. it is the parent in the
+    /// spanning tree, or a confirmed neighbor outside the localnet, which relays it to its parent.
+    /// a lost report is not sent again, because it is only for diagnosis.
+    fn send_conflict_reports(&mut self) {
+        if self.unsent_conflict_reports.is_empty() {
+            return;
+        }
+        let next = self.spanning_tree.get_parent().or_else(|| {
+            self.estimation
+                .get_neighbor_confirmed()
+                .iter()
+                .map(|(from_id, _, _)| *from_id)
+                .find(|from_id| !is_same_localnet(self.mac_address, *from_id))
+        });
+        let next = match next {
+            Some(next) => next,
+            None => return,
+        };
+        for report in std::mem::take(&mut self.unsent_conflict_reports) {
+            if let Err(e) = report.to_packet(next).and_then(|mut packet| {
+                packet.change_from_and_to(self.mac_address, ToId::Unicast(next));
+                packet.send(&mut self.serial)
+            }) {
+                info!("failed to report conflict: {:?}", e);
+            }
+        }
+    }
+
+    /// relay a conflict report to the root, and the root keeps it
+    fn process_conflict_report(&mut self, packet: &Packet) -> Result<()> {
+        if packet.get_to() != ToId::Unicast(self.mac_address) {
+            return Ok(());
+        }
+        let report = ConflictReport::from_packet(packet)?;
+        if self.membership.is_some() {
+            info!("conflict is reported: {:?}", report);
+        }
+        self.queue_conflict_report(report);
+        self.send_conflict_reports();
+        Ok(())
+    }
+
+    /// conflicts of coordinates which nodes reported since the last call.
+    /// only the root receives them, and only the latest EVENTS_LENGTH reports are kept.
+    pub fn take_conflict_reports(&mut self) -> Vec<ConflictReport> {
+        std::mem::take(&mut self.conflict_reports)
+    }
+
+    fn process_join_reply(&mut self, reply: JoinReply) -> Result<()> {
+        if reply.status != JoinStatus::Accepted {
+            return Err(anyhow!("join request is not accepted: {:?}", reply));
+        }
+        self.protocol
+            .set_routing_parameters(&reply.routing_parameters)?;
+        self.ip_address = reply.ip_address;
+        self.is_joined = true;
+        // the lease is renewed at the half of its time, so that a lost reply can be retried
+        self.lease_renew_at = self.now() + reply.lease_time / 2;
+        info!("joined global network: ip_address = {}", self.ip_address);
+        Ok(())
+    }
+
+    /// renew the lease of ip address, and the root reclaims addresses of expired leases.
+    /// it should be called periodically after joining global network.
+    /// if the renewal has failed, the node joins again as poll does.
+    pub fn update_lease(&mut self) -> Result<()> {
+        if !self.is_joined {
+            self.poll()?;
+            return Ok(());
+        }
+        let now = self.now();
+        if now >= self.lease_renew_at {
+            let request = JoinRequest::new(self.mac_address, self.coordinate);
+            if let Some(membership) = self.membership.as_mut() {
+                let reply = membership.join(&mut self.protocol, &request, now);
+                self.process_renewal_reply(reply);
+            } else if let Some(parent) = self.spanning_tree.get_parent() {
+                request.to_packet(parent)?.send(&mut self.serial)?;
+                // until the reply comes
+                self.lease_renew_at = now + LEASE_RETRY_INTERVAL;
+            }
+        }
+        if let Some(membership) = self.membership.as_mut() {
+            membership.expire(&mut self.protocol, now);
+        }
+        Ok(())
+    }
+
+    /// the address may be lost if the lease has expired and another node got the coordinate,
+    /// so the node goes back to joining, and the join request is sent by the next poll.
+    fn process_renewal_reply(&mut self, reply: JoinReply) {
+        if let Err(e) = self.process_join_reply(reply) {
+            info!("failed to renew lease: {:?}", e);
+            self.is_joined = false;
+            self.join_request_at = 0;
+            self.join_deadline = self.now() + JOIN_TIMEOUT;
+        }
+    }
+
+    /// relay join request to the root and join reply to the requester
+    fn process_join_packet(&mut self, packet: &Packet) -> Result<()> {
+        if packet.get_to() != ToId::Unicast(self.mac_address) {
+            return Ok(());
+        }
+        let from = packet.get_from();
+        match packet.get_header() {
+            Header::RequestJoinNetwork => {
+                let now = self.now();
+                if let Some(membership) = self.membership.as_mut() {
+                    let request = JoinRequest::from_packet(packet)?;
+                    let reply = membership.join(&mut self.protocol, &request, now);
+                    for packet in reply.to_packets(self.mac_address, from)? {
+                        packet.send(&mut self.serial)?;
+                    }
+                    return Ok(());
+                }
+                let parent = match self.spanning_tree.get_parent() {
+                    Some(parent) => parent,
+                    None => return Ok(()),
+                };
+                let requester = packet.get_global_from();
+                self.join_routes
+                    .retain(|(mac_address, _)| *mac_address != requester);
+                if self.join_routes.len() >= JOIN_ROUTES_LENGTH {
+                    self.join_routes.remove(0);
+                }
+                self.join_routes.push((requester, from));
+
+                let mut packet = packet.clone();
+                packet.change_from_and_to(self.mac_address, ToId::Unicast(parent));
+                packet.send(&mut self.serial)?;
+            }
+            Header::ReplyJoinNetwork => {
+                let fragment = JoinReplyFragment::from_packet(packet)?;
+                let mac_address = fragment.reply.mac_address;
+                if mac_address == self.mac_address {
+                    let reply = match fragment.assemble(&mut self.receiving_join_reply) {
+                        Some(reply) => reply,
+                        None => return Ok(()),
+                    };
+                    if self.is_joined {
+                        self.process_renewal_reply(reply);
+                    } else {
+                        self.join_reply = Some(reply);
+                    }
+                    return Ok(());
+                }
+                let index = match self
+                    .join_routes
+                    .iter()
+                    .position(|(requester, _)| *requester == mac_address)
+                {
+                    Some(index) => index,
+                    None => return Ok(()),
+                };
+                // the route is kept until the last fragment is relayed
+                let (_, child) = match fragment.is_last() {
+                    true => self.join_routes.remove(index),
+                    false => self.join_routes[index],
+                };
+                let mut packet = packet.clone();
+                packet.change_from_and_to(self.mac_address, ToId::Unicast(child));
+                packet.send(&mut self.serial)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// handle packets which are exchanged only between neighbors to make local network, so that
+    /// the application doesn't have to.
+    /// return true if the packet is handled.
+    fn process_control_packet(&mut self, packet: &Packet) -> Result<bool> {
+        match packet.get_header() {
+            Header::HRequestConfirmedCoordinate => {
+                // a new node estimates its coordinate. the stale coordinate of a detached
+                // localnet is not told.
+                if self.is_located {
+                    self.reply_confirmed_coordinate(packet.get_global_from())?;
+                }
+                Ok(true)
+            }
+            // replies to other new nodes
+            Header::ConfirmCoordinate => Ok(true),
+            // connections are tracked by update_neighbors. it is not replied, otherwise two
+            // nodes would reply to each other forever.
+            Header::HCheckConnection => Ok(true),
+            // acks of flits which arrived after the sender stopped waiting for them.
+            // GeneralAck is for applications, so it is not handled here.
+            Header::HAck => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn reply_confirmed_coordinate(&mut self, destination: Id) -> Result<()> {
+        let packet = match Packet::make_confirm_coordinate_packet_by_confirmed_node(
+            self.mac_address,
+            destination,
+            self.coordinate,
+            self.global_location,
+        ) {
+            Some(packet) => packet,
+            None => return Err(anyhow!("failed to make confirm coordinate packet")),
+        };
+        self.queue_coordinate_reply(packet, 0);
+        Ok(())
+    }
+
+    /// send the reply after a random delay from `delay` to `delay + COORDINATE_REPLY_DELAY`,
+    /// because neighbors receive the same broadcast request and reply at the same time.
+    /// a reply which is not sent yet is replaced by the newer one to the same node.
+    fn queue_coordinate_reply(&mut self, packet: Packet, delay: Millis) {
+        let send_at = self.now() + delay + rand::random::<Millis>() % COORDINATE_REPLY_DELAY;
+        self.coordinate_replies
+            .retain(|(_, queued)| queued.get_global_to() != packet.get_global_to());
+        self.coordinate_replies.push((send_at, packet));
+    }
+
+    /// send replies to coordinate requests whose delay has passed
+    fn send_coordinate_replies(&mut self) -> Result<()> {
+        let now = self.now();
+        let (due, waiting) = std::mem::take(&mut self.coordinate_replies)
+            .into_iter()
+            .partition(|(send_at, _)| *send_at <= now);
+        self.coordinate_replies = waiting;
+        for (_, packet) in due {
+            packet.send(&mut self.serial)?;
+        }
+        Ok(())
     }
 
     /// check connection with other nodes that is not in the same local network.
+    /// it checks only one reply. neighbors after joining are tracked by update_neighbors.
     pub fn check_connection(serial: &mut S, node_id: Id) -> Result<bool> {
         info!("making check connection packet");
         let packet = Packet::make_check_connection_packet(node_id);
@@ -492,19 +965,56 @@ where
         messages: Vec<u8>,
     ) -> Result<Packet> {
         let from = self.ip_address;
-        let to = self
-            .protocol
-            .get_next_node(self.ip_address, globalto.to_id());
-        let packet = Packet::new(
+        let (to, channel) = match globalto {
+            // a packet to a group is flooded from neighbors
+            ToId::Broadcast
+            | ToId::Localnet(_)
+            | ToId::Row(_)
+            | ToId::Column(_)
+            | ToId::Rectangle(_, _) => (ToId::Broadcast, 0),
+            ToId::Unicast(global_destination) => self.get_first_hop(global_destination)?,
+            ToId::Anycast(service) => self.get_first_hop(self.get_nearest_provider(service)?)?,
+        };
+        let mut packet = Packet::new(
             self.packet_id,
             header,
             globalfrom,
             globalto,
             from,
-            ToId::from_id(to),
+            to,
             messages,
-        );
-        self.packet_id += 1;
+        )?;
+        packet.set_channel(channel);
+        // packet id is carried by 6 bits
+        self.packet_id = (self.packet_id + 1) & MAX_PACKET_ID;
+        Ok(packet)
+    }
+
+    /// neighbor and channel of a packet which this node sends to the destination
+    fn get_first_hop(&self, global_destination: Id) -> Result<(ToId, ChannelId)> {
+        Ok((
+            ToId::from_id(
+                self.protocol
+                    .get_next_node(self.ip_address, global_destination)?,
+            ),
+            self.protocol
+                .get_channel(self.ip_address, global_destination),
+        ))
+    }
+
+    /// make a broadcast packet which reaches nodes within the radius (the number of hops).
+    /// NEIGHBOR_RADIUS reaches only neighbors, and packets by make_packet reach all nodes.
+    pub fn make_radius_broadcast_packet(
+        &mut self,
+        header: Header,
+        messages: Vec<u8>,
+        radius: Ttl,
+    ) -> Result<Packet> {
+        if radius == 0 {
+            return Err(anyhow!("radius of broadcast must be positive"));
+        }
+        let mut packet = self.make_packet(header, self.ip_address, ToId::Broadcast, messages)?;
+        packet.set_ttl(radius);
         Ok(packet)
     }
 
@@ -517,6 +1027,7 @@ where
 
     /// get packet from serial
     pub fn get_packet(&mut self) -> Result<Option<Packet>> {
+        self.send_coordinate_replies()?;
         // whether there is data in buffer.
         let packet = match Packet::receive(&mut self.serial, self.ip_address) {
             Ok(Some(packet)) => packet,
@@ -524,54 +1035,632 @@ where
                 // no data in buffer
                 return Ok(None);
             }
-            Err(_) => {
-                info!("receive error in get_packet");
+            Err(e) => {
+                info!("receive error in get_packet: {:?}", e);
                 self.flush_all()?;
+                if let Some(error) = e.downcast_ref::<PacketError>() {
+                    self.report_error(*error)?;
+                }
                 return self.get_packet();
             }
         };
+        if self.process_control_packet(&packet)? {
+            return Ok(None);
+        }
+        // packets of spanning tree are exchanged only between neighbors
+        if SpanningTree::is_spanning_tree_header(packet.get_header()) {
+            self.process_spanning_tree_packet(&packet)?;
+            return Ok(None);
+        }
+        if matches!(
+            packet.get_header(),
+            Header::RequestJoinNetwork | Header::ReplyJoinNetwork
+        ) {
+            self.process_join_packet(&packet)?;
+            return Ok(None);
+        }
+        if packet.get_header() == Header::ProbeNeighbor {
+            self.process_probe_packet(&packet)?;
+            return Ok(None);
+        }
+        if packet.get_header() == Header::ReportConflict {
+            self.process_conflict_report(&packet)?;
+            return Ok(None);
+        }
+        // packets of routing protocol are exchanged only between neighbors
+        if packet.get_header() == Header::Routing {
+            self.process_routing_packet(&packet)?;
+            return Ok(None);
+        }
+
         // whether it is packet that was sent to this node
         // if it is not, look routing table and send it to next node or not.
         let to = packet.get_global_to();
-        let from = packet.get_from();
         match to {
-            ToId::Broadcast => {}
+            ToId::Broadcast
+            | ToId::Localnet(_)
+            | ToId::Row(_)
+            | ToId::Column(_)
+            | ToId::Rectangle(_, _) => {
+                if !self.flood(&packet)? {
+                    return Ok(None);
+                }
+            }
             ToId::Unicast(global_destination_id) => {
                 // unicast
                 // send to specific node
                 if global_destination_id != self.ip_address {
-                    // send to next node
-                    if self
-                        .protocol
-                        .is_in_route(self.ip_address, from, global_destination_id)
-                    {
-                        // send to next node
-                        // todo: this is not efficient way because you just need to change head
-                        // flit.
-                        let mut packet = packet;
-                        packet.change_from_and_to(
-                            self.ip_address,
-                            ToId::from_id(
-                                self.protocol
-                                    .get_next_node(self.ip_address, global_destination_id),
-                            ),
-                        );
-                        packet.send(&mut self.serial)?;
+                    if !self.is_joined {
+                        // this node doesn't have routing information yet
+                        return Ok(None);
+                    }
+                    // the previous node chose this node as the next node
+                    if packet.get_to() == ToId::Unicast(self.ip_address) {
+                        self.relay(packet, Ok(global_destination_id))?;
+                    }
+                    return Ok(None);
+                }
+            }
+            ToId::Anycast(service) => {
+                if !self.services.contains(&service) {
+                    if self.is_joined && packet.get_to() == ToId::Unicast(self.ip_address) {
+                        // the nearest provider is chosen again in each node, so the packet goes
+                        // to a provider which is closer than the one the source chose.
+                        let provider = self.get_nearest_provider(service);
+                        self.relay(packet, provider)?;
                     }
-
                     return Ok(None);
                 }
             }
         }
         // it is my packet
+        if packet.get_header() == Header::Error && self.process_error_packet(&packet)? {
+            // the packet has been resent, so the error is already handled.
+            return Ok(None);
+        }
+        if packet.get_header() == Header::RequestRoutingTable {
+            self.reply_routing_table(&packet)?;
+            return Ok(None);
+        }
+        if packet.get_header() == Header::AdvertiseService {
+            self.process_service_advertisement(&packet)?;
+            return Ok(None);
+        }
         Ok(Some(packet))
     }
+
+    /// send the packet which is routed through this node to the next node toward the global
+    /// destination. the source is told if the packet is dropped.
+    fn relay(&mut self, mut packet: Packet, global_destination: Result<Id>) -> Result<()> {
+        // drop the packet which may be in a routing loop
+        if !packet.decrement_ttl() {
+            info!("ttl of packet to {:?} expired", packet.get_global_to());
+            if packet.get_header() != Header::Error {
+                self.report_error(PacketError::new(
+                    ErrorCode::TtlExpired,
+                    packet.get_packet_id(),
+                    packet.get_global_from(),
+                ))?;
+            }
+            return Ok(());
+        }
+        // send to next node
+        // todo: this is not efficient way because you just need to change head
+        // flit.
+        let next_hop = global_destination.and_then(|global_destination| {
+            self.protocol.get_next_hop(
+                self.ip_address,
+                packet.get_from(),
+                global_destination,
+                packet.get_channel(),
+            )
+        });
+        let (next_node, channel) = match next_hop {
+            Ok(next_hop) => next_hop,
+            Err(e) => {
+                info!("no route to {:?}: {:?}", packet.get_global_to(), e);
+                if packet.get_header() != Header::Error {
+                    self.report_error(PacketError::new(
+                        ErrorCode::Unreachable,
+                        packet.get_packet_id(),
+                        packet.get_global_from(),
+                    ))?;
+                }
+                return Ok(());
+            }
+        };
+        packet.change_from_and_to(self.ip_address, ToId::from_id(next_node));
+        packet.set_channel(channel);
+        self.forward(packet)
+    }
+
+    /// relay the packet to broadcast or a group to neighbors once, because members of the group
+    /// may be reached only through nodes which are not members. ttl limits the radius.
+    /// return true if the packet should be delivered to this node.
+    fn flood(&mut self, packet: &Packet) -> Result<bool> {
+        if packet.get_global_from() == self.ip_address {
+            return Ok(false);
+        }
+        let source = packet.get_global_from();
+        let packet_id = packet.get_packet_id();
+        let ttl = packet.get_ttl();
+        let seen = self
+            .seen_packets
+            .iter_mut()
+            .find(|(seen_source, seen_id, _)| *seen_source == source && *seen_id == packet_id);
+        let is_first = match seen {
+            // it has been relayed as far as this copy can reach, and delivered already
+            Some((_, _, seen_ttl)) if *seen_ttl >= ttl => return Ok(false),
+            // this copy came by a shorter path, so it can be relayed farther
+            Some((_, _, seen_ttl)) => {
+                *seen_ttl = ttl;
+                false
+            }
+            None => {
+                if self.seen_packets.len() >= SEEN_PACKETS_LENGTH {
+                    self.seen_packets.pop_front();
+                }
+                self.seen_packets.push_back((source, packet_id, ttl));
+                true
+            }
+        };
+
+        let mut relayed = packet.clone();
+        if relayed.decrement_ttl() {
+            relayed.change_from_and_to(self.ip_address, ToId::Broadcast);
+            self.forward(relayed)?;
+        }
+        let to = packet.get_global_to();
+        Ok(is_first && (to == ToId::Broadcast || to.contains(self.coordinate)))
+    }
+
+    /// put the packet into the buffer of its channel, and send buffered packets.
+    /// if the buffer is full, the packet is dropped and its source is told congestion.
+    fn forward(&mut self, packet: Packet) -> Result<()> {
+        let buffer = &mut self.forward_buffers[packet.get_channel() as usize];
+        if buffer.len() >= FORWARD_BUFFER_LENGTH {
+            info!("channel {} is full", packet.get_channel());
+            // a flooded packet reaches nodes by other relays, and resending it is dropped as a
+            // duplicate, so congestion is not reported.
+            if packet.get_header() != Header::Error
+                && matches!(packet.get_global_to(), ToId::Unicast(_) | ToId::Anycast(_))
+            {
+                self.report_error(PacketError::new(
+                    ErrorCode::Congestion,
+                    packet.get_packet_id(),
+                    packet.get_global_from(),
+                ))?;
+            }
+            return Ok(());
+        }
+        buffer.push_back(packet);
+        self.flush_forward_buffers();
+        Ok(())
+    }
+
+    /// send packets which wait for forwarding.
+    /// higher channels are sent first, because packets in them are closer to leaving the cycle of
+    /// dependencies. a packet which cannot be sent stays in the buffer, and the channel waits.
+    fn flush_forward_buffers(&mut self) {
+        for channel in (0..CHANNEL_LENGTH).rev() {
+            while let Some(packet) = self.forward_buffers[channel].front() {
+                if let Err(e) = packet.send(&mut self.serial) {
+                    info!("failed to forward packet in channel {}: {:?}", channel, e);
+                    break;
+                }
+                self.forward_buffers[channel].pop_front();
+            }
+        }
+    }
+
+    fn process_spanning_tree_packet(&mut self, packet: &Packet) -> Result<()> {
+        let packets = self.spanning_tree.process_packet(packet)?;
+        for packet in packets {
+            packet.send(&mut self.serial)?;
+        }
+        Ok(())
+    }
+
+    /// send SendParentId to neighbors if this node is in the spanning tree.
+    /// it should be called periodically, so that new nodes can join the tree.
+    pub fn advertise_parent_id(&mut self) -> Result<()> {
+        if let Some(packet) = self.spanning_tree.make_advertisement() {
+            packet.send(&mut self.serial)?;
+        }
+        Ok(())
+    }
+
+    /// send a probe to neighbors every PROBE_INTERVAL, and remove neighbors which are not heard.
+    /// connections to neighbors which are up or down are told to the protocol by
+    /// add_connection/remove_connection, and the events are kept for take_link_events.
+    /// it should be called periodically after joining global network.
+    pub fn update_neighbors(&mut self) -> Result<()> {
+        if !self.is_joined {
+            return Ok(());
+        }
+        let now = self.now();
+        if now >= self.probe_at {
+            let probe = NeighborProbe {
+                mac_address: self.mac_address,
+                is_located: self.is_located,
+                coordinate: self.coordinate,
+                outside_neighbors: self
+                    .neighbors
+                    .get_neighbors()
+                    .iter()
+                    .filter(|neighbor| !neighbor.is_localnet && neighbor.is_located)
+                    .map(|neighbor| neighbor.coordinate)
+                    .collect(),
+            };
+            probe.to_packet(self.ip_address)?.send(&mut self.serial)?;
+            self.neighbors.tick();
+            self.probe_at = now + PROBE_INTERVAL;
+        }
+        let events = self.neighbors.expire(now);
+        self.process_link_events(events);
+        self.relocate();
+        Ok(())
+    }
+
+    fn process_probe_packet(&mut self, packet: &Packet) -> Result<()> {
+        if !self.is_joined {
+            return Ok(());
+        }
+        let probe = NeighborProbe::from_packet(packet)?;
+        let is_localnet = is_same_localnet(self.mac_address, probe.mac_address);
+        let now = self.now();
+        if let Some(event) = self
+            .neighbors
+            .hear(packet.get_from(), probe, is_localnet, now)
+        {
+            self.process_link_events(vec![event]);
+        }
+        // a node in this localnet may tell new connections to other localnets
+        self.relocate();
+        Ok(())
+    }
+
+    /// estimate the coordinate again when the connections of this localnet to other localnets
+    /// change, e.g. when this localnet is moved, or when a localnet is added or removed next to
+    /// it. only this localnet is estimated, and the root localnet never moves.
+    /// the new coordinate is told to the root by renewing the lease, and ShapeEvent is kept for
+    /// take_shape_events.
+    fn relocate(&mut self) {
+        if self.localnet.is_root() {
+            return;
+        }
+        let local_location = self.localnet.get_location();
+        let mut evidence: Vec<Evidence> = Vec::new();
+        for neighbor in self.neighbors.get_neighbors() {
+            if neighbor.is_localnet {
+                let location = LocalNetworkLocation::from_id(neighbor.mac_address);
+                evidence.extend(
+                    neighbor
+                        .outside_neighbors
+                        .iter()
+                        .map(|coordinate| (location, *coordinate)),
+                );
+            } else if neighbor.is_located {
+                evidence.push((local_location, neighbor.coordinate));
+            }
+        }
+        evidence.sort_by_key(|(location, coordinate)| (*location as u8, *coordinate));
+        evidence.dedup();
+        if evidence == self.evidence {
+            return;
+        }
+        self.evidence = evidence;
+
+        let placements = localization::estimate(&self.evidence);
+        let current = Placement::new(local_location, self.coordinate);
+        if self.is_located && placements.contains(&current) {
+            return;
+        }
+        let event = match placements[..] {
+            [placement] => {
+                let from = self.coordinate;
+                self.coordinate = placement.get_coordinate(local_location);
+                self.global_location = placement.get_location(local_location);
+                self.is_located = true;
+                // the address may depend on the coordinate, so this node joins again
+                self.lease_renew_at = 0;
+                ShapeEvent::Moved {
+                    from,
+                    to: self.coordinate,
+                    location: self.global_location,
+                }
+            }
+            // no placement or ambiguous placements
+            _ if self.is_located => {
+                self.is_located = false;
+                ShapeEvent::Lost(self.coordinate)
+            }
+            _ => return,
+        };
+        info!("shape event: {:?}", event);
+        if self.shape_events.len() >= EVENTS_LENGTH {
+            self.shape_events.remove(0);
+        }
+        self.shape_events.push(event);
+    }
+
+    /// events of re-estimation of the coordinate since the last call.
+    /// only the latest EVENTS_LENGTH events are kept.
+    pub fn take_shape_events(&mut self) -> Vec<ShapeEvent> {
+        std::mem::take(&mut self.shape_events)
+    }
+
+    fn process_link_events(&mut self, events: Vec<LinkEvent>) {
+        for event in events {
+            info!("link event: {:?}", event);
+            let result = match &event {
+                LinkEvent::Up(neighbor) => {
+                    self.protocol.add_connection(self.ip_address, neighbor.id)
+                }
+                LinkEvent::Down(neighbor) => {
+                    // the spanning tree is made of mac addresses
+                    for packet in self.spanning_tree.remove_node(neighbor.mac_address) {
+                        if let Err(e) = packet.send(&mut self.serial) {
+                            info!("failed to leave spanning tree: {:?}", e);
+                        }
+                    }
+                    self.protocol
+                        .remove_connection(self.ip_address, neighbor.id)
+                }
+            };
+            // the protocol may not accept the connection, e.g. a neighbor outside its grid
+            if let Err(e) = result {
+                info!("protocol rejected {:?}: {:?}", event, e);
+            }
+            if self.link_events.len() >= EVENTS_LENGTH {
+                self.link_events.remove(0);
+            }
+            self.link_events.push(event);
+        }
+    }
+
+    /// link-up and link-down events since the last call.
+    /// only the latest EVENTS_LENGTH events are kept.
+    pub fn take_link_events(&mut self) -> Vec<LinkEvent> {
+        std::mem::take(&mut self.link_events)
+    }
+
+    /// neighbors which are alive, sorted by ip address
+    pub fn get_neighbors(&self) -> &[Neighbor] {
+        self.neighbors.get_neighbors()
+    }
+
+    /// let the protocol exchange routing messages with neighbors.
+    /// it should be called periodically, so that dynamic protocols can follow topology changes.
+    /// packets which wait for forwarding are also sent.
+    pub fn update_routing(&mut self) -> Result<()> {
+        if !self.is_joined {
+            return Ok(());
+        }
+        self.flush_forward_buffers();
+        let now = self.now();
+        let messages = self.protocol.update(self.ip_address, now);
+        self.send_routing_messages(messages)
+    }
+
+    fn process_routing_packet(&mut self, packet: &Packet) -> Result<()> {
+        if !self.is_joined {
+            return Ok(());
+        }
+        let to = packet.get_to();
+        if to != ToId::Broadcast && to != ToId::Unicast(self.ip_address) {
+            return Ok(());
+        }
+        let now = self.now();
+        let messages = self.protocol.process_routing_message(
+            self.ip_address,
+            packet.get_from(),
+            &packet.get_ref_messages()[..packet.get_real_messages_length()],
+            now,
+        )?;
+        self.send_routing_messages(messages)
+    }
+
+    fn send_routing_messages(&mut self, messages: Vec<RoutingMessage>) -> Result<()> {
+        for RoutingMessage { to, messages } in messages {
+            let packet = Packet::new(
+                0,
+                Header::Routing,
+                self.ip_address,
+                to,
+                self.ip_address,
+                to,
+                messages,
+            )?;
+            packet.send(&mut self.serial)?;
+        }
+        Ok(())
+    }
+
+    /// routing table of this node, e.g. to print it on the display
+    pub fn get_routing_table(&self) -> Vec<RouteEntry> {
+        self.protocol.get_routing_table(self.ip_address, self.now())
+    }
+
+    /// ask the node for a page of its routing table which begins at start.
+    /// the reply comes from get_packet as ReplyRoutingTable packet, and it is loaded by
+    /// Packet::load_routing_table_packet. the next page is requested by
+    /// RoutingTablePage::get_next_start.
+    pub fn request_routing_table(&mut self, destination: Id, start: u16) -> Result<()> {
+        let packet = self.make_packet(
+            Header::RequestRoutingTable,
+            self.ip_address,
+            ToId::Unicast(destination),
+            start.to_be_bytes().to_vec(),
+        )?;
+        packet.send(&mut self.serial)?;
+        Ok(())
+    }
+
+    fn reply_routing_table(&mut self, packet: &Packet) -> Result<()> {
+        let start = match packet.get_ref_messages().as_slice() {
+            [first, second, ..] => u16::from_be_bytes([*first, *second]),
+            _ => 0,
+        };
+        let page = RoutingTablePage::new(&self.get_routing_table(), start);
+        let reply = self.make_packet(
+            Header::ReplyRoutingTable,
+            self.ip_address,
+            ToId::Unicast(packet.get_global_from()),
+            page.to_messages(),
+        )?;
+        reply.send(&mut self.serial)?;
+        Ok(())
+    }
+
+    /// advertise that this node provides the service, so that packets to ToId::Anycast(service)
+    /// come to this node if it is the nearest provider.
+    pub fn provide_service(&mut self, service: ServiceId) -> Result<()> {
+        if self.services.contains(&service) {
+            return Ok(());
+        }
+        if self.services.len() >= MAX_SERVICES {
+            return Err(anyhow!("too many services to provide: {}", service));
+        }
+        self.services.push(service);
+        // other nodes should know it soon
+        self.services_advertise_at = 0;
+        Ok(())
+    }
+
+    /// flood the services which this node provides, and forget providers which are not
+    /// advertised any more. it should be called periodically after joining global network.
+    pub fn advertise_services(&mut self) -> Result<()> {
+        if !self.is_joined {
+            return Ok(());
+        }
+        let now = self.now();
+        self.service_table.expire(now);
+        if self.services.is_empty() || now < self.services_advertise_at {
+            return Ok(());
+        }
+        let packet = self.make_packet(
+            Header::AdvertiseService,
+            self.ip_address,
+            ToId::Broadcast,
+            service::to_messages(&self.services)?,
+        )?;
+        packet.send(&mut self.serial)?;
+        self.services_advertise_at = now + SERVICE_ADVERTISE_INTERVAL;
+        Ok(())
+    }
+
+    fn process_service_advertisement(&mut self, packet: &Packet) -> Result<()> {
+        let services = service::from_messages(
+            &packet.get_ref_messages()[..packet.get_real_messages_length()],
+        )?;
+        let now = self.now();
+        self.service_table
+            .advertise(packet.get_global_from(), &services, now);
+        Ok(())
+    }
+
+    /// the provider of the service which is the nearest from this node by Protocol::get_distance.
+    /// providers of the same distance are chosen by smaller ip address.
+    pub fn get_nearest_provider(&self, service: ServiceId) -> Result<Id> {
+        self.service_table
+            .get_providers(service)
+            .into_iter()
+            .filter(|provider| *provider != self.ip_address)
+            .filter_map(|provider| {
+                self.protocol
+                    .get_distance(self.ip_address, provider)
+                    .ok()
+                    .map(|distance| (distance, provider))
+            })
+            .min()
+            .map(|(_, provider)| provider)
+            .ok_or_else(|| anyhow!("no provider of service {} is reachable", service))
+    }
+
+    fn now(&self) -> Millis {
+        self.started_at.elapsed().as_millis() as Millis
+    }
+
+    /// send Error packet to the original source of the packet.
+    /// an error of the packet which this node sent is handled here without sending it.
+    fn report_error(&mut self, error: PacketError) -> Result<()> {
+        if error.source == self.ip_address {
+            info!("error of own packet: {}", error);
+            self.process_error(error)?;
+            return Ok(());
+        }
+        let packet = match self.make_packet(
+            Header::Error,
+            self.ip_address,
+            ToId::Unicast(error.source),
+            error.to_messages(),
+        ) {
+            Ok(packet) => packet,
+            Err(e) => {
+                // error cannot be reported, so just ignore it
+                info!("failed to report error {}: {:?}", error, e);
+                return Ok(());
+            }
+        };
+        packet.send(&mut self.serial)?;
+        Ok(())
+    }
+
+    /// handle Error packet which is sent to this node.
+    /// return true if the packet is resent.
+    fn process_error_packet(&mut self, packet: &Packet) -> Result<bool> {
+        let error = packet.load_error_packet()?;
+        if error.source != self.ip_address {
+            return Ok(false);
+        }
+        self.process_error(error)
+    }
+
+    /// resend the packet of the error if it can be recovered, or forget it.
+    /// return true if the packet is resent.
+    fn process_error(&mut self, error: PacketError) -> Result<bool> {
+        if !error.code.is_resendable() {
+            self.sent_packets
+                .retain(|(packet, _)| packet.get_packet_id() != error.packet_id);
+            return Ok(false);
+        }
+        self.resend(error.packet_id)
+    }
+
+    /// resend the packet which this node sent.
+    /// return false if the packet is not kept or it has been resent too many times.
+    pub fn resend(&mut self, packet_id: PacketId) -> Result<bool> {
+        let index = match self
+            .sent_packets
+            .iter()
+            .position(|(packet, _)| packet.get_packet_id() == packet_id)
+        {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        let (packet, resend_count) = &mut self.sent_packets[index];
+        if *resend_count >= MAX_RESEND {
+            self.sent_packets.remove(index);
+            return Ok(false);
+        }
+        *resend_count += 1;
+        info!("resend packet {} ({} times)", packet_id, resend_count);
+        packet.send(&mut self.serial)?;
+        Ok(true)
+    }
     pub fn get_coordinate(&self) -> Coordinate {
         self.coordinate
     }
     pub fn get_local_location(&self) -> LocalNetworkLocation {
         self.localnet.get_location()
     }
+    /// coordinate of the down left node of this localnet, which is used by ToId::Localnet
+    pub fn get_localnet_coordinate(&self) -> Coordinate {
+        let (x, y) = self.coordinate;
+        let (dx, dy) = self.global_location.get_root_coordinate();
+        (x - dx, y - dy)
+    }
     pub fn get_global_location(&self) -> LocalNetworkLocation {
         self.global_location
     }
@@ -581,6 +1670,38 @@ where
     pub fn get_ip_address(&self) -> Id {
         self.ip_address
     }
+    pub fn is_joined(&self) -> bool {
+        self.is_joined
+    }
+    /// whether the coordinate agrees with the connections to other localnets
+    pub fn is_located(&self) -> bool {
+        self.is_located
+    }
+    /// progress of the estimation of the coordinate, e.g. to show it on the display
+    pub fn get_estimation(&self) -> &Estimation {
+        &self.estimation
+    }
+    /// protocol of this node, e.g. to query its routing table or topology
+    pub fn get_protocol(&self) -> &T {
+        &self.protocol
+    }
+    /// members of global network. it is available only in the root.
+    pub fn get_members(&self) -> Option<&[Member]> {
+        self.membership
+            .as_ref()
+            .map(|membership| membership.get_members())
+    }
+    /// mac address of parent in the spanning tree
+    pub fn get_parent(&self) -> Option<Id> {
+        self.spanning_tree.get_parent()
+    }
+    /// mac addresses of children in the spanning tree
+    pub fn get_children(&self) -> &[Id] {
+        self.spanning_tree.get_children()
+    }
+    pub fn get_depth(&self) -> Option<Depth> {
+        self.spanning_tree.get_depth()
+    }
     pub fn print_coordinate(&self) {
         println!("coordinate: {:?}", self.coordinate);
     }
@@ -598,14 +1719,27 @@ where
     }
     pub fn send(&mut self, packet: Packet) -> Result<()> {
         packet.send(&mut self.serial)?;
+        // keep packets which may be resent when Error packet is received
+        if packet.get_global_from() == self.ip_address
+            && packet.get_header() != Header::Error
+            && !packet.get_header().is_only_head()
+        {
+            if self.sent_packets.len() >= SENT_PACKETS_LENGTH {
+                self.sent_packets.remove(0);
+            }
+            self.sent_packets
+                .retain(|(sent, _)| sent.get_packet_id() != packet.get_packet_id());
+            self.sent_packets.push((packet, 0));
+        }
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::flit::Flit;
     use crate::serial::test::TestSerial;
-    // use crate::system::test::TestSystemInfo;
+    use crate::system::test::TestSystemInfo;
     // use global_network::DefaultProtocol;
     use crate::protocol::test::TestProtocol;
 
@@ -630,7 +1764,7 @@ mod test {
             );
         assert_eq!(
             get_global_coordinate,
-            ((2, 0), LocalNetworkLocation::DownLeft)
+            Some(((2, 0), LocalNetworkLocation::DownLeft))
         );
 
         let get_global_coordinate =
@@ -642,7 +1776,7 @@ mod test {
             );
         assert_eq!(
             get_global_coordinate,
-            ((2, 1), LocalNetworkLocation::UpLeft)
+            Some(((2, 1), LocalNetworkLocation::UpLeft))
         );
 
         // second
@@ -660,7 +1794,7 @@ mod test {
             );
         assert_eq!(
             get_global_coordinate,
-            ((1, 2), LocalNetworkLocation::DownRight)
+            Some(((1, 2), LocalNetworkLocation::DownRight))
         );
         let get_global_coordinate =
             NetworkNode::<TestProtocol, TestSerial>::get_global_coordinate_and_global_location_from_local_location(
@@ -671,7 +1805,7 @@ mod test {
             );
         assert_eq!(
             get_global_coordinate,
-            ((0, 2), LocalNetworkLocation::DownLeft)
+            Some(((0, 2), LocalNetworkLocation::DownLeft))
         );
 
         // third
@@ -689,7 +1823,7 @@ mod test {
             );
         assert_eq!(
             get_global_coordinate,
-            ((-1, 0), LocalNetworkLocation::DownRight)
+            Some(((-1, 0), LocalNetworkLocation::DownRight))
         );
         let get_global_coordinate =
             NetworkNode::<TestProtocol, TestSerial>::get_global_coordinate_and_global_location_from_local_location(
@@ -700,7 +1834,7 @@ mod test {
             );
         assert_eq!(
             get_global_coordinate,
-            ((-1, 1), LocalNetworkLocation::UpRight)
+            Some(((-1, 1), LocalNetworkLocation::UpRight))
         );
 
         // fourth
@@ -718,7 +1852,7 @@ mod test {
             );
         assert_eq!(
             get_global_coordinate,
-            ((0, -1), LocalNetworkLocation::UpLeft)
+            Some(((0, -1), LocalNetworkLocation::UpLeft))
         );
         let get_global_coordinate =
             NetworkNode::<TestProtocol, TestSerial>::get_global_coordinate_and_global_location_from_local_location(
@@ -729,7 +1863,596 @@ mod test {
             );
         assert_eq!(
             get_global_coordinate,
-            ((1, -1), LocalNetworkLocation::UpRight)
+            Some(((1, -1), LocalNetworkLocation::UpRight))
+        );
+    }
+
+    /// node which has joined global network without waiting for neighbors
+    fn make_joined_node(
+        ip_address: Id,
+        coordinate: Coordinate,
+    ) -> NetworkNode<TestProtocol, TestSerial> {
+        let localnet = LocalNetwork::new(&TestSystemInfo::new(ip_address));
+        NetworkNode {
+            ip_address,
+            mac_address: ip_address,
+            global_location: localnet.get_location(),
+            localnet,
+            coordinate,
+            is_located: true,
+            serial: TestSerial::new(),
+            protocol: TestProtocol::new(),
+            spanning_tree: SpanningTree::new(ip_address, false),
+            parent_advertise_at: 0,
+            estimation: Estimation::confirmed(),
+            coordinate_replies: Vec::new(),
+
+            is_joined: true,
+            membership: None,
+            join_routes: Vec::new(),
+            join_reply: None,
+            receiving_join_reply: None,
+            join_request_at: 0,
+            join_deadline: JOIN_TIMEOUT,
+            conflict_reports: Vec::new(),
+            unsent_conflict_reports: Vec::new(),
+            lease_renew_at: 0,
+
+            services: Vec::new(),
+            service_table: ServiceTable::new(),
+            services_advertise_at: 0,
+
+            neighbors: NeighborTable::new(),
+            link_events: Vec::new(),
+            probe_at: 0,
+            evidence: Vec::new(),
+            shape_events: Vec::new(),
+
+            packet_id: 0,
+            sent_packets: Vec::new(),
+            forward_buffers: Default::default(),
+            seen_packets: VecDeque::new(),
+
+            started_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_flood() {
+        let mut node = make_joined_node(8, (0, 0));
+        // Routing header doesn't require ack, so the test serial can send it
+        let make_packet = |packet_id: PacketId, to: ToId, ttl: Ttl| {
+            let mut packet = Packet::new(
+                packet_id,
+                Header::Routing,
+                2,
+                to,
+                3,
+                ToId::Broadcast,
+                vec![1],
+            )
+            .unwrap();
+            packet.set_ttl(ttl);
+            packet
+        };
+
+        // delivered and relayed once
+        assert!(node.flood(&make_packet(1, ToId::Broadcast, 3)).unwrap());
+        let relayed = Packet::from_flits(
+            node.serial
+                .data
+                .drain(..)
+                .map(Flit::from_be_bytes)
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(relayed.get_ttl(), 2);
+        assert_eq!(relayed.get_from(), 8);
+        assert!(!node.flood(&make_packet(1, ToId::Broadcast, 3)).unwrap());
+        assert!(node.serial.data.is_empty());
+
+        // a copy by a shorter path is relayed farther, but it is not delivered again
+        assert!(!node.flood(&make_packet(1, ToId::Broadcast, 5)).unwrap());
+        assert!(!node.serial.data.is_empty());
+        node.serial.data.clear();
+
+        // the last hop of radius broadcast
+        assert!(node.flood(&make_packet(2, ToId::Broadcast, 1)).unwrap());
+        assert!(node.serial.data.is_empty());
+
+        // a group is relayed even if this node is not a member
+        assert!(!node.flood(&make_packet(3, ToId::Row(1), 3)).unwrap());
+        assert!(!node.serial.data.is_empty());
+        assert!(node.flood(&make_packet(4, ToId::Column(0), 3)).unwrap());
+
+        // own packet is not received
+        let own = Packet::new(
+            5,
+            Header::Routing,
+            8,
+            ToId::Broadcast,
+            9,
+            ToId::Broadcast,
+            vec![],
+        )
+        .unwrap();
+        assert!(!node.flood(&own).unwrap());
+    }
+
+    #[test]
+    fn test_reply_routing_table() {
+        let mut node = make_joined_node(8, (0, 0));
+        let request = Packet::new(
+            1,
+            Header::RequestRoutingTable,
+            3,
+            ToId::Unicast(8),
+            2,
+            ToId::Unicast(8),
+            vec![0, 0],
+        )
+        .unwrap();
+        node.reply_routing_table(&request).unwrap();
+        let reply = Packet::from_flits(
+            node.serial
+                .data
+                .drain(..)
+                .map(Flit::from_be_bytes)
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(reply.get_header(), Header::ReplyRoutingTable);
+        assert_eq!(reply.get_global_to(), ToId::Unicast(3));
+        let page = reply.load_routing_table_packet().unwrap();
+        assert_eq!(page.entries, node.get_routing_table());
+        assert_eq!(page.total, 1);
+        assert!(request.load_routing_table_packet().is_err());
+    }
+
+    #[test]
+    fn test_update_lease() {
+        let mut node = make_joined_node(8, (0, 0));
+        node.membership = Some(Membership::new());
+        // the root renews its own lease
+        node.update_lease().unwrap();
+        assert_eq!(node.get_members().unwrap().len(), 1);
+        assert!(node.lease_renew_at >= LEASE_TIME / 2);
+        assert!(node.is_joined());
+
+        // another node has taken the coordinate, so the node has to join again
+        let reply = node.membership.as_mut().unwrap().join(
+            &mut TestProtocol::new(),
+            &JoinRequest::new(node.mac_address + 1, (0, 0)),
+            0,
+        );
+        assert_eq!(reply.status, JoinStatus::Duplicated);
+        node.process_renewal_reply(reply);
+        assert!(!node.is_joined());
+        assert_eq!(node.join_request_at, 0);
+    }
+
+    #[test]
+    fn test_join_again_after_failed_renewal() {
+        let mut node = make_joined_node(8, (1, 0));
+        let mut membership = Membership::new();
+        let mut protocol = TestProtocol::new();
+        membership.join(&mut protocol, &JoinRequest::new(9, (1, 0)), 0);
+        let reply = membership.join(&mut protocol, &JoinRequest::new(8, (1, 0)), 0);
+        assert_eq!(reply.status, JoinStatus::Duplicated);
+        node.process_renewal_reply(reply);
+        assert!(!node.is_joined());
+
+        // the node waits for the join reply instead of renewing the lease
+        node.update_lease().unwrap();
+        assert!(!node.is_joined());
+
+        // the reply which arrives later makes the node joined again
+        membership.expire(&mut protocol, LEASE_TIME + 1);
+        node.join_reply = Some(membership.join(&mut protocol, &JoinRequest::new(8, (1, 0)), 0));
+        node.update_lease().unwrap();
+        assert!(node.is_joined());
+    }
+
+    #[test]
+    fn test_report_own_error() {
+        let mut node = make_joined_node(8, (0, 0));
+        let packet = node
+            .make_packet(Header::Data, 8, ToId::Unicast(3), vec![1])
+            .unwrap();
+        node.sent_packets.push((packet.clone(), 0));
+
+        // the error of own packet is not sent, and the packet is forgotten
+        node.report_error(PacketError::new(
+            ErrorCode::TtlExpired,
+            packet.get_packet_id(),
+            8,
+        ))
+        .unwrap();
+        assert!(node.serial.data.is_empty());
+        assert!(node.sent_packets.is_empty());
+    }
+
+    #[test]
+    fn test_anycast() {
+        let mut node = make_joined_node(8, (0, 0));
+        node.provide_service(1).unwrap();
+        node.advertise_services().unwrap();
+        let advertisement = Packet::from_flits(
+            node.serial
+                .data
+                .drain(..)
+                .map(Flit::from_be_bytes)
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(advertisement.get_header(), Header::AdvertiseService);
+        assert_eq!(advertisement.get_global_to(), ToId::Broadcast);
+        // the next advertisement waits for the interval
+        node.advertise_services().unwrap();
+        assert!(node.serial.data.is_empty());
+
+        assert!(node.get_nearest_provider(2).is_err());
+        for provider in [5, 3] {
+            let advertisement = Packet::new(
+                1,
+                Header::AdvertiseService,
+                provider,
+                ToId::Broadcast,
+                provider,
+                ToId::Broadcast,
+                service::to_messages(&[2]).unwrap(),
+            )
+            .unwrap();
+            node.process_service_advertisement(&advertisement).unwrap();
+        }
+        // TestProtocol tells smaller ids are nearer
+        assert_eq!(node.get_nearest_provider(2).unwrap(), 3);
+        // this node is not a provider for itself
+        assert!(node.get_nearest_provider(1).is_err());
+
+        let packet = node
+            .make_packet(Header::Routing, 8, ToId::Anycast(2), vec![1])
+            .unwrap();
+        assert_eq!(packet.get_global_to(), ToId::Anycast(2));
+        assert_eq!(packet.get_to(), ToId::Unicast(0));
+        assert!(node
+            .make_packet(Header::Routing, 8, ToId::Anycast(4), vec![1])
+            .is_err());
+
+        // the nearest provider from this node is chosen again when the packet is relayed
+        let mut packet = packet;
+        packet.change_from_and_to(2, ToId::Unicast(8));
+        node.relay(packet, node.get_nearest_provider(2)).unwrap();
+        let relayed = Packet::from_flits(
+            node.serial
+                .data
+                .drain(..)
+                .map(Flit::from_be_bytes)
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(relayed.get_global_to(), ToId::Anycast(2));
+        assert_eq!(relayed.get_from(), 8);
+        assert_eq!(relayed.get_ttl(), packet::DEFAULT_TTL - 1);
+    }
+
+    #[test]
+    fn test_vote_coordinate() {
+        type Node = NetworkNode<TestProtocol, TestSerial>;
+        // DownLeft of the localnet at the right of the root localnet. DownRight of the root
+        // localnet is its neighbor, and UpLeft and DownRight of this localnet tell UpRight of the
+        // root localnet.
+        let this_id = 0b1100;
+        let mut claims = vec![(0b111, 0b111, (1, 0)), (0b1000, 0b011, (1, 1))];
+        let mut conflicts = Vec::new();
+        let decided = ((2, 0), LocalNetworkLocation::DownLeft);
+        assert_eq!(
+            Node::coordinate_and_global_location_from_neighbor_confirmed(
+                &claims,
+                this_id,
+                &mut conflicts
+            )
+            .unwrap(),
+            decided
+        );
+
+        // a wrong claim ties
+        claims.push((0b1000, 0b10010, (1, -1)));
+        assert!(
+            Node::coordinate_and_global_location_from_neighbor_confirmed(
+                &claims,
+                this_id,
+                &mut conflicts
+            )
+            .is_err()
+        );
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, ConflictKind::Tie);
+        assert_eq!(conflicts[0].claims, [(this_id, (0, 0)), (this_id, (2, 0))]);
+
+        // the majority wins
+        claims.push((0b1110, 0b011, (1, 1)));
+        conflicts.clear();
+        assert_eq!(
+            Node::coordinate_and_global_location_from_neighbor_confirmed(
+                &claims,
+                this_id,
+                &mut conflicts
+            )
+            .unwrap(),
+            decided
+        );
+        assert!(conflicts.is_empty());
+
+        // two nodes at the same coordinate are not used if they are claimed equally
+        let mut claims = vec![
+            (0b111, 0b111, (1, 0)),
+            (0b1000, 0b011, (1, 1)),
+            (0b1110, 0b10110, (1, 1)),
+        ];
+        assert!(
+            Node::coordinate_and_global_location_from_neighbor_confirmed(
+                &claims,
+                this_id,
+                &mut conflicts
+            )
+            .is_err()
+        );
+        assert_eq!(conflicts[0].kind, ConflictKind::SameCoordinate);
+
+        // otherwise the node which more neighbors claim is used
+        claims.push((0b1101, 0b011, (1, 1)));
+        let mut resolved = Vec::new();
+        assert_eq!(
+            Node::coordinate_and_global_location_from_neighbor_confirmed(
+                &claims,
+                this_id,
+                &mut resolved
+            )
+            .unwrap(),
+            decided
+        );
+        assert!(resolved.is_empty());
+
+        // the root keeps reports
+        let mut root = NetworkNode::start(
+            TestSerial::new(),
+            TestProtocol::new(),
+            &TestSystemInfo::new(0b1),
+        );
+        let report = ConflictReport {
+            mac_address: this_id,
+            conflict: conflicts[0],
+        };
+        for flit in report.to_packet(0b1).unwrap().to_flits().into_iter().rev() {
+            root.serial.data.push(flit.to_be_bytes());
+        }
+        assert!(root.get_packet().unwrap().is_none());
+        assert_eq!(root.take_conflict_reports(), vec![report]);
+
+        // a node which is not in the spanning tree reports through a neighbor outside the localnet
+        let mut node = NetworkNode::start(
+            TestSerial::new(),
+            TestProtocol::new(),
+            &TestSystemInfo::new(this_id),
+        );
+        node.queue_conflict_report(report);
+        node.estimation.receive(0b1101, vec![(0b011, (1, 1))]);
+        node.send_conflict_reports();
+        assert!(node.serial.data.is_empty());
+        node.estimation.receive(0b111, vec![(0b111, (1, 0))]);
+        node.send_conflict_reports();
+        let sent = Packet::from_flits(
+            node.serial
+                .data
+                .drain(..)
+                .map(Flit::from_be_bytes)
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(sent.get_to(), ToId::Unicast(0b111));
+        assert_eq!(ConflictReport::from_packet(&sent).unwrap(), report);
+        assert!(node.unsent_conflict_reports.is_empty());
+    }
+
+    #[test]
+    fn test_process_control_packet() {
+        let mut node = make_joined_node(0b1100, (2, 0));
+        let push = |node: &mut NetworkNode<TestProtocol, TestSerial>, packet: Packet| {
+            for flit in packet.to_flits().into_iter().rev() {
+                node.serial.data.push(flit.to_be_bytes());
+            }
+        };
+
+        // a new node in another localnet asks the coordinate
+        push(
+            &mut node,
+            Packet::make_request_confirmed_coordinate_packet(0b10000),
+        );
+        assert!(node.get_packet().unwrap().is_none());
+        // the reply is delayed
+        assert!(node.serial.data.is_empty());
+        assert_eq!(node.coordinate_replies.len(), 1);
+        node.coordinate_replies[0].0 = 0;
+        node.send_coordinate_replies().unwrap();
+        assert!(node.coordinate_replies.is_empty());
+        let reply = Packet::from_flits(
+            node.serial
+                .data
+                .drain(..)
+                .map(Flit::from_be_bytes)
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(reply.get_header(), Header::ConfirmCoordinate);
+        assert_eq!(
+            reply.load_confirmed_coordinate_packet(0b10000).unwrap(),
+            vec![(0b1100, (2, 0))]
+        );
+
+        // the coordinate of a detached localnet is not told
+        node.is_located = false;
+        push(
+            &mut node,
+            Packet::make_request_confirmed_coordinate_packet(0b10000),
+        );
+        assert!(node.get_packet().unwrap().is_none());
+        assert!(node.serial.data.is_empty());
+
+        // connection checks are neither replied nor flooded
+        push(&mut node, Packet::make_check_connection_packet(0b10000));
+        assert!(node.get_packet().unwrap().is_none());
+        assert!(node.serial.data.is_empty());
+    }
+
+    #[test]
+    fn test_update_neighbors() {
+        let mut node = make_joined_node(8, (0, 0));
+        node.update_neighbors().unwrap();
+        let probe = Packet::from_flits(
+            node.serial
+                .data
+                .drain(..)
+                .map(Flit::from_be_bytes)
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(
+            NeighborProbe::from_packet(&probe).unwrap(),
+            NeighborProbe::new(8, (0, 0))
+        );
+        // the next probe waits for the interval
+        node.update_neighbors().unwrap();
+        assert!(node.serial.data.is_empty());
+
+        let probe = NeighborProbe::new(0x20, (1, 0)).to_packet(5).unwrap();
+        node.process_probe_packet(&probe).unwrap();
+        node.process_probe_packet(&probe).unwrap();
+        let events = node.take_link_events();
+        assert!(matches!(
+            events[..],
+            [LinkEvent::Up(Neighbor {
+                id: 5,
+                coordinate: (1, 0),
+                is_localnet: false,
+                ..
+            })]
+        ));
+        assert!(node.take_link_events().is_empty());
+        assert_eq!(node.get_neighbors().len(), 1);
+    }
+
+    #[test]
+    fn test_relocate() {
+        // UpLeft of its localnet, which is placed at (2, 0) without rotation
+        let mut node = make_joined_node(8, (2, 1));
+        node.process_probe_packet(&NeighborProbe::new(0x20, (1, 1)).to_packet(5).unwrap())
+            .unwrap();
+        assert!(node.take_shape_events().is_empty());
+
+        // the localnet is detached
+        let events = node.neighbors.expire(Millis::MAX);
+        node.process_link_events(events);
+        node.relocate();
+        assert_eq!(node.take_shape_events(), vec![ShapeEvent::Lost((2, 1))]);
+        assert!(!node.is_located());
+
+        // and attached at (0, 2). a connection of this node leaves two placements, and the
+        // connection which the node at UpRight of the localnet tells decides it.
+        node.process_probe_packet(&NeighborProbe::new(0x30, (-1, 3)).to_packet(6).unwrap())
+            .unwrap();
+        assert!(node.take_shape_events().is_empty());
+        let mut probe = NeighborProbe::new(10, (3, 1));
+        probe.is_located = false;
+        probe.outside_neighbors = vec![(2, 3)];
+        node.process_probe_packet(&probe.to_packet(9).unwrap())
+            .unwrap();
+        assert_eq!(
+            node.take_shape_events(),
+            vec![ShapeEvent::Moved {
+                from: (2, 1),
+                to: (0, 3),
+                location: LocalNetworkLocation::UpLeft,
+            }]
+        );
+        assert!(node.is_located());
+        assert_eq!(node.get_coordinate(), (0, 3));
+        // the new coordinate is told to the root
+        assert_eq!(node.lease_renew_at, 0);
+    }
+
+    #[test]
+    fn test_poll_estimation() {
+        // DownLeft of the localnet at the right of the root localnet
+        let mut node = NetworkNode::start(
+            TestSerial::new(),
+            TestProtocol::new(),
+            &TestSystemInfo::new(0b1100),
+        );
+        assert!(!node.is_located());
+        assert!(!node.poll().unwrap());
+        let estimation = node.get_estimation();
+        assert_eq!(estimation.get_state(), EstimationState::Discovering);
+        assert_eq!(estimation.get_requests(), 1);
+
+        // DownRight of the root localnet replies, and UpLeft of this localnet tells UpRight of
+        // the root localnet. the test serial pops the last flit first.
+        let replies = [
+            Packet::make_confirm_coordinate_packet(0b1000, 0b1100, &vec![(0b011, 0b011, (1, 1))])
+                .unwrap(),
+            Packet::make_confirm_coordinate_packet_by_confirmed_node(
+                0b111,
+                0b1100,
+                (1, 0),
+                LocalNetworkLocation::DownRight,
+            )
+            .unwrap(),
+        ];
+        for packet in replies {
+            for flit in packet.to_flits().into_iter().rev() {
+                node.serial.data.push(flit.to_be_bytes());
+            }
+        }
+        assert!(!node.poll().unwrap());
+        assert_eq!(
+            node.get_estimation().get_state(),
+            EstimationState::Confirmed
+        );
+        assert_eq!(node.get_estimation().get_replies(), 2);
+        assert!(node.is_located());
+        assert_eq!(node.get_coordinate(), (2, 0));
+        assert_eq!(node.get_global_location(), LocalNetworkLocation::DownLeft);
+        // it waits for the spanning tree to send join request
+        assert!(!node.is_joined());
+        assert!(node.join_deadline >= JOIN_TIMEOUT);
+
+        // the node gives up when it is not joined in time
+        node.join_deadline = 0;
+        sleep(Duration::from_millis(1));
+        assert!(!node.poll().unwrap());
+        assert_eq!(node.get_estimation().get_state(), EstimationState::Failed);
+
+        let mut root = NetworkNode::start(
+            TestSerial::new(),
+            TestProtocol::new(),
+            &TestSystemInfo::new(0b1),
+        );
+        assert_eq!(
+            root.get_estimation().get_state(),
+            EstimationState::Confirmed
         );
+        assert!(root.poll().unwrap());
+        // the root advertises itself as a parent while poll is called
+        let advertisement = Packet::from_flits(
+            root.serial
+                .data
+                .drain(..)
+                .map(Flit::from_be_bytes)
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(advertisement.get_header(), Header::SendParentId);
+        assert!(root.poll().unwrap());
+        assert!(root.serial.data.is_empty());
     }
 }
@@ -0,0 +1,160 @@
+//! re-estimation of coordinates after the wall is reshaped.
+//!
+//! A unit (localnet) is a rigid 2x2 tile, and units are aligned to the root localnet, whose down
+//! left node is at (0, 0). So the coordinates of a unit are decided by its placement: the down
+//! left coordinate and the rotation of the tile. Each node of the unit knows its neighbors outside
+//! the unit, and every such connection limits the placement. The placement which agrees with all
+//! connections of the unit is the new placement.
+
+use crate::localnet::LocalNetworkLocation;
+use crate::utils::type_alias::Coordinate;
+
+/// locations in clockwise order, whose index is the value of LocalNetworkLocation
+const LOCATIONS: [LocalNetworkLocation; 4] = [
+    LocalNetworkLocation::UpLeft,
+    LocalNetworkLocation::UpRight,
+    LocalNetworkLocation::DownRight,
+    LocalNetworkLocation::DownLeft,
+];
+
+/// connection from a node of the unit to a node outside the unit.
+/// (location of the node of the unit in its localnet (by mac address), coordinate of the outside
+/// node)
+pub type Evidence = (LocalNetworkLocation, Coordinate);
+
+/// coordinate of this node changes by re-estimation
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ShapeEvent {
+    /// the unit is placed again, e.g. it is moved or rotated
+    Moved {
+        from: Coordinate,
+        to: Coordinate,
+        location: LocalNetworkLocation,
+    },
+    /// the connections of the unit don't decide its placement, e.g. it is detached from the wall.
+    /// the coordinate is kept, but it is stale until the unit is placed again.
+    Lost(Coordinate),
+}
+
+/// location of the node at the coordinate in its unit
+pub fn location_of(coordinate: Coordinate) -> LocalNetworkLocation {
+    match (coordinate.0.rem_euclid(2), coordinate.1.rem_euclid(2)) {
+        (0, 0) => LocalNetworkLocation::DownLeft,
+        (1, 0) => LocalNetworkLocation::DownRight,
+        (0, 1) => LocalNetworkLocation::UpLeft,
+        _ => LocalNetworkLocation::UpRight,
+    }
+}
+
+fn is_same_unit(coordinate: Coordinate, coordinate2: Coordinate) -> bool {
+    coordinate.0.div_euclid(2) == coordinate2.0.div_euclid(2)
+        && coordinate.1.div_euclid(2) == coordinate2.1.div_euclid(2)
+}
+
+/// position and rotation of a unit
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Placement {
+    /// coordinate of the down left node
+    pub origin: Coordinate,
+    /// the number of clockwise rotations from the location in localnet to the global location
+    pub rotation: u8,
+}
+
+impl Placement {
+    /// placement of the unit whose node at the local location is at the coordinate
+    pub fn new(local_location: LocalNetworkLocation, coordinate: Coordinate) -> Self {
+        let global_location = location_of(coordinate);
+        let rotation = (global_location as u8 + 4 - local_location as u8) % 4;
+        Self {
+            origin: (
+                coordinate.0.div_euclid(2) * 2,
+                coordinate.1.div_euclid(2) * 2,
+            ),
+            rotation,
+        }
+    }
+
+    /// global location of the node at the local location
+    pub fn get_location(&self, local_location: LocalNetworkLocation) -> LocalNetworkLocation {
+        LOCATIONS[(local_location as usize + self.rotation as usize) % 4]
+    }
+
+    /// coordinate of the node at the local location
+    pub fn get_coordinate(&self, local_location: LocalNetworkLocation) -> Coordinate {
+        let (dx, dy) = self.get_location(local_location).get_root_coordinate();
+        (self.origin.0 + dx, self.origin.1 + dy)
+    }
+}
+
+/// placements which agree with all connections.
+/// a connection says that the node of the unit is next to the outside node.
+/// there is no placement without connections.
+pub fn estimate(evidence: &[Evidence]) -> Vec<Placement> {
+    let mut placements: Option<Vec<Placement>> = None;
+    for &(local_location, (x, y)) in evidence {
+        let candidates = [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)]
+            .into_iter()
+            .filter(|coordinate| !is_same_unit(*coordinate, (x, y)))
+            .map(|coordinate| Placement::new(local_location, coordinate));
+        placements = Some(match placements {
+            None => candidates.collect(),
+            Some(placements) => candidates
+                .filter(|candidate| placements.contains(candidate))
+                .collect(),
+        });
+    }
+    placements.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use LocalNetworkLocation::*;
+
+    #[test]
+    fn test_placement() {
+        // not rotated
+        let placement = Placement::new(DownLeft, (2, -2));
+        assert_eq!(placement.origin, (2, -2));
+        assert_eq!(placement.rotation, 0);
+        assert_eq!(placement.get_coordinate(UpRight), (3, -1));
+
+        // rotated clockwise: UpLeft of the localnet is at UpRight
+        let placement = Placement::new(UpLeft, (-1, 1));
+        assert_eq!(placement.origin, (-2, 0));
+        assert_eq!(placement.get_location(UpLeft), UpRight);
+        assert_eq!(placement.get_location(DownLeft), UpLeft);
+        assert_eq!(placement.get_coordinate(DownRight), (-2, 0));
+        for location in LOCATIONS {
+            assert_eq!(
+                Placement::new(location, placement.get_coordinate(location)),
+                placement
+            );
+        }
+    }
+
+    #[test]
+    fn test_estimate() {
+        assert!(estimate(&[]).is_empty());
+
+        // the unit is at the right of the root localnet, and not rotated
+        let placement = Placement::new(DownLeft, (2, 0));
+        // only one connection leaves two placements
+        let placements = estimate(&[(DownLeft, (1, 0))]);
+        assert_eq!(placements.len(), 2);
+        assert!(placements.contains(&placement));
+        // a connection of another node decides it
+        assert_eq!(
+            estimate(&[(DownLeft, (1, 0)), (UpLeft, (1, 1))]),
+            vec![placement]
+        );
+        // the unit is rotated, so UpRight of the localnet is next to the root localnet
+        let placements = estimate(&[(UpRight, (1, 0)), (DownRight, (1, 1))]);
+        assert_eq!(placements.len(), 1);
+        assert_eq!(placements[0].get_coordinate(UpRight), (2, 0));
+        assert_eq!(placements[0].get_location(UpRight), DownLeft);
+
+        // connections which disagree, e.g. a probe from the old position
+        assert!(estimate(&[(DownLeft, (1, 0)), (DownLeft, (5, 5))]).is_empty());
+    }
+}
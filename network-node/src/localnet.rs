@@ -9,7 +9,7 @@ use crate::utils::{
     util_const::*,
 };
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum LocalNetworkLocation {
     UpLeft = 0,
     UpRight = 1,
@@ -0,0 +1,482 @@
+use std::mem::size_of;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use num_enum::TryFromPrimitive;
+
+use crate::header::Header;
+use crate::packet::{Packet, ToId, MAX_MESSAGES_LENGTH};
+use crate::protocol::{Millis, Protocol};
+use crate::utils::type_alias::{Coordinate, CoordinateComponent, Id};
+
+/// time for which an assigned address is leased.
+/// a node renews the lease by joining again, and the root reclaims expired addresses.
+pub const LEASE_TIME: Millis = 60_000;
+
+/// request for joining global network, which is sent to the root along the spanning tree.
+/// Data form is like this [ mac_address(16) | x(16) | y(16) ]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct JoinRequest {
+    pub mac_address: Id,
+    pub coordinate: Coordinate,
+}
+
+#[derive(TryFromPrimitive, Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum JoinStatus {
+    Accepted,
+    /// another node has already joined with the same coordinate or address
+    Duplicated,
+    /// the protocol of the root cannot assign an address
+    Failed,
+}
+
+/// reply for JoinRequest, which is sent from the root along the spanning tree.
+/// routing parameters may not fit in a packet, so the reply is sent in fragments which have
+/// parts of them in order (see JoinReplyFragment).
+/// Data form of each fragment is like this
+/// [ status(8) | mac_address(16) | ip_address(16) | lease time in seconds(16) |
+///   length of routing parameters(16) | offset of this part(16) | part of routing parameters(...) ]
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct JoinReply {
+    pub status: JoinStatus,
+    pub mac_address: Id,
+    pub ip_address: Id,
+    /// the address must be renewed in this time. it is 0 if the request is not accepted.
+    pub lease_time: Millis,
+    pub routing_parameters: Vec<u8>,
+}
+
+impl JoinRequest {
+    const MESSAGE_LENGTH: usize = size_of::<Id>() + size_of::<CoordinateComponent>() * 2;
+
+    pub fn new(mac_address: Id, coordinate: Coordinate) -> Self {
+        Self {
+            mac_address,
+            coordinate,
+        }
+    }
+
+    /// the request goes up along the spanning tree, so global destination is not used.
+    pub fn to_packet(&self, parent: Id) -> Result<Packet> {
+        let mut messages = Vec::new();
+        messages.extend(self.mac_address.to_be_bytes());
+        messages.extend(self.coordinate.0.to_be_bytes());
+        messages.extend(self.coordinate.1.to_be_bytes());
+        Packet::new(
+            0,
+            Header::RequestJoinNetwork,
+            self.mac_address,
+            ToId::Broadcast,
+            self.mac_address,
+            ToId::Unicast(parent),
+            messages,
+        )
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<Self> {
+        if packet.get_header() != Header::RequestJoinNetwork {
+            return Err(anyhow!(
+                "This packet is not RequestJoinNetwork: {:?}",
+                packet.get_header()
+            ));
+        }
+        let messages = packet.get_ref_messages();
+        if messages.len() < Self::MESSAGE_LENGTH {
+            return Err(anyhow!("length of join request is not enough: {:?}", messages));
+        }
+        let mac_address = Id::from_be_bytes([messages[0], messages[1]]);
+        let x = CoordinateComponent::from_be_bytes([messages[2], messages[3]]);
+        let y = CoordinateComponent::from_be_bytes([messages[4], messages[5]]);
+        Ok(Self::new(mac_address, (x, y)))
+    }
+}
+
+impl JoinReply {
+    fn new(status: JoinStatus, mac_address: Id, ip_address: Id) -> Self {
+        Self {
+            status,
+            mac_address,
+            ip_address,
+            lease_time: 0,
+            routing_parameters: Vec::new(),
+        }
+    }
+
+    /// packets of the fragments. a reply without routing parameters is sent in a packet.
+    pub fn to_packets(&self, root: Id, child: Id) -> Result<Vec<Packet>> {
+        let total = u16::try_from(self.routing_parameters.len())
+            .map_err(|_| anyhow!("routing parameters are too long: {:?}", self))?;
+        let lease_time = (self.lease_time / 1000).min(u16::MAX as Millis) as u16;
+        let mut parts: Vec<&[u8]> = self
+            .routing_parameters
+            .chunks(JoinReplyFragment::MAX_PART_LENGTH)
+            .collect();
+        if parts.is_empty() {
+            parts.push(&[]);
+        }
+        let mut offset: u16 = 0;
+        let mut packets = Vec::with_capacity(parts.len());
+        for part in parts {
+            let mut messages = vec![self.status as u8];
+            messages.extend(self.mac_address.to_be_bytes());
+            messages.extend(self.ip_address.to_be_bytes());
+            messages.extend(lease_time.to_be_bytes());
+            messages.extend(total.to_be_bytes());
+            messages.extend(offset.to_be_bytes());
+            messages.extend(part);
+            offset += part.len() as u16;
+            packets.push(Packet::new(
+                0,
+                Header::ReplyJoinNetwork,
+                root,
+                ToId::Unicast(self.mac_address),
+                root,
+                ToId::Unicast(child),
+                messages,
+            )?);
+        }
+        Ok(packets)
+    }
+}
+
+/// fragment of JoinReply, whose routing parameters are the part from offset.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct JoinReplyFragment {
+    pub reply: JoinReply,
+    pub offset: usize,
+    /// length of the whole routing parameters
+    pub total: usize,
+}
+
+impl JoinReplyFragment {
+    const HEADER_LENGTH: usize = 1 + size_of::<Id>() * 2 + size_of::<u16>() * 3;
+    const MAX_PART_LENGTH: usize = MAX_MESSAGES_LENGTH - Self::HEADER_LENGTH;
+
+    pub fn from_packet(packet: &Packet) -> Result<Self> {
+        if packet.get_header() != Header::ReplyJoinNetwork {
+            return Err(anyhow!(
+                "This packet is not ReplyJoinNetwork: {:?}",
+                packet.get_header()
+            ));
+        }
+        let messages = packet.get_ref_messages();
+        let length = packet.get_real_messages_length();
+        if length < Self::HEADER_LENGTH {
+            return Err(anyhow!(
+                "length of join reply is not enough: {:?}",
+                messages
+            ));
+        }
+        let status = JoinStatus::try_from(messages[0])?;
+        let mac_address = Id::from_be_bytes([messages[1], messages[2]]);
+        let ip_address = Id::from_be_bytes([messages[3], messages[4]]);
+        let mut reply = JoinReply::new(status, mac_address, ip_address);
+        reply.lease_time = u16::from_be_bytes([messages[5], messages[6]]) as Millis * 1000;
+        reply.routing_parameters = messages[Self::HEADER_LENGTH..length].to_vec();
+        let total = u16::from_be_bytes([messages[7], messages[8]]) as usize;
+        let offset = u16::from_be_bytes([messages[9], messages[10]]) as usize;
+        if offset + reply.routing_parameters.len() > total {
+            return Err(anyhow!("invalid fragment of join reply: {:?}", messages));
+        }
+        Ok(Self {
+            reply,
+            offset,
+            total,
+        })
+    }
+
+    pub fn is_last(&self) -> bool {
+        self.offset + self.reply.routing_parameters.len() == self.total
+    }
+
+    /// append this fragment to the reply which is being received, and return the reply when
+    /// it is complete. a reply which misses a fragment is dropped, and the request is sent again.
+    pub fn assemble(self, receiving: &mut Option<JoinReply>) -> Option<JoinReply> {
+        let reply = match receiving.take() {
+            _ if self.offset == 0 => self.reply,
+            Some(mut reply)
+                if reply.mac_address == self.reply.mac_address
+                    && reply.ip_address == self.reply.ip_address
+                    && reply.routing_parameters.len() == self.offset =>
+            {
+                reply
+                    .routing_parameters
+                    .extend(self.reply.routing_parameters);
+                reply
+            }
+            _ => {
+                info!("fragment of join reply is lost: {:?}", self);
+                return None;
+            }
+        };
+        if reply.routing_parameters.len() < self.total {
+            *receiving = Some(reply);
+            return None;
+        }
+        Some(reply)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Member {
+    pub mac_address: Id,
+    pub ip_address: Id,
+    pub coordinate: Coordinate,
+    /// the member is removed at this time unless it renews the lease
+    pub expires_at: Millis,
+}
+
+/// membership table of global network, which is kept by the root.
+/// it is also the lease table: each member has a lease of its address.
+#[derive(Debug, Default)]
+pub struct Membership {
+    members: Vec<Member>,
+}
+
+impl Membership {
+    pub fn new() -> Self {
+        Self {
+            members: Vec::new(),
+        }
+    }
+
+    /// assign an address to the node by the protocol of the root, and lease it until
+    /// now + LEASE_TIME.
+    /// a node which joins again (e.g. after reboot or to renew the lease) gets the address which
+    /// the addressing scheme of the protocol keeps for it, which is the same address unless the
+    /// scheme has moved it.
+    pub fn join(
+        &mut self,
+        protocol: &mut impl Protocol,
+        request: &JoinRequest,
+        now: Millis,
+    ) -> JoinReply {
+        let JoinRequest {
+            mac_address,
+            coordinate,
+        } = *request;
+
+        let mut reply = self.assign(protocol, mac_address, coordinate);
+        if reply.status == JoinStatus::Accepted {
+            if let Some(member) = self
+                .members
+                .iter_mut()
+                .find(|member| member.mac_address == mac_address)
+            {
+                member.expires_at = now + LEASE_TIME;
+            }
+            reply.lease_time = LEASE_TIME;
+            reply.routing_parameters = protocol.get_routing_parameters();
+        }
+        info!("join request: {:?}, reply: {:?}", request, reply);
+        reply
+    }
+
+    fn assign(
+        &mut self,
+        protocol: &mut impl Protocol,
+        mac_address: Id,
+        coordinate: Coordinate,
+    ) -> JoinReply {
+        let is_duplicated_coordinate = self
+            .members
+            .iter()
+            .any(|member| member.mac_address != mac_address && member.coordinate == coordinate);
+        if is_duplicated_coordinate {
+            return JoinReply::new(JoinStatus::Duplicated, mac_address, 0);
+        }
+
+        let ip_address = match protocol.join_global_network(mac_address, coordinate) {
+            Ok(ip_address) => ip_address,
+            Err(e) => {
+                info!("failed to assign address: {:?}", e);
+                return JoinReply::new(JoinStatus::Failed, mac_address, 0);
+            }
+        };
+        let is_duplicated_address = self
+            .members
+            .iter()
+            .any(|member| member.mac_address != mac_address && member.ip_address == ip_address);
+        if is_duplicated_address {
+            return JoinReply::new(JoinStatus::Duplicated, mac_address, 0);
+        }
+
+        self.remove(mac_address);
+        self.members.push(Member {
+            mac_address,
+            ip_address,
+            coordinate,
+            expires_at: 0,
+        });
+        JoinReply::new(JoinStatus::Accepted, mac_address, ip_address)
+    }
+
+    /// remove the node from the table, e.g. when the tile is removed from the wall.
+    pub fn remove(&mut self, mac_address: Id) -> Option<Member> {
+        let index = self
+            .members
+            .iter()
+            .position(|member| member.mac_address == mac_address)?;
+        Some(self.members.remove(index))
+    }
+
+    /// remove members whose leases expired, e.g. tiles which were removed from the wall,
+    /// and let the protocol reclaim their addresses.
+    pub fn expire(&mut self, protocol: &mut impl Protocol, now: Millis) -> Vec<Member> {
+        let (expired, members) = self
+            .members
+            .drain(..)
+            .partition(|member| member.expires_at <= now);
+        self.members = members;
+        for member in expired.iter() {
+            info!("lease expired: {:?}", member);
+            if let Err(e) = protocol.leave_global_network(member.mac_address, member.ip_address) {
+                info!("failed to reclaim address: {:?}", e);
+            }
+        }
+        expired
+    }
+
+    pub fn find_by_mac_address(&self, mac_address: Id) -> Option<&Member> {
+        self.members
+            .iter()
+            .find(|member| member.mac_address == mac_address)
+    }
+
+    pub fn get_members(&self) -> &[Member] {
+        &self.members
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::test::TestProtocol;
+
+    #[test]
+    fn test_join_request_packet() {
+        let request = JoinRequest::new(0x1234, (-1, 3));
+        let packet = request.to_packet(0x10).unwrap();
+        assert_eq!(packet.get_to(), ToId::Unicast(0x10));
+        let received = Packet::from_flits(packet.to_flits()).unwrap();
+        assert_eq!(JoinRequest::from_packet(&received).unwrap(), request);
+    }
+
+    /// receive the packets of the reply, and assemble it
+    fn assemble(packets: Vec<Packet>) -> Option<JoinReply> {
+        let mut receiving = None;
+        let mut assembled = None;
+        for packet in packets {
+            let received = Packet::from_flits(packet.to_flits()).unwrap();
+            assembled = JoinReplyFragment::from_packet(&received)
+                .unwrap()
+                .assemble(&mut receiving);
+        }
+        assembled
+    }
+
+    #[test]
+    fn test_join_reply_packet() {
+        let mut reply = JoinReply::new(JoinStatus::Accepted, 0x1234, 5);
+        reply.lease_time = LEASE_TIME;
+        reply.routing_parameters = vec![1, 2, 3];
+        let packets = reply.to_packets(1, 0x10).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].get_global_to(), ToId::Unicast(0x1234));
+        assert_eq!(assemble(packets), Some(reply));
+
+        let reply = JoinReply::new(JoinStatus::Duplicated, 0x1234, 0);
+        assert_eq!(assemble(reply.to_packets(1, 0x10).unwrap()), Some(reply));
+    }
+
+    #[test]
+    fn test_fragmented_join_reply() {
+        // e.g. an address table of 200 nodes
+        let mut reply = JoinReply::new(JoinStatus::Accepted, 0x1234, 5);
+        reply.routing_parameters = (0..1200).map(|i| i as u8).collect();
+        let packets = reply.to_packets(1, 0x10).unwrap();
+        assert_eq!(packets.len(), 4);
+        assert_eq!(assemble(packets.clone()), Some(reply.clone()));
+
+        // the second fragment is lost
+        let mut lost = packets.clone();
+        lost.remove(1);
+        assert_eq!(assemble(lost), None);
+        // the reply to the next request replaces the incomplete one
+        let mut receiving = None;
+        let assembled: Vec<JoinReply> = packets[..2]
+            .iter()
+            .chain(packets.iter())
+            .filter_map(|packet| {
+                let received = Packet::from_flits(packet.to_flits()).unwrap();
+                JoinReplyFragment::from_packet(&received)
+                    .unwrap()
+                    .assemble(&mut receiving)
+            })
+            .collect();
+        assert_eq!(assembled, vec![reply.clone()]);
+
+        reply.routing_parameters = vec![0; u16::MAX as usize + 1];
+        assert!(reply.to_packets(1, 0x10).is_err());
+    }
+
+    #[test]
+    fn test_membership() {
+        let mut protocol = TestProtocol::new();
+        let mut membership = Membership::new();
+
+        let reply = membership.join(&mut protocol, &JoinRequest::new(10, (1, 0)), 0);
+        assert_eq!(reply.status, JoinStatus::Accepted);
+        let ip_address = reply.ip_address;
+
+        // rejoin after reboot
+        let reply = membership.join(&mut protocol, &JoinRequest::new(10, (1, 0)), 0);
+        assert_eq!(reply.status, JoinStatus::Accepted);
+        assert_eq!(reply.ip_address, ip_address);
+        assert_eq!(membership.get_members().len(), 1);
+
+        // another node claims the same coordinate
+        let reply = membership.join(&mut protocol, &JoinRequest::new(11, (1, 0)), 0);
+        assert_eq!(reply.status, JoinStatus::Duplicated);
+
+        // TestProtocol always assigns 0, so the address is duplicated
+        let reply = membership.join(&mut protocol, &JoinRequest::new(11, (2, 0)), 0);
+        assert_eq!(reply.status, JoinStatus::Duplicated);
+
+        assert!(membership.remove(10).is_some());
+        let reply = membership.join(&mut protocol, &JoinRequest::new(11, (1, 0)), 0);
+        assert_eq!(reply.status, JoinStatus::Accepted);
+        assert_eq!(membership.get_members().len(), 1);
+    }
+
+    #[test]
+    fn test_lease() {
+        let mut protocol = TestProtocol::new();
+        let mut membership = Membership::new();
+
+        let reply = membership.join(&mut protocol, &JoinRequest::new(10, (1, 0)), 0);
+        assert_eq!(reply.lease_time, LEASE_TIME);
+        assert!(membership.expire(&mut protocol, LEASE_TIME - 1).is_empty());
+
+        // renewed lease is extended
+        membership.join(&mut protocol, &JoinRequest::new(10, (1, 0)), LEASE_TIME / 2);
+        assert_eq!(
+            membership.get_members()[0].expires_at,
+            LEASE_TIME / 2 + LEASE_TIME
+        );
+        assert!(membership.expire(&mut protocol, LEASE_TIME).is_empty());
+
+        // the tile is removed, so its address is reclaimed
+        let expired = membership.expire(&mut protocol, LEASE_TIME * 2);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].mac_address, 10);
+        assert!(membership.get_members().is_empty());
+        let reply = membership.join(&mut protocol, &JoinRequest::new(11, (1, 0)), LEASE_TIME * 2);
+        assert_eq!(reply.status, JoinStatus::Accepted);
+
+        // rejected request has no lease
+        let reply = membership.join(&mut protocol, &JoinRequest::new(12, (1, 0)), 0);
+        assert_eq!(reply.lease_time, 0);
+    }
+}
@@ -0,0 +1,263 @@
+use std::mem::size_of;
+
+use anyhow::{anyhow, Result};
+
+use crate::header::Header;
+use crate::packet::{Packet, ToId};
+use crate::protocol::Millis;
+use crate::utils::type_alias::{Coordinate, CoordinateComponent, Id};
+
+/// interval of sending a probe to neighbors
+pub const PROBE_INTERVAL: Millis = 2_000;
+/// a neighbor is down when its probe is not received in this time
+pub const NEIGHBOR_TIMEOUT: Millis = PROBE_INTERVAL * 3;
+
+/// x(16) | y(16)
+const COORDINATE_LENGTH: usize = size_of::<CoordinateComponent>() * 2;
+
+/// probe which is sent to neighbors periodically, so that they know this node is alive.
+/// it also tells the neighbors outside the localnet, so that nodes in the localnet can estimate
+/// their coordinates again (see localization.rs).
+/// Data form is like this
+/// [ mac_address(16) | is_located(8) | x(16) | y(16) | (x(16) | y(16) of outside neighbor)* ]
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct NeighborProbe {
+    pub mac_address: Id,
+    /// whether the coordinate is decided. it is false while the localnet is detached.
+    pub is_located: bool,
+    pub coordinate: Coordinate,
+    /// coordinates of located neighbors outside the localnet
+    pub outside_neighbors: Vec<Coordinate>,
+}
+
+impl NeighborProbe {
+    const HEADER_LENGTH: usize = size_of::<Id>() + 1 + COORDINATE_LENGTH;
+
+    pub fn new(mac_address: Id, coordinate: Coordinate) -> Self {
+        Self {
+            mac_address,
+            is_located: true,
+            coordinate,
+            outside_neighbors: Vec::new(),
+        }
+    }
+
+    /// the probe is sent to all neighbors, and it is not relayed.
+    pub fn to_packet(&self, ip_address: Id) -> Result<Packet> {
+        let mut messages = Vec::new();
+        messages.extend(self.mac_address.to_be_bytes());
+        messages.push(self.is_located as u8);
+        for (x, y) in [self.coordinate]
+            .iter()
+            .chain(self.outside_neighbors.iter())
+        {
+            messages.extend(x.to_be_bytes());
+            messages.extend(y.to_be_bytes());
+        }
+        Packet::new(
+            0,
+            Header::ProbeNeighbor,
+            ip_address,
+            ToId::Broadcast,
+            ip_address,
+            ToId::Broadcast,
+            messages,
+        )
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<Self> {
+        if packet.get_header() != Header::ProbeNeighbor {
+            return Err(anyhow!(
+                "This packet is not ProbeNeighbor: {:?}",
+                packet.get_header()
+            ));
+        }
+        let messages = &packet.get_ref_messages()[..packet.get_real_messages_length()];
+        if messages.len() < Self::HEADER_LENGTH {
+            return Err(anyhow!(
+                "length of neighbor probe is not enough: {:?}",
+                messages
+            ));
+        }
+        let mac_address = Id::from_be_bytes([messages[0], messages[1]]);
+        let is_located = messages[2] != 0;
+        let coordinates = messages[3..].chunks_exact(COORDINATE_LENGTH);
+        if !coordinates.remainder().is_empty() {
+            return Err(anyhow!("invalid neighbor probe: {:?}", messages));
+        }
+        let mut coordinates = coordinates.map(|bytes| {
+            (
+                CoordinateComponent::from_be_bytes([bytes[0], bytes[1]]),
+                CoordinateComponent::from_be_bytes([bytes[2], bytes[3]]),
+            )
+        });
+        let coordinate = coordinates.next().expect("checked by HEADER_LENGTH");
+        Ok(Self {
+            mac_address,
+            is_located,
+            coordinate,
+            outside_neighbors: coordinates.collect(),
+        })
+    }
+}
+
+/// node which is directly connected to this node
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Neighbor {
+    /// ip address of the neighbor
+    pub id: Id,
+    pub mac_address: Id,
+    pub is_located: bool,
+    pub coordinate: Coordinate,
+    /// whether the neighbor is in the same localnet as this node
+    pub is_localnet: bool,
+    /// coordinates of located neighbors of the neighbor outside its localnet
+    pub outside_neighbors: Vec<Coordinate>,
+    pub last_heard: Millis,
+    /// whether probes are heard in the recent intervals. the lowest bit is the current one.
+    history: u8,
+}
+
+impl Neighbor {
+    /// percentage of probes which are heard in the recent 8 intervals
+    pub fn get_quality(&self) -> u8 {
+        (self.history.count_ones() * 100 / u8::BITS) as u8
+    }
+}
+
+/// connection to a neighbor is up or down
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum LinkEvent {
+    Up(Neighbor),
+    Down(Neighbor),
+}
+
+/// neighbors which are alive, sorted by ip address
+#[derive(Debug, Default, Clone)]
+pub struct NeighborTable {
+    neighbors: Vec<Neighbor>,
+}
+
+impl NeighborTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_neighbors(&self) -> &[Neighbor] {
+        &self.neighbors
+    }
+
+    pub fn get(&self, id: Id) -> Option<&Neighbor> {
+        self.neighbors.iter().find(|neighbor| neighbor.id == id)
+    }
+
+    /// the probe is received from the neighbor.
+    /// return Up if the neighbor is new.
+    pub fn hear(
+        &mut self,
+        id: Id,
+        probe: NeighborProbe,
+        is_localnet: bool,
+        now: Millis,
+    ) -> Option<LinkEvent> {
+        if let Some(neighbor) = self.neighbors.iter_mut().find(|neighbor| neighbor.id == id) {
+            neighbor.mac_address = probe.mac_address;
+            neighbor.is_located = probe.is_located;
+            neighbor.coordinate = probe.coordinate;
+            neighbor.is_localnet = is_localnet;
+            neighbor.outside_neighbors = probe.outside_neighbors;
+            neighbor.last_heard = now;
+            neighbor.history |= 1;
+            return None;
+        }
+        let neighbor = Neighbor {
+            id,
+            mac_address: probe.mac_address,
+            is_located: probe.is_located,
+            coordinate: probe.coordinate,
+            is_localnet,
+            outside_neighbors: probe.outside_neighbors,
+            last_heard: now,
+            history: 1,
+        };
+        let index = self.neighbors.partition_point(|known| known.id < id);
+        self.neighbors.insert(index, neighbor.clone());
+        Some(LinkEvent::Up(neighbor))
+    }
+
+    /// the next interval of probes begins. it is called when this node sends its probe.
+    pub fn tick(&mut self) {
+        for neighbor in self.neighbors.iter_mut() {
+            neighbor.history <<= 1;
+        }
+    }
+
+    /// remove neighbors which are not heard in NEIGHBOR_TIMEOUT, and return Down of them.
+    pub fn expire(&mut self, now: Millis) -> Vec<LinkEvent> {
+        let mut events = Vec::new();
+        self.neighbors.retain(|neighbor| {
+            let is_alive = now.saturating_sub(neighbor.last_heard) <= NEIGHBOR_TIMEOUT;
+            if !is_alive {
+                events.push(LinkEvent::Down(neighbor.clone()));
+            }
+            is_alive
+        });
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_neighbor_probe() {
+        let mut probe = NeighborProbe::new(0x1234, (-3, 5));
+        probe.is_located = false;
+        probe.outside_neighbors = vec![(-4, 5), (-3, 6)];
+        let packet = probe.to_packet(7).unwrap();
+        assert_eq!(packet.get_from(), 7);
+        assert_eq!(packet.get_to(), ToId::Broadcast);
+        assert_eq!(NeighborProbe::from_packet(&packet).unwrap(), probe);
+        let packet = Packet::new(
+            0,
+            Header::Data,
+            7,
+            ToId::Broadcast,
+            7,
+            ToId::Broadcast,
+            vec![],
+        )
+        .unwrap();
+        assert!(NeighborProbe::from_packet(&packet).is_err());
+    }
+
+    #[test]
+    fn test_neighbor_table() {
+        let mut table = NeighborTable::new();
+        let probe = NeighborProbe::new(0x10, (1, 0));
+        let event = table.hear(5, probe.clone(), false, 0);
+        assert!(matches!(event, Some(LinkEvent::Up(Neighbor { id: 5, .. }))));
+        assert_eq!(table.hear(5, probe.clone(), false, 100), None);
+        table.hear(3, NeighborProbe::new(0x11, (0, 1)), true, 100);
+        let ids: Vec<Id> = table.get_neighbors().iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![3, 5]);
+        assert!(table.get(3).unwrap().is_localnet);
+
+        // 5 is heard in every interval, and 3 is heard only once
+        for i in 1..8 {
+            table.tick();
+            table.hear(5, probe.clone(), false, i * PROBE_INTERVAL);
+        }
+        assert_eq!(table.get(5).unwrap().get_quality(), 100);
+        assert_eq!(table.get(3).unwrap().get_quality(), 12);
+
+        let events = table.expire(100 + NEIGHBOR_TIMEOUT + 1);
+        assert!(matches!(
+            events[..],
+            [LinkEvent::Down(Neighbor { id: 3, .. })]
+        ));
+        assert!(table.get(3).is_none());
+        assert!(table.get(5).is_some());
+    }
+}
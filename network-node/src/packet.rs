@@ -1,3 +1,4 @@
+use std::fmt;
 use std::mem::size_of;
 
 use crate::localnet::LocalNetworkLocation;
@@ -8,18 +9,126 @@ use crate::utils::util::{
 
 use super::flit::{Flit, FlitType, MAX_FLIT_LENGTH};
 use super::header::Header;
+use crate::protocol::{ChannelId, RoutingTablePage, CHANNEL_LENGTH};
+use crate::service::ServiceId;
 use crate::utils::type_alias::{Coordinate, CoordinateComponent, Id};
 use anyhow::{anyhow, Result};
+use num_enum::TryFromPrimitive;
 
 type FromId = Id;
+/// packet id is 6 bits in head flit
 pub type PacketId = u8;
+pub const MAX_PACKET_ID: PacketId = 0x3F;
+
+/// the number of hops which a packet can take more.
+/// it is carried in first body flit, so packets which have only head flit don't have it.
+pub type Ttl = u8;
+/// ttl of a new packet
+pub const DEFAULT_TTL: Ttl = Ttl::MAX;
+
+/// max length of messages in a packet.
+/// head flit, first body flit, and eof are needed besides messages.
+/// a packet to a group also carries parameters of the group (up to 8 bytes) before messages,
+/// so its messages must be shorter by the parameters (see Packet::new).
+pub const MAX_MESSAGES_LENGTH: usize = (MAX_FLIT_LENGTH as usize - 3) * 6 - 1;
+
+/// error code which is carried by Error packet
+#[derive(TryFromPrimitive, Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// checksum of packet is not correct
+    Checksum,
+    /// flits cannot be reassembled into packet
+    Reassembly,
+    /// there is no route to the destination
+    Unreachable,
+    /// a node on the route cannot buffer the packet
+    Congestion,
+    /// ttl of the packet becomes zero, e.g. the packet is in a routing loop
+    TtlExpired,
+}
+
+impl ErrorCode {
+    /// whether the sender should resend the packet
+    pub fn is_resendable(&self) -> bool {
+        match self {
+            ErrorCode::Checksum | ErrorCode::Reassembly | ErrorCode::Congestion => true,
+            ErrorCode::Unreachable | ErrorCode::TtlExpired => false,
+        }
+    }
+}
+
+/// error of a packet, which is reported to the original source by Error packet.
+/// Error packet data is like this [ error_code(8) | packet_id(8) | original_source(16) ]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct PacketError {
+    pub code: ErrorCode,
+    pub packet_id: PacketId,
+    pub source: Id,
+}
+
+impl PacketError {
+    pub fn new(code: ErrorCode, packet_id: PacketId, source: Id) -> Self {
+        Self {
+            code,
+            packet_id,
+            source,
+        }
+    }
+    pub fn to_messages(&self) -> Vec<u8> {
+        let source = self.source.to_be_bytes();
+        vec![self.code as u8, self.packet_id, source[0], source[1]]
+    }
+    pub fn from_messages(messages: &[u8]) -> Result<Self> {
+        if messages.len() < 4 {
+            return Err(anyhow!(
+                "length of error packet is not enough: {:?}",
+                messages
+            ));
+        }
+        let code = ErrorCode::try_from(messages[0])?;
+        let source = Id::from_be_bytes([messages[2], messages[3]]);
+        Ok(Self::new(code, messages[1], source))
+    }
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} error in packet {} from {}",
+            self.code, self.packet_id, self.source
+        )
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+/// ids from this value are reserved for groups and broadcast, so unicast ids must be less than it.
+pub const GROUP_ID_BASE: Id = 0xFF00;
+const LOCALNET_GROUP_ID: Id = GROUP_ID_BASE | 1;
+const ROW_GROUP_ID: Id = GROUP_ID_BASE | 2;
+const COLUMN_GROUP_ID: Id = GROUP_ID_BASE | 3;
+const RECTANGLE_GROUP_ID: Id = GROUP_ID_BASE | 4;
+const ANYCAST_ID: Id = GROUP_ID_BASE | 5;
 
 // broadcast is represented by 0xFFFF
-// localnet is only used when making localnet
+// groups (including localnet) and anycast are represented by reserved ids, and their parameters
+// are carried at the head of data.
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum ToId {
     Unicast(Id),
     Broadcast,
+    /// all nodes in the localnet whose down left node is at the coordinate
+    Localnet(Coordinate),
+    /// all nodes whose y coordinate is the value
+    Row(CoordinateComponent),
+    /// all nodes whose x coordinate is the value
+    Column(CoordinateComponent),
+    /// all nodes in the rectangle from min to max (both inclusive)
+    Rectangle(Coordinate, Coordinate),
+    /// the nearest node which provides the service
+    Anycast(ServiceId),
 }
 
 impl ToId {
@@ -28,8 +137,15 @@ impl ToId {
         match self {
             ToId::Unicast(id) => *id,
             ToId::Broadcast => 0xFFFF,
+            ToId::Localnet(_) => LOCALNET_GROUP_ID,
+            ToId::Row(_) => ROW_GROUP_ID,
+            ToId::Column(_) => COLUMN_GROUP_ID,
+            ToId::Rectangle(_, _) => RECTANGLE_GROUP_ID,
+            ToId::Anycast(_) => ANYCAST_ID,
         }
     }
+    /// destination in head flit, which is a neighbor or Broadcast.
+    /// groups need their parameters, so they are loaded by from_id_and_parameters.
     pub fn from_id(id: Id) -> Self {
         // todo
         match id {
@@ -37,9 +153,78 @@ impl ToId {
             _ => ToId::Unicast(id),
         }
     }
+
+    /// whether the destination is a group of nodes
+    pub fn is_group(&self) -> bool {
+        !matches!(self, ToId::Unicast(_) | ToId::Broadcast | ToId::Anycast(_))
+    }
+    /// whether the node at the coordinate is in the group.
+    /// it is false for Unicast, Broadcast and Anycast, because they don't depend on coordinates.
+    pub fn contains(&self, coordinate: Coordinate) -> bool {
+        let (x, y) = coordinate;
+        match *self {
+            ToId::Unicast(_) | ToId::Broadcast | ToId::Anycast(_) => false,
+            ToId::Localnet((min_x, min_y)) => {
+                (min_x..=min_x.saturating_add(1)).contains(&x)
+                    && (min_y..=min_y.saturating_add(1)).contains(&y)
+            }
+            ToId::Row(row) => y == row,
+            ToId::Column(column) => x == column,
+            ToId::Rectangle((min_x, min_y), (max_x, max_y)) => {
+                (min_x..=max_x).contains(&x) && (min_y..=max_y).contains(&y)
+            }
+        }
+    }
+
+    /// parameters of the group, which are carried at the head of data
+    fn to_parameters(self) -> Vec<u8> {
+        let components = match self {
+            ToId::Anycast(service) => return service.to_be_bytes().to_vec(),
+            ToId::Unicast(_) | ToId::Broadcast => vec![],
+            ToId::Localnet((x, y)) => vec![x, y],
+            ToId::Row(row) => vec![row],
+            ToId::Column(column) => vec![column],
+            ToId::Rectangle((min_x, min_y), (max_x, max_y)) => vec![min_x, min_y, max_x, max_y],
+        };
+        components
+            .into_iter()
+            .flat_map(|component| component.to_be_bytes())
+            .collect()
+    }
+    /// load the destination from id and data of a packet.
+    /// return the destination and the length of its parameters.
+    fn from_id_and_parameters(id: Id, data: &[u8]) -> Result<(Self, usize)> {
+        let length = match id {
+            LOCALNET_GROUP_ID => 2,
+            ROW_GROUP_ID | COLUMN_GROUP_ID => 1,
+            RECTANGLE_GROUP_ID => 4,
+            ANYCAST_ID => 1,
+            _ => return Ok((Self::from_id(id), 0)),
+        };
+        let size = size_of::<CoordinateComponent>();
+        if data.len() < length * size {
+            return Err(anyhow!("parameters of group {:x} are too short", id));
+        }
+        let components: Vec<CoordinateComponent> = data[..length * size]
+            .chunks(size)
+            .map(|bytes| CoordinateComponent::from_be_bytes([bytes[0], bytes[1]]))
+            .collect();
+        let to = match id {
+            LOCALNET_GROUP_ID => ToId::Localnet((components[0], components[1])),
+            ROW_GROUP_ID => ToId::Row(components[0]),
+            COLUMN_GROUP_ID => ToId::Column(components[0]),
+            // service id has the same size as a component
+            ANYCAST_ID => ToId::Anycast(ServiceId::from_be_bytes(components[0].to_be_bytes())),
+            _ => ToId::Rectangle(
+                (components[0], components[1]),
+                (components[2], components[3]),
+            ),
+        };
+        Ok((to, length * size))
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Packet {
     packet_id: PacketId,
     header: Header,
@@ -51,6 +236,9 @@ pub struct Packet {
     /// last 2 items represent checksum
     checksum: u8,
     length_of_flit: usize,
+    /// virtual channel, which is carried in head flit
+    channel: ChannelId,
+    ttl: Ttl,
 }
 
 /// core functions
@@ -70,19 +258,46 @@ impl Packet {
             None => return Ok(None),
         };
 
-        let (length_of_flit, _, src, _, _) = Flit::get_head_information(&flit)?;
+        let (length_of_flit, header, src, _, packet_id) = Flit::get_head_information(&flit)?;
         if src == this_id {
             return Ok(None);
         }
 
         flits.push(flit);
 
+        // global source is carried by first body flit, so it is unknown until it arrives
+        let mut global_source = None;
         for _ in 1..length_of_flit {
-            flits.push(Flit::wait_receive(serial)?);
+            let flit = Flit::wait_receive(serial).map_err(|e| {
+                Self::make_error(header, ErrorCode::Reassembly, packet_id, global_source, e)
+            })?;
+            if flits.len() == 1 {
+                global_source = Self::load_first_message(flit)
+                    .ok()
+                    .map(|(_, _, global_source, _)| global_source);
+            }
+            flits.push(flit);
         }
         Ok(Some(Self::from_flits(flits)?))
     }
 
+    /// make error which is reported by Error packet to the global source.
+    /// the error of Error packet is not reported, otherwise errors may be sent each other forever.
+    /// the error is not reported either if the global source is unknown.
+    fn make_error(
+        header: Header,
+        code: ErrorCode,
+        packet_id: PacketId,
+        source: Option<Id>,
+        cause: impl fmt::Display,
+    ) -> anyhow::Error {
+        let source = match source {
+            Some(source) if header != Header::Error && !header.is_only_head() => source,
+            _ => return anyhow!("{:?} error in packet {}: {}", code, packet_id, cause),
+        };
+        anyhow::Error::new(PacketError::new(code, packet_id, source)).context(cause.to_string())
+    }
+
     pub fn new(
         packet_id: PacketId,
         header: Header,
@@ -91,10 +306,22 @@ impl Packet {
         from: FromId,
         to: ToId,
         mut messages: Vec<u8>,
-    ) -> Self {
+    ) -> Result<Self> {
         // checksum is 2 bytes and packet id is 2 bytes
 
-        let length_of_flit = Self::calculate_length_of_flit(header, &messages);
+        // parameters of group are sent before messages
+        let parameters = global_to.to_parameters();
+        if parameters.len() + messages.len() > MAX_MESSAGES_LENGTH {
+            return Err(anyhow!(
+                "messages of {:?} to {:?} are too long: {} bytes and {} bytes of parameters",
+                header,
+                global_to,
+                messages.len(),
+                parameters.len()
+            ));
+        }
+        let length_of_flit =
+            Self::calculate_length_of_flit(header, &[parameters.as_slice(), &messages].concat());
 
         // end of file
         if !header.is_only_head() {
@@ -104,10 +331,10 @@ impl Packet {
             }
         }
 
-        let checksum = Self::calculate_checksum(&messages);
+        let checksum = Self::calculate_checksum(&[parameters, messages.clone()].concat());
 
-        Self {
-            packet_id,
+        Ok(Self {
+            packet_id: packet_id & MAX_PACKET_ID,
             header,
             from,
             to,
@@ -116,7 +343,9 @@ impl Packet {
             messages,
             checksum,
             length_of_flit,
-        }
+            channel: 0,
+            ttl: DEFAULT_TTL,
+        })
     }
 
     fn calculate_length_of_flit(header: Header, messages: &Vec<u8>) -> usize {
@@ -147,13 +376,15 @@ impl Packet {
         //
         //
 
-        let mut flits = vec![Flit::make_head_flit(
+        let mut head_flit = Flit::make_head_flit(
             self.length_of_flit as u8,
             self.header,
             self.from,
             self.to.to_id(),
             self.packet_id,
-        )];
+        );
+        head_flit.set_channel(self.channel);
+        let mut flits = vec![head_flit];
 
         if self.header.is_only_head() {
             return flits;
@@ -174,10 +405,11 @@ impl Packet {
 
         // add message
 
+        let messages = [self.global_to.to_parameters(), self.messages.clone()].concat();
         for i in 0..self.length_of_flit - 2 {
             flit_id += 1;
             for j in 0..6 {
-                data[j] = self.messages[i * 6 + j]
+                data[j] = messages[i * 6 + j]
             }
             let body_flit = Flit::make_body_flit(flit_id, data);
             flits.push(body_flit);
@@ -197,7 +429,7 @@ impl Packet {
     fn make_first_message(&self) -> [u8; 6] {
         let mut data: [u8; 6] = [0; 6];
 
-        data[0] = self.packet_id;
+        data[0] = self.ttl;
         data[1] = self.checksum;
         let ids = self.global_to.to_id().to_be_bytes();
         data[2] = ids[0];
@@ -207,13 +439,13 @@ impl Packet {
         data[5] = ids[1];
         return data;
     }
-    fn load_first_message(flit: Flit) -> Result<(PacketId, u8, Id, Id)> {
+    fn load_first_message(flit: Flit) -> Result<(Ttl, u8, Id, Id)> {
         let (_flittype, _flit_id, data) = Flit::get_body_or_tail_information(&flit)?;
-        let packet_id = data[0];
+        let ttl = data[0];
         let checksum = data[1];
         let to = Id::from_be_bytes([data[2], data[3]]);
         let from = Id::from_be_bytes([data[4], data[5]]);
-        Ok((packet_id, checksum, from, to))
+        Ok((ttl, checksum, from, to))
     }
 
     pub fn from_flits(flits: Vec<Flit>) -> Result<Packet> {
@@ -223,10 +455,13 @@ impl Packet {
         let (length_of_flit, header, from, to, packet_id) = Flit::get_head_information(&flits[0])?;
         let to = ToId::from_id(to);
         let length_of_flit = length_of_flit.into();
+        let channel = flits[0].get_channel();
 
         if header.is_only_head() {
             // global_from and global_to is the same as from and to
-            return Ok(Self::new(packet_id, header, from, to, from, to, Vec::new()));
+            let mut packet = Self::new(packet_id, header, from, to, from, to, Vec::new())?;
+            packet.channel = channel;
+            return Ok(packet);
         }
 
         // general packet has at least 2 flits
@@ -234,21 +469,31 @@ impl Packet {
             return Err(anyhow!("The length of flits is not enough."));
         }
 
-        let (packet_id, checksum, global_source, global_destination) =
-            Self::load_first_message(flits[1])?;
+        let (ttl, checksum, global_source, global_destination) = Self::load_first_message(flits[1])
+            .map_err(|e| Self::make_error(header, ErrorCode::Reassembly, packet_id, None, e))?;
+        let reassembly_error = |message: &str| {
+            Self::make_error(
+                header,
+                ErrorCode::Reassembly,
+                packet_id,
+                Some(global_source),
+                message,
+            )
+        };
 
         let mut data = Vec::new();
 
         for i in 2..length_of_flit {
-            let (flittype, flit_id, message) = Flit::get_body_or_tail_information(&flits[i])?;
+            let (flittype, flit_id, message) = Flit::get_body_or_tail_information(&flits[i])
+                .map_err(|e| reassembly_error(&e.to_string()))?;
             if flit_id as usize != i {
                 #[cfg(test)]
                 assert_eq!(flit_id as usize, i, "The flit id is not correct.");
-                return Err(anyhow!("The flit id is not correct."));
+                return Err(reassembly_error("The flit id is not correct."));
             }
 
             if flittype == FlitType::Tail && i != length_of_flit - 1 {
-                return Err(anyhow!("The flit is not last but Tail."));
+                return Err(reassembly_error("The flit is not last but Tail."));
             }
 
             for j in message {
@@ -263,15 +508,23 @@ impl Packet {
         if Self::check_checksum(&data, checksum) {
             // remove end of message
             data.pop();
-            Ok(Self::new(
+            let (global_destination, parameters_length) =
+                ToId::from_id_and_parameters(global_destination, &data)
+                    .map_err(|e| reassembly_error(&e.to_string()))?;
+            data.drain(..parameters_length);
+            let mut packet = Self::new(
                 packet_id,
                 header,
                 global_source,
-                ToId::from_id(global_destination),
+                global_destination,
                 from,
                 to,
                 data,
-            ))
+            )
+            .map_err(|e| reassembly_error(&e.to_string()))?;
+            packet.channel = channel;
+            packet.ttl = ttl;
+            Ok(packet)
         } else {
             #[cfg(test)]
             assert_eq!(
@@ -280,10 +533,16 @@ impl Packet {
                 "Checksum is not correct: data: {:?}",
                 data
             );
-            Err(anyhow!(
-                "Checksum is not correct: {:x}, {:x}",
-                checksum,
-                Self::calculate_checksum(&data)
+            Err(Self::make_error(
+                header,
+                ErrorCode::Checksum,
+                packet_id,
+                Some(global_source),
+                format!(
+                    "Checksum is not correct: {:x}, {:x}",
+                    checksum,
+                    Self::calculate_checksum(&data)
+                ),
             ))
         }
     }
@@ -305,12 +564,50 @@ impl Packet {
         length
     }
 
+    /// load error information from Error packet
+    pub fn load_error_packet(&self) -> Result<PacketError> {
+        if self.header != Header::Error {
+            return Err(anyhow!("This packet is not Error packet: {:?}", self.header));
+        }
+        PacketError::from_messages(&self.messages)
+    }
+
+    /// load a page of routing table from ReplyRoutingTable packet
+    pub fn load_routing_table_packet(&self) -> Result<RoutingTablePage> {
+        if self.header != Header::ReplyRoutingTable {
+            return Err(anyhow!(
+                "This packet is not ReplyRoutingTable packet: {:?}",
+                self.header
+            ));
+        }
+        RoutingTablePage::from_messages(&self.messages)
+    }
+
     // ///////////////////////////////
     // getter
     // ///////////////////////////////
     pub fn get_packet_id(&self) -> PacketId {
         self.packet_id
     }
+    pub fn get_channel(&self) -> ChannelId {
+        self.channel
+    }
+    pub fn set_channel(&mut self, channel: ChannelId) {
+        debug_assert!((channel as usize) < CHANNEL_LENGTH);
+        self.channel = channel;
+    }
+    pub fn get_ttl(&self) -> Ttl {
+        self.ttl
+    }
+    pub fn set_ttl(&mut self, ttl: Ttl) {
+        self.ttl = ttl;
+    }
+    /// decrement ttl when the packet is forwarded.
+    /// return false if ttl becomes zero, which means the packet must be dropped.
+    pub fn decrement_ttl(&mut self) -> bool {
+        self.ttl = self.ttl.saturating_sub(1);
+        self.ttl > 0
+    }
     pub fn get_header(&self) -> Header {
         self.header
     }
@@ -419,7 +716,7 @@ impl Packet {
             source,
             ToId::Broadcast,
             Vec::new(),
-        );
+        )?;
         Ok(packet)
     }
 
@@ -465,7 +762,8 @@ impl Packet {
             global_from,
             global_to,
             messages,
-        );
+        )
+        .ok()?;
         Some(packet)
     }
 
@@ -537,7 +835,7 @@ impl Packet {
             messages.append(&mut y.to_be_bytes().to_vec());
         }
 
-        Some(Self::new(
+        Self::new(
             packet_id,
             header,
             from,
@@ -545,7 +843,8 @@ impl Packet {
             global_from,
             global_to,
             messages,
-        ))
+        )
+        .ok()
     }
 
     fn make_confirm_coordinate_packet_to_different_localnet_by_confirmed_node(
@@ -571,7 +870,7 @@ impl Packet {
         let global_from = source;
         let global_to = ToId::Broadcast;
 
-        Some(Self::new(
+        Self::new(
             packet_id,
             header,
             from,
@@ -579,7 +878,8 @@ impl Packet {
             global_from,
             global_to,
             messages,
-        ))
+        )
+        .ok()
     }
     /// make broudcast packet
     pub fn make_request_confirmed_coordinate_packet(source: Id) -> Packet {
@@ -600,6 +900,7 @@ impl Packet {
             global_to,
             messages,
         )
+        .expect("only head flit has no messages")
     }
 }
 
@@ -622,7 +923,8 @@ mod test {
             0,
             ToId::Broadcast,
             packet_data,
-        );
+        )
+        .unwrap();
         fn packet_test(packet: Packet, checksum: u8) {
             println!("packet: {:?}", packet);
             assert_eq!(packet.checksum, checksum);
@@ -660,7 +962,8 @@ mod test {
             2,
             ToId::Unicast(1),
             packet_data,
-        );
+        )
+        .unwrap();
         packet_test(packet, 0);
 
         // third(meaning less but test)
@@ -673,7 +976,8 @@ mod test {
             4,
             ToId::Unicast(4),
             packet_data,
-        );
+        )
+        .unwrap();
         packet_test(packet, 186_u8.wrapping_add(255_u8));
     }
 
@@ -688,7 +992,8 @@ mod test {
             4,
             ToId::Broadcast,
             packet_data,
-        );
+        )
+        .unwrap();
 
         let flits = packet.to_flits();
         let result = Packet::from_flits(flits);
@@ -706,25 +1011,121 @@ mod test {
             0,
             ToId::Broadcast,
             packet_data,
-        );
+        )
+        .unwrap();
         let bytes = packet.make_first_message();
         let flit = Flit::make_body_flit(1, bytes);
         println!("bytes: {:?}", bytes);
         println!("flit: {:064b}", u64::from_be_bytes(flit.to_be_bytes()));
-        assert_eq!(bytes[0], 0b00000000, "packet id is not correct");
+        assert_eq!(bytes[0], DEFAULT_TTL, "ttl is not correct");
         assert_eq!(bytes[1], 28_u8.wrapping_add(255), "checksum is not correct");
         assert_eq!(bytes[2], 0xFF, "to id is not correct");
         assert_eq!(bytes[3], 0xFF, "to id is not correct");
         assert_eq!(bytes[4], 0x0, "from id is not correct");
         assert_eq!(bytes[5], 0x0, "from id is not correct");
 
-        let (packet_id, checksum, from, to) = Packet::load_first_message(flit).unwrap();
-        assert_eq!(packet_id, 0, "packet id is not correct");
+        let (ttl, checksum, from, to) = Packet::load_first_message(flit).unwrap();
+        assert_eq!(ttl, DEFAULT_TTL, "ttl is not correct");
         assert_eq!(checksum, 28_u8.wrapping_add(255), "checksum is not correct");
         assert_eq!(from, 0, "from id is not correct");
         assert_eq!(to, 0xFFFF, "to id is not correct");
     }
 
+    #[test]
+    fn test_ttl() {
+        let mut packet = Packet::new(
+            5,
+            Header::Data,
+            1,
+            ToId::Unicast(3),
+            2,
+            ToId::Unicast(4),
+            vec![1, 2, 3],
+        )
+        .unwrap();
+        packet.set_ttl(2);
+        assert!(packet.decrement_ttl());
+        let received = Packet::from_flits(packet.to_flits()).unwrap();
+        assert_eq!(received.get_ttl(), 1);
+        assert_eq!(received.get_packet_id(), 5);
+
+        let mut packet = received;
+        assert!(!packet.decrement_ttl());
+        assert!(!packet.decrement_ttl());
+        assert_eq!(packet.get_ttl(), 0);
+        assert!(!ErrorCode::TtlExpired.is_resendable());
+    }
+
+    #[test]
+    fn test_group() {
+        let groups = [
+            ToId::Localnet((2, -4)),
+            ToId::Row(-3),
+            ToId::Column(7),
+            ToId::Rectangle((-1, 0), (3, 2)),
+        ];
+        for group in groups {
+            assert!(group.is_group());
+            for messages in [vec![], vec![1, 2, 3, 4, 5, 6, 7, 8]] {
+                let packet = Packet::new(
+                    9,
+                    Header::Data,
+                    1,
+                    group,
+                    1,
+                    ToId::Broadcast,
+                    messages.clone(),
+                )
+                .unwrap();
+                let received = Packet::from_flits(packet.to_flits()).unwrap();
+                assert_eq!(received.get_global_to(), group);
+                assert_eq!(
+                    received.get_ref_messages()[..received.get_real_messages_length()],
+                    messages
+                );
+                assert_eq!(received, packet);
+            }
+        }
+        assert!(!ToId::Broadcast.is_group());
+
+        assert!(ToId::Localnet((2, -4)).contains((3, -3)));
+        assert!(!ToId::Localnet((2, -4)).contains((4, -3)));
+        assert!(ToId::Row(-3).contains((100, -3)));
+        assert!(!ToId::Column(7).contains((6, 7)));
+        assert!(ToId::Rectangle((-1, 0), (3, 2)).contains((-1, 2)));
+        assert!(!ToId::Rectangle((-1, 0), (3, 2)).contains((0, 3)));
+        assert!(!ToId::Unicast(0).contains((0, 0)));
+    }
+
+    #[test]
+    fn test_too_long_messages() {
+        let make = |to: ToId, length: usize| {
+            Packet::new(0, Header::Data, 1, to, 1, ToId::Broadcast, vec![1; length])
+        };
+        let packet = make(ToId::Broadcast, MAX_MESSAGES_LENGTH).unwrap();
+        assert!(packet.length_of_flit < MAX_FLIT_LENGTH as usize);
+        assert!(make(ToId::Broadcast, MAX_MESSAGES_LENGTH + 1).is_err());
+        // parameters of the group take 8 bytes
+        let group = ToId::Rectangle((0, 0), (1, 1));
+        assert!(make(group, MAX_MESSAGES_LENGTH - 8).is_ok());
+        assert!(make(group, MAX_MESSAGES_LENGTH - 7).is_err());
+    }
+
+    #[test]
+    fn test_anycast() {
+        // anycast is routed to a provider, so it is not flooded as a group
+        let to = ToId::Anycast(0xABCD);
+        assert!(!to.is_group());
+        assert!(!to.contains((0, 0)));
+        let packet = Packet::new(9, Header::Data, 1, to, 1, ToId::Unicast(2), vec![7, 8]).unwrap();
+        let received = Packet::from_flits(packet.to_flits()).unwrap();
+        assert_eq!(received.get_global_to(), to);
+        assert_eq!(
+            received.get_ref_messages()[..received.get_real_messages_length()],
+            [7, 8]
+        );
+    }
+
     #[test]
     fn test_checksum() {
         let data = vec![0, 1, 2, 3, 4, 5, 6, 7, 8];
@@ -744,7 +1145,8 @@ mod test {
             5,
             ToId::Broadcast,
             packet_data,
-        );
+        )
+        .unwrap();
         packet.send(&mut serial).expect("failed to send packet");
 
         let received = match Packet::receive(&mut serial, 4).expect("failed to receive packet") {
@@ -776,6 +1178,80 @@ mod test {
         assert_eq!(messages[0], (3, coordinate));
     }
 
+    #[test]
+    fn test_error_packet() {
+        let error = PacketError::new(ErrorCode::Checksum, 12, 0x1234);
+        let packet = Packet::new(
+            3,
+            Header::Error,
+            5,
+            ToId::Unicast(0x1234),
+            5,
+            ToId::Unicast(6),
+            error.to_messages(),
+        )
+        .unwrap();
+        let flits = packet.to_flits();
+        let received = Packet::from_flits(flits).unwrap();
+        assert_eq!(received.load_error_packet().unwrap(), error);
+        assert!(error.code.is_resendable());
+        assert!(!ErrorCode::Unreachable.is_resendable());
+
+        let packet = Packet::new(
+            3,
+            Header::Data,
+            5,
+            ToId::Unicast(0x1234),
+            5,
+            ToId::Unicast(6),
+            error.to_messages(),
+        )
+        .unwrap();
+        assert!(packet.load_error_packet().is_err());
+    }
+
+    #[test]
+    fn test_make_error() {
+        let error = Packet::make_error(Header::Data, ErrorCode::Reassembly, 4, Some(9), "timeout");
+        assert_eq!(
+            error.downcast_ref::<PacketError>(),
+            Some(&PacketError::new(ErrorCode::Reassembly, 4, 9))
+        );
+        // errors of Error packet are not reported.
+        let error = Packet::make_error(Header::Error, ErrorCode::Reassembly, 4, Some(9), "timeout");
+        assert!(error.downcast_ref::<PacketError>().is_none());
+        // nor errors whose global source is unknown
+        let error = Packet::make_error(Header::Data, ErrorCode::Reassembly, 4, None, "timeout");
+        assert!(error.downcast_ref::<PacketError>().is_none());
+    }
+
+    #[test]
+    fn test_reassembly_error_source() {
+        // Routing header doesn't require ack, so the test serial doesn't get ack flits
+        let packet = Packet::new(
+            3,
+            Header::Routing,
+            0x1234,
+            ToId::Unicast(6),
+            5,
+            ToId::Unicast(6),
+            vec![1; 10],
+        )
+        .unwrap();
+        let mut serial = TestSerial::new();
+        let flits = packet.to_flits();
+        // the tail flit is lost. the test serial pops the last flit first.
+        for flit in flits[..flits.len() - 1].iter().rev() {
+            serial.data.push(flit.to_be_bytes());
+        }
+        let error = Packet::receive(&mut serial, 6).unwrap_err();
+        // the error is reported to the global source, not to the previous node
+        assert_eq!(
+            error.downcast_ref::<PacketError>(),
+            Some(&PacketError::new(ErrorCode::Reassembly, 3, 0x1234))
+        );
+    }
+
     #[test]
     fn test_make_localnet_coordinates() {
         let mut id = 0;
@@ -1,14 +1,195 @@
-use anyhow::Result;
+use std::fmt;
+use std::mem::size_of;
 
+use anyhow::{anyhow, Result};
+
+use crate::packet::{ToId, MAX_MESSAGES_LENGTH};
 use crate::utils::type_alias::{Coordinate, Id};
 
 pub type ChannelId = u8;
+/// the number of virtual channels, which are carried by 2 bits in head flit
+pub const CHANNEL_LENGTH: usize = 4;
+/// milliseconds from the start of NetworkNode
+pub type Millis = u64;
+
+/// message of dynamic routing protocol, which is sent to neighbors by Routing packet.
+/// `to` is a neighbor or Broadcast (all neighbors).
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct RoutingMessage {
+    pub to: ToId,
+    pub messages: Vec<u8>,
+}
+
+/// entry of routing table, which is listed for diagnosis.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct RouteEntry {
+    pub destination: Id,
+    pub next_hop: Id,
+    /// cost of the route. its unit depends on the protocol, e.g. hops.
+    pub metric: u16,
+    pub channel: ChannelId,
+    /// time since the route was learned or refreshed
+    pub age: Millis,
+}
+
+/// destination(16) | next_hop(16) | metric(16) | channel(8) | age in seconds(16)
+pub const ROUTE_ENTRY_LENGTH: usize =
+    size_of::<Id>() * 2 + size_of::<u16>() + size_of::<ChannelId>() + size_of::<u16>();
+
+impl RouteEntry {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let age = (self.age / 1000).min(u16::MAX as Millis) as u16;
+        let mut bytes = Vec::with_capacity(ROUTE_ENTRY_LENGTH);
+        bytes.extend(self.destination.to_be_bytes());
+        bytes.extend(self.next_hop.to_be_bytes());
+        bytes.extend(self.metric.to_be_bytes());
+        bytes.push(self.channel);
+        bytes.extend(age.to_be_bytes());
+        bytes
+    }
+    /// age is rounded down to seconds.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < ROUTE_ENTRY_LENGTH {
+            return Err(anyhow!("length of route entry is not enough: {:?}", bytes));
+        }
+        Ok(Self {
+            destination: Id::from_be_bytes([bytes[0], bytes[1]]),
+            next_hop: Id::from_be_bytes([bytes[2], bytes[3]]),
+            metric: u16::from_be_bytes([bytes[4], bytes[5]]),
+            channel: bytes[6],
+            age: u16::from_be_bytes([bytes[7], bytes[8]]) as Millis * 1000,
+        })
+    }
+}
+
+/// one line which fits the display: destination>next_hop metric channel age
+impl fmt::Display for RouteEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:5}>{:<5}{:3} {} {}s",
+            self.destination,
+            self.next_hop,
+            self.metric,
+            self.channel,
+            self.age / 1000
+        )
+    }
+}
+
+/// part of routing table which is carried by a packet.
+/// data is like this [ total(16) | start(16) | length(8) | entries ... ]
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct RoutingTablePage {
+    /// the number of entries in the whole table
+    pub total: u16,
+    /// index of the first entry of this page in the table
+    pub start: u16,
+    pub entries: Vec<RouteEntry>,
+}
+
+impl RoutingTablePage {
+    /// the number of entries in a packet
+    pub const MAX_ENTRIES: usize = (MAX_MESSAGES_LENGTH - 5) / ROUTE_ENTRY_LENGTH;
+
+    /// take the page which begins at start from the table
+    pub fn new(table: &[RouteEntry], start: u16) -> Self {
+        let entries = table
+            .iter()
+            .skip(start as usize)
+            .take(Self::MAX_ENTRIES)
+            .copied()
+            .collect();
+        Self {
+            total: table.len().min(u16::MAX as usize) as u16,
+            start,
+            entries,
+        }
+    }
+    /// start of the next page, if the table has more entries
+    pub fn get_next_start(&self) -> Option<u16> {
+        let next = self.start as usize + self.entries.len();
+        (!self.entries.is_empty() && next < self.total as usize).then_some(next as u16)
+    }
+    pub fn to_messages(&self) -> Vec<u8> {
+        let mut messages = Vec::with_capacity(5 + self.entries.len() * ROUTE_ENTRY_LENGTH);
+        messages.extend(self.total.to_be_bytes());
+        messages.extend(self.start.to_be_bytes());
+        messages.push(self.entries.len() as u8);
+        for entry in self.entries.iter() {
+            messages.extend(entry.to_bytes());
+        }
+        messages
+    }
+    /// messages may have padding after the entries.
+    pub fn from_messages(messages: &[u8]) -> Result<Self> {
+        if messages.len() < 5 {
+            return Err(anyhow!(
+                "length of routing table is not enough: {:?}",
+                messages
+            ));
+        }
+        let length = messages[4] as usize;
+        let entries = messages[5..]
+            .chunks(ROUTE_ENTRY_LENGTH)
+            .take(length)
+            .map(RouteEntry::from_bytes)
+            .collect::<Result<Vec<_>>>()?;
+        if entries.len() != length {
+            return Err(anyhow!(
+                "routing table has {} entries, but {} are expected",
+                entries.len(),
+                length
+            ));
+        }
+        Ok(Self {
+            total: u16::from_be_bytes([messages[0], messages[1]]),
+            start: u16::from_be_bytes([messages[2], messages[3]]),
+            entries,
+        })
+    }
+}
+
+/// text which can be printed line by line on the display or by a host tool
+impl fmt::Display for RoutingTablePage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "routes {}-{}/{}",
+            self.start,
+            self.start as usize + self.entries.len(),
+            self.total
+        )?;
+        for entry in self.entries.iter() {
+            write!(f, "\n{}", entry)?;
+        }
+        Ok(())
+    }
+}
 
 pub trait Protocol {
-    /// check whether this node is in route
+    /// check whether this node is in route.
+    /// NetworkNode doesn't call it: a node relays a unicast packet only when the previous node
+    /// chose it as the next node, because the path of adaptive protocols depends on the view of
+    /// each node, which the other nodes cannot reproduce. get_next_hop decides the route.
+    #[deprecated(note = "NetworkNode relays to the next hop which get_next_hop chooses")]
     fn is_in_route(&self, this: Id, global_source: Id, global_destination: Id) -> bool;
     /// get next node
-    fn get_next_node(&self, this: Id, global_destination: Id) -> Id;
+    /// return error if there is no route to global_destination
+    fn get_next_node(&self, this: Id, global_destination: Id) -> Result<Id>;
+    /// get next node and its channel of a packet which is received from previous node by the
+    /// channel. adaptive routing uses previous node to restrict turns of the packet, and moves it
+    /// to another channel to break cyclic dependencies.
+    /// default implementation doesn't depend on previous node, and keeps the channel.
+    fn get_next_hop(
+        &self,
+        this: Id,
+        _previous: Id,
+        global_destination: Id,
+        channel: ChannelId,
+    ) -> Result<(Id, ChannelId)> {
+        Ok((self.get_next_node(this, global_destination)?, channel))
+    }
     /// add nodes' connection to routing table
     /// nodes which have id or id2 are connected
     fn add_connection(&mut self, id: Id, id2: Id) -> Result<()>;
@@ -18,7 +199,8 @@ pub trait Protocol {
 
     // channels
 
-    ///
+    /// channel of a packet which this node sends to the destination.
+    /// it must be less than CHANNEL_LENGTH.
     fn get_channel(&self, this: Id, destinateion: Id) -> ChannelId {
         // default implementation has only one channel.
         0
@@ -28,13 +210,121 @@ pub trait Protocol {
         Ok(())
     }
     // return is global network ip_address
+    // it is called only in the root, when a node requests joining global network.
     fn join_global_network(&mut self, mac_address: Id, coordinate: Coordinate) -> Result<Id>;
+    /// ip address which the root has assigned to the node, if the protocol keeps it.
+    /// it is called only in the root, so that renewed leases follow the addressing scheme.
+    fn address_of(&self, _mac_address: Id) -> Option<Id> {
+        None
+    }
+    /// it is called only in the root, when the lease of the node expires.
+    /// the address may be reclaimed and assigned to another node.
+    fn leave_global_network(&mut self, _mac_address: Id, _ip_address: Id) -> Result<()> {
+        // default implementation derives addresses from coordinates, so nothing is kept.
+        Ok(())
+    }
+
+    /// parameters which the root hands out to joining nodes.
+    fn get_routing_parameters(&self) -> Vec<u8> {
+        // default implementation doesn't need any parameter.
+        Vec::new()
+    }
+    /// set parameters which are received from the root.
+    fn set_routing_parameters(&mut self, _parameters: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    // dynamic routing
+
+    /// called periodically after joining global network.
+    /// return messages which should be sent to neighbors.
+    fn update(&mut self, _this: Id, _now: Millis) -> Vec<RoutingMessage> {
+        // default implementation is static routing.
+        Vec::new()
+    }
+    /// process messages which are received from the neighbor.
+    /// return messages which should be sent to neighbors.
+    fn process_routing_message(
+        &mut self,
+        _this: Id,
+        _neighbor: Id,
+        _messages: &[u8],
+        _now: Millis,
+    ) -> Result<Vec<RoutingMessage>> {
+        Ok(Vec::new())
+    }
+
+    // anycast
+
+    /// distance from this node to the destination, e.g. hops.
+    /// a packet to ToId::Anycast is sent to the provider of the smallest distance.
+    /// return error if the distance is unknown.
+    fn get_distance(&self, this: Id, destination: Id) -> Result<u16> {
+        // default implementation cannot compare providers, so anycast is not available.
+        Err(anyhow!(
+            "distance from {} to {} is unknown",
+            this,
+            destination
+        ))
+    }
+
+    // introspection
+
+    /// list routes which this node knows, sorted by destination.
+    /// it is used for diagnosis, e.g. when a packet goes missing.
+    fn get_routing_table(&self, _this: Id, _now: Millis) -> Vec<RouteEntry> {
+        // default implementation computes routes on demand, so it has no table.
+        Vec::new()
+    }
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
 
+    #[test]
+    fn test_routing_table_page() {
+        let table: Vec<RouteEntry> = (0..50)
+            .map(|i| RouteEntry {
+                destination: i,
+                next_hop: 0x1234,
+                metric: i,
+                channel: 1,
+                age: 5300,
+            })
+            .collect();
+        let page = RoutingTablePage::new(&table, 0);
+        assert_eq!(page.entries.len(), RoutingTablePage::MAX_ENTRIES);
+        let start = page.get_next_start().unwrap();
+        assert_eq!(start as usize, RoutingTablePage::MAX_ENTRIES);
+
+        let page = RoutingTablePage::new(&table, start);
+        assert_eq!(page.get_next_start(), None);
+        let mut messages = page.to_messages();
+        assert!(messages.len() <= MAX_MESSAGES_LENGTH);
+        // padding is ignored
+        messages.extend([0, 0]);
+        let loaded = RoutingTablePage::from_messages(&messages).unwrap();
+        assert_eq!(loaded.total, 50);
+        assert_eq!(loaded.entries[0].destination, start);
+        // age is carried in seconds
+        assert_eq!(loaded.entries[0].age, 5000);
+        assert!(RoutingTablePage::from_messages(&messages[..10]).is_err());
+
+        let text = loaded.to_string();
+        let mut lines = text.lines();
+        assert_eq!(
+            lines.next(),
+            Some(format!("routes {}-50/50", start).as_str())
+        );
+        assert_eq!(
+            lines.next(),
+            Some(format!("{:5}>4660 {:3} 1 5s", start, start).as_str())
+        );
+        // a line fits the display (128px / 6px font)
+        assert!(text.lines().all(|line| line.len() <= 21));
+    }
+
     pub struct RoutingTable {}
     impl RoutingTable {
         pub fn new() -> Self {
@@ -57,8 +347,8 @@ pub mod test {
         fn is_in_route(&self, this_id: Id, source_id: Id, destination_id: Id) -> bool {
             true
         }
-        fn get_next_node(&self, this_id: Id, destination_id: Id) -> Id {
-            0
+        fn get_next_node(&self, this_id: Id, destination_id: Id) -> Result<Id> {
+            Ok(0)
         }
         fn add_connection(&mut self, id: Id, id2: Id) -> Result<()> {
             Ok(())
@@ -75,5 +365,18 @@ pub mod test {
         fn join_global_network(&mut self, mac_address: Id, coordinate: Coordinate) -> Result<Id> {
             Ok(0)
         }
+        /// nodes of smaller ids are nearer
+        fn get_distance(&self, this: Id, destination: Id) -> Result<u16> {
+            Ok(destination)
+        }
+        fn get_routing_table(&self, this: Id, now: Millis) -> Vec<RouteEntry> {
+            vec![RouteEntry {
+                destination: 1,
+                next_hop: 0,
+                metric: 1,
+                channel: 0,
+                age: 0,
+            }]
+        }
     }
 }
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::mem::size_of;
+
+use anyhow::{anyhow, Result};
+
+use crate::packet::MAX_MESSAGES_LENGTH;
+use crate::protocol::Millis;
+use crate::utils::type_alias::Id;
+
+/// id of a service which nodes provide, e.g. a gateway to the host or the root.
+/// ids other than the ones below are defined by applications. a packet to ToId::Anycast(service)
+/// reaches the nearest provider.
+pub type ServiceId = u16;
+
+/// the root provides it, so that nodes reach the root without knowing its address
+pub const ROOT_SERVICE: ServiceId = 0;
+/// nodes which are connected to the host (e.g. by usb) provide it
+pub const GATEWAY_SERVICE: ServiceId = 1;
+
+/// interval of advertising services which this node provides
+pub const SERVICE_ADVERTISE_INTERVAL: Millis = 10_000;
+/// a provider is forgotten when its advertisement is not received in this time
+pub const SERVICE_TIMEOUT: Millis = SERVICE_ADVERTISE_INTERVAL * 3;
+/// the number of services which an advertisement can carry
+pub const MAX_SERVICES: usize = MAX_MESSAGES_LENGTH / size_of::<ServiceId>();
+
+/// encode services of AdvertiseService packet.
+/// Data form is like this [ service(16)* ]
+pub fn to_messages(services: &[ServiceId]) -> Result<Vec<u8>> {
+    if services.len() > MAX_SERVICES {
+        return Err(anyhow!(
+            "too many services to advertise: {} > {}",
+            services.len(),
+            MAX_SERVICES
+        ));
+    }
+    Ok(services
+        .iter()
+        .flat_map(|service| service.to_be_bytes())
+        .collect())
+}
+
+pub fn from_messages(messages: &[u8]) -> Result<Vec<ServiceId>> {
+    let chunks = messages.chunks_exact(size_of::<ServiceId>());
+    if !chunks.remainder().is_empty() {
+        return Err(anyhow!("invalid services: {:?}", messages));
+    }
+    Ok(chunks
+        .map(|bytes| ServiceId::from_be_bytes([bytes[0], bytes[1]]))
+        .collect())
+}
+
+/// providers of services which are learned from advertisements
+#[derive(Debug, Default, Clone)]
+pub struct ServiceTable {
+    /// (service, provider) -> when it is advertised last
+    providers: BTreeMap<(ServiceId, Id), Millis>,
+}
+
+impl ServiceTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the provider advertised the services.
+    /// services which are not advertised any more are removed.
+    pub fn advertise(&mut self, provider: Id, services: &[ServiceId], now: Millis) {
+        self.providers
+            .retain(|(service, id), _| *id != provider || services.contains(service));
+        for service in services {
+            self.providers.insert((*service, provider), now);
+        }
+    }
+
+    /// remove providers which are not advertised in SERVICE_TIMEOUT
+    pub fn expire(&mut self, now: Millis) {
+        self.providers
+            .retain(|_, advertised_at| now.saturating_sub(*advertised_at) <= SERVICE_TIMEOUT);
+    }
+
+    /// providers of the service in ascending order
+    pub fn get_providers(&self, service: ServiceId) -> Vec<Id> {
+        self.providers
+            .range((service, Id::MIN)..=(service, Id::MAX))
+            .map(|((_, provider), _)| *provider)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_service_table() {
+        let mut table = ServiceTable::new();
+        table.advertise(3, &[1, 2], 0);
+        table.advertise(5, &[1], 0);
+        assert_eq!(table.get_providers(1), vec![3, 5]);
+        assert_eq!(table.get_providers(2), vec![3]);
+        assert!(table.get_providers(0).is_empty());
+
+        // 3 stopped providing 2
+        table.advertise(3, &[1], SERVICE_TIMEOUT);
+        assert!(table.get_providers(2).is_empty());
+        // 5 is not advertised any more
+        table.expire(SERVICE_TIMEOUT + 1);
+        assert_eq!(table.get_providers(1), vec![3]);
+
+        let messages = to_messages(&[1, 0x1234]).unwrap();
+        assert_eq!(messages, vec![0, 1, 0x12, 0x34]);
+        assert_eq!(from_messages(&messages).unwrap(), vec![1, 0x1234]);
+        assert!(from_messages(&messages[..3]).is_err());
+        assert!(to_messages(&[0; MAX_SERVICES + 1]).is_err());
+    }
+}
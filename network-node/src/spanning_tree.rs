@@ -0,0 +1,376 @@
+use anyhow::{anyhow, Result};
+use log::info;
+
+use crate::header::Header;
+use crate::packet::{Packet, ToId};
+use crate::utils::type_alias::Id;
+
+pub type Depth = u8;
+/// depth which SendParentId carries when the sender has left the tree
+const UNKNOWN_DEPTH: Depth = Depth::MAX;
+
+/// Spanning tree which is rooted at the root node.
+/// Each node uses its mac address in the tree, so the tree can be built before joining global
+/// network.
+///
+/// The process is this:
+/// 1. A node in the tree sends SendParentId by broadcast, which has depth of the node.
+/// 2. A node which is not in the tree (or finds shallower parent) sends SendChildId to the sender.
+/// 3. The parent registers the child, and sends ReceiveChildId, which has depth of the parent.
+/// 4. The child registers the parent. If it had another parent, it sends ReceiveParentId to
+///    the old parent, which removes the child.
+/// 5. The child sends SendParentId, so the tree grows.
+///
+/// If a node loses its parent, it sends SendParentId of unknown depth, and its subtree leaves the
+/// tree until each node finds another parent.
+#[derive(Debug)]
+pub struct SpanningTree {
+    this_id: Id,
+    is_root: bool,
+    parent: Option<Id>,
+    /// root is 0. it is None if this node is not in the tree.
+    depth: Option<Depth>,
+    children: Vec<Id>,
+    /// (parent, depth of the parent) which this node requested to be a child of
+    pending_parent: Option<(Id, Depth)>,
+}
+
+impl SpanningTree {
+    pub fn new(this_id: Id, is_root: bool) -> Self {
+        Self {
+            this_id,
+            is_root,
+            parent: None,
+            depth: if is_root { Some(0) } else { None },
+            children: Vec::new(),
+            pending_parent: None,
+        }
+    }
+
+    pub fn is_spanning_tree_header(header: Header) -> bool {
+        matches!(
+            header,
+            Header::SendParentId
+                | Header::ReceiveParentId
+                | Header::SendChildId
+                | Header::ReceiveChildId
+        )
+    }
+
+    /// make SendParentId packet if this node is in the tree
+    pub fn make_advertisement(&self) -> Option<Packet> {
+        let depth = self.depth?;
+        Some(Self::make_packet(
+            Header::SendParentId,
+            self.this_id,
+            ToId::Broadcast,
+            vec![depth],
+        ))
+    }
+
+    /// process packet of spanning tree, and return packets which should be sent.
+    pub fn process_packet(&mut self, packet: &Packet) -> Result<Vec<Packet>> {
+        let from = packet.get_global_from();
+        if from == self.this_id {
+            return Ok(Vec::new());
+        }
+        let header = packet.get_header();
+        if header != Header::SendParentId && packet.get_global_to() != ToId::Unicast(self.this_id)
+        {
+            // unicast packet to other node
+            return Ok(Vec::new());
+        }
+
+        let mut packets = Vec::new();
+        match header {
+            Header::SendParentId => {
+                let depth = Self::load_depth(packet)?;
+                if Some(from) == self.parent {
+                    match depth.checked_add(1) {
+                        // parent has left the tree, so does this node
+                        None => packets.extend(self.leave()),
+                        // depth of parent may be changed
+                        Some(depth) if self.depth != Some(depth) => {
+                            self.depth = Some(depth);
+                            packets.extend(self.make_advertisement());
+                        }
+                        Some(_) => {}
+                    }
+                } else if self.is_better_parent(from, depth) {
+                    self.pending_parent = Some((from, depth));
+                    packets.push(Self::make_packet(
+                        Header::SendChildId,
+                        self.this_id,
+                        ToId::Unicast(from),
+                        Vec::new(),
+                    ));
+                }
+            }
+            Header::SendChildId => {
+                let depth = match self.depth {
+                    Some(depth) => depth,
+                    // this node cannot be a parent
+                    None => return Ok(packets),
+                };
+                if Some(from) == self.parent {
+                    return Ok(packets);
+                }
+                if !self.children.contains(&from) {
+                    info!("add child: {}", from);
+                    self.children.push(from);
+                }
+                packets.push(Self::make_packet(
+                    Header::ReceiveChildId,
+                    self.this_id,
+                    ToId::Unicast(from),
+                    vec![depth],
+                ));
+            }
+            Header::ReceiveChildId => {
+                let depth = Self::load_depth(packet)?;
+                match self.pending_parent {
+                    Some((parent, _)) if parent == from => {}
+                    _ => {
+                        // this node has already chosen another parent,
+                        // so the sender must remove this node from its children.
+                        if Some(from) != self.parent {
+                            packets.push(Self::make_packet(
+                                Header::ReceiveParentId,
+                                self.this_id,
+                                ToId::Unicast(from),
+                                Vec::new(),
+                            ));
+                        }
+                        return Ok(packets);
+                    }
+                }
+                if let Some(old_parent) = self.parent {
+                    if old_parent != from {
+                        packets.push(Self::make_packet(
+                            Header::ReceiveParentId,
+                            self.this_id,
+                            ToId::Unicast(old_parent),
+                            Vec::new(),
+                        ));
+                    }
+                }
+                info!("set parent: {}", from);
+                self.parent = Some(from);
+                self.depth = depth.checked_add(1);
+                self.pending_parent = None;
+                self.children.retain(|child| *child != from);
+                packets.extend(self.make_advertisement());
+            }
+            Header::ReceiveParentId => {
+                self.children.retain(|child| *child != from);
+            }
+            _ => {
+                return Err(anyhow!(
+                    "This packet is not for spanning tree: {:?}",
+                    header
+                ))
+            }
+        }
+        Ok(packets)
+    }
+
+    /// remove the node which is disconnected from this node, and return packets which should be
+    /// sent. if it is the parent, this node and its subtree leave the tree until they find
+    /// another parent.
+    pub fn remove_node(&mut self, id: Id) -> Vec<Packet> {
+        self.children.retain(|child| *child != id);
+        if matches!(self.pending_parent, Some((parent, _)) if parent == id) {
+            self.pending_parent = None;
+        }
+        if self.parent == Some(id) {
+            return self.leave().into_iter().collect();
+        }
+        Vec::new()
+    }
+
+    /// forget the parent and the children, and tell the children that the depth is unknown, so
+    /// that their depths are not stale.
+    fn leave(&mut self) -> Option<Packet> {
+        info!("leave spanning tree: parent {:?}", self.parent);
+        self.parent = None;
+        self.depth = None;
+        if self.children.is_empty() {
+            return None;
+        }
+        self.children.clear();
+        Some(Self::make_packet(
+            Header::SendParentId,
+            self.this_id,
+            ToId::Broadcast,
+            vec![UNKNOWN_DEPTH],
+        ))
+    }
+
+    fn is_better_parent(&self, id: Id, depth: Depth) -> bool {
+        if self.is_root || self.children.contains(&id) {
+            return false;
+        }
+        let new_depth = match depth.checked_add(1) {
+            Some(depth) => depth,
+            None => return false,
+        };
+        // the pending parent may not receive SendChildId, so it can be requested again.
+        if let Some((pending_id, pending_depth)) = self.pending_parent {
+            if pending_id != id && depth >= pending_depth {
+                return false;
+            }
+        }
+        match self.depth {
+            Some(this_depth) => new_depth < this_depth,
+            None => true,
+        }
+    }
+
+    fn make_packet(header: Header, source: Id, to: ToId, messages: Vec<u8>) -> Packet {
+        Packet::new(0, header, source, to, source, to, messages)
+            .expect("messages of spanning tree fit in a packet")
+    }
+
+    fn load_depth(packet: &Packet) -> Result<Depth> {
+        match packet.get_ref_messages().first() {
+            Some(depth) => Ok(*depth),
+            None => Err(anyhow!("depth is not contained: {:?}", packet)),
+        }
+    }
+
+    // ------------------------------
+    // getter
+    // ------------------------------
+
+    pub fn is_in_tree(&self) -> bool {
+        self.depth.is_some()
+    }
+    pub fn get_parent(&self) -> Option<Id> {
+        self.parent
+    }
+    pub fn get_children(&self) -> &[Id] {
+        &self.children
+    }
+    pub fn get_depth(&self) -> Option<Depth> {
+        self.depth
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// deliver packets to the nodes until no packet is sent.
+    /// neighbors[i] is the list of index of nodes which are connected to node i.
+    fn run(trees: &mut [SpanningTree], neighbors: &[Vec<usize>], mut packets: Vec<(usize, Packet)>) {
+        let mut count = 0;
+        while let Some((sender, packet)) = packets.pop() {
+            count += 1;
+            assert!(count < 1000, "spanning tree does not converge");
+            for &receiver in neighbors[sender].iter() {
+                for reply in trees[receiver].process_packet(&packet).unwrap() {
+                    packets.insert(0, (receiver, reply));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_line() {
+        // 0(root) - 1 - 2 - 3
+        let mut trees: Vec<SpanningTree> = (0..4).map(|i| SpanningTree::new(i, i == 0)).collect();
+        let neighbors = vec![vec![1], vec![0, 2], vec![1, 3], vec![2]];
+        let advertisement = trees[0].make_advertisement().unwrap();
+        run(&mut trees, &neighbors, vec![(0, advertisement)]);
+
+        assert_eq!(trees[0].get_parent(), None);
+        assert_eq!(trees[0].get_children(), &[1]);
+        for i in 1..4 {
+            assert_eq!(trees[i].get_parent(), Some(i as Id - 1));
+            assert_eq!(trees[i].get_depth(), Some(i as Depth));
+        }
+        assert!(trees[3].get_children().is_empty());
+    }
+
+    #[test]
+    fn test_build_grid() {
+        // 3 * 3 grid, root is 0
+        // 0 1 2
+        // 3 4 5
+        // 6 7 8
+        let mut trees: Vec<SpanningTree> = (0..9).map(|i| SpanningTree::new(i, i == 0)).collect();
+        let neighbors: Vec<Vec<usize>> = (0..9)
+            .map(|i: usize| {
+                let (x, y) = (i % 3, i / 3);
+                let mut ids = Vec::new();
+                if x > 0 {
+                    ids.push(i - 1);
+                }
+                if x < 2 {
+                    ids.push(i + 1);
+                }
+                if y > 0 {
+                    ids.push(i - 3);
+                }
+                if y < 2 {
+                    ids.push(i + 3);
+                }
+                ids
+            })
+            .collect();
+        let advertisement = trees[0].make_advertisement().unwrap();
+        run(&mut trees, &neighbors, vec![(0, advertisement)]);
+
+        for (i, tree) in trees.iter().enumerate() {
+            // depth is the shortest distance from root
+            assert_eq!(tree.get_depth(), Some((i % 3 + i / 3) as Depth));
+            // parent and children are consistent
+            if let Some(parent) = tree.get_parent() {
+                assert!(trees[parent as usize].get_children().contains(&(i as Id)));
+            }
+            for child in tree.get_children() {
+                assert_eq!(trees[*child as usize].get_parent(), Some(i as Id));
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_parent() {
+        let mut tree = SpanningTree::new(1, false);
+        let offer = SpanningTree::make_packet(Header::SendParentId, 0, ToId::Broadcast, vec![0]);
+        let replies = tree.process_packet(&offer).unwrap();
+        assert_eq!(replies[0].get_header(), Header::SendChildId);
+        let accept =
+            SpanningTree::make_packet(Header::ReceiveChildId, 0, ToId::Unicast(1), vec![0]);
+        tree.process_packet(&accept).unwrap();
+        assert_eq!(tree.get_parent(), Some(0));
+
+        assert!(tree.remove_node(0).is_empty());
+        assert!(!tree.is_in_tree());
+        assert!(tree.make_advertisement().is_none());
+    }
+
+    #[test]
+    fn test_remove_parent_of_subtree() {
+        // 0(root) - 1 - 2 - 3
+        let mut trees: Vec<SpanningTree> = (0..4).map(|i| SpanningTree::new(i, i == 0)).collect();
+        let neighbors = vec![vec![1], vec![0, 2], vec![1, 3], vec![2]];
+        let advertisement = trees[0].make_advertisement().unwrap();
+        run(&mut trees, &neighbors, vec![(0, advertisement)]);
+
+        // 0 and 1 are disconnected, and the subtree of 1 is orphaned
+        trees[0].remove_node(1);
+        let packets = trees[1].remove_node(0);
+        let neighbors = vec![vec![], vec![2], vec![1, 3], vec![2]];
+        run(
+            &mut trees,
+            &neighbors,
+            packets.into_iter().map(|packet| (1, packet)).collect(),
+        );
+        for tree in trees[1..].iter() {
+            assert_eq!(tree.get_parent(), None);
+            assert_eq!(tree.get_depth(), None);
+            assert!(tree.get_children().is_empty());
+        }
+    }
+}
@@ -8,8 +8,6 @@ use esp_idf_hal::prelude::*;
 
 use global_network::DefaultProtocol;
 use log::info;
-use network_node::header::Header;
-use network_node::packet::Packet;
 use network_node::utils::util::{self, get_first_messages};
 use network_node::NetworkNode;
 use std_display::display::Display;
@@ -157,31 +155,15 @@ fn main() -> Result<()> {
                 if flag {
                     flag = false;
                 }
+                // let new nodes join the spanning tree, and join again if the address is lost
+                network.poll()?;
                 esp_idf_hal::delay::Delay::delay_ms(500);
                 continue;
             }
             messages.unwrap()
         };
 
-        let from = packet.get_global_from();
-        esp_idf_hal::delay::Delay::delay_ms(rand::random::<u32>() % 90 + 10);
-
-        match packet.get_header() {
-            Header::HRequestConfirmedCoordinate => {
-                let packet = Packet::make_confirm_coordinate_packet_by_confirmed_node(
-                    network.get_mac_address(),
-                    from,
-                    network.get_coordinate(),
-                    network.get_global_location(),
-                )
-                .unwrap();
-
-                println!("send packet: {:?}", packet);
-                network.send(packet)?;
-            }
-            _ => {
-                println!("received packet: {:?}", packet);
-            }
-        }
+        // control packets, e.g. coordinate requests of new nodes, are handled by the node
+        println!("received packet: {:?}", packet);
     }
 }
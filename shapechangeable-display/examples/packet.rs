@@ -28,7 +28,8 @@ fn main() -> Result<()> {
         ip_address,
         ToId::Broadcast,
         vec![],
-    );
+    )
+    .unwrap();
 
     loop {
         match packet.send(&mut serial) {
@@ -29,6 +29,7 @@ use st7735_lcd::Orientation;
 use st7735_lcd::ST7735;
 
 use network_node::localnet::LocalNetworkLocation;
+use network_node::protocol::RoutingTablePage;
 use network_node::utils::type_alias::Coordinate;
 
 use core::fmt::Write;
@@ -187,6 +188,12 @@ impl<'d> Display2<'d> {
             self.y = y_size;
         }
     }
+    /// print the page line by line
+    pub fn print_routing_table(&mut self, page: &RoutingTablePage) {
+        for line in page.to_string().lines() {
+            self.print(line, true);
+        }
+    }
     pub fn depict(&mut self, image: &Image<'_, ImageRawLE<Rgb565>>) {
         image.draw(&mut self.display).expect("failed to draw image");
     }
@@ -13,8 +13,7 @@ use log::info;
 
 use global_network::DefaultProtocol;
 
-use network_node::header::Header;
-use network_node::packet::Packet;
+use network_node::estimation::EstimationState;
 use network_node::utils::util::{self, get_first_messages};
 use network_node::NetworkNode;
 
@@ -95,14 +94,35 @@ fn main() -> Result<()> {
     // network initialization
     let protocol: DefaultProtocol = DefaultProtocol::new();
 
-    let mut network = match NetworkNode::new(serial, protocol, &efuse) {
-        Ok(network) => network,
-        Err(e) => {
-            display_println!("network initialization failed: {:?}", e);
-            println!("network initialization failed: {:?}", e);
-            loop {}
+    let mut network = NetworkNode::start(serial, protocol, &efuse);
+    let mut last_progress = String::new();
+    loop {
+        match network.poll() {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => {
+                display_println!("network initialization failed: {:?}", e);
+                println!("network initialization failed: {:?}", e);
+                loop {}
+            }
         }
-    };
+        // show what the node is waiting for
+        let progress = network.get_estimation().to_string();
+        if progress != last_progress {
+            display.reset();
+            display_print!("{}", progress);
+            last_progress = progress;
+        }
+        if network.get_estimation().get_state() == EstimationState::Failed {
+            println!("estimation failed: {:?}", network.get_estimation());
+            // conflicts which made the estimation fail are reported while poll is called
+            loop {
+                let _ = network.poll();
+                esp_idf_hal::delay::Delay::delay_ms(500);
+            }
+        }
+        esp_idf_hal::delay::Delay::delay_ms(10);
+    }
 
     network.print_coordinate();
     display.set_rotation_by_coordinate(
@@ -150,31 +170,34 @@ fn main() -> Result<()> {
                 if flag {
                     flag = false;
                 }
+                // let new nodes join the spanning tree, and join again while the address is
+                // lost, e.g. the lease could not be renewed
+                if !network.poll()? {
+                    esp_idf_hal::delay::Delay::delay_ms(10);
+                    continue;
+                }
+                // probe neighbors, and tell the protocol connections which are up or down
+                network.update_neighbors()?;
+                for event in network.take_shape_events() {
+                    println!("shape changed: {:?}", event);
+                }
+                // only the root receives coordinates which neighbors claimed inconsistently
+                for report in network.take_conflict_reports() {
+                    println!("conflict reported: {:?}", report);
+                }
+                // dynamic routing protocols exchange routing messages
+                network.update_routing()?;
+                // renew the lease of ip address, and the root reclaims expired ones
+                network.update_lease()?;
+                // let other nodes know services of this node for anycast
+                network.advertise_services()?;
                 esp_idf_hal::delay::Delay::delay_ms(500);
                 continue;
             }
             messages.unwrap()
         };
 
-        let from = packet.get_global_from();
-        esp_idf_hal::delay::Delay::delay_ms(rand::random::<u32>() % 90 + 10);
-
-        match packet.get_header() {
-            Header::HRequestConfirmedCoordinate => {
-                let packet = Packet::make_confirm_coordinate_packet_by_confirmed_node(
-                    network.get_mac_address(),
-                    from,
-                    network.get_coordinate(),
-                    network.get_global_location(),
-                )
-                .unwrap();
-
-                println!("send packet: {:?}", packet);
-                network.send(packet)?;
-            }
-            _ => {
-                println!("received packet: {:?}", packet);
-            }
-        }
+        // control packets, e.g. coordinate requests of new nodes, are handled by the node
+        println!("received packet: {:?}", packet);
     }
 }